@@ -0,0 +1,80 @@
+//! The fixed ACPI description table (signature `FACP`).
+//!
+//! Only the ACPI 1.0 part is described, the kernel needs nothing beyond it yet.
+
+use super::{SdtHeader, Table};
+
+/// `iapc_boot_arch` bit: the CMOS RTC is not present.
+pub const BOOT_ARCH_CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+/// The fixed part of the FADT up to the ACPI 1.0 flags.
+#[allow(dead_code)]
+#[repr(C, packed)]
+pub struct Fadt {
+    header: SdtHeader,
+    firmware_ctrl: u32,
+    dsdt: u32,
+    reserved0: u8,
+    preferred_pm_profile: u8,
+    sci_interrupt: u16,
+    smi_command: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_request: u8,
+    pstate_control: u8,
+    pm1a_event_block: u32,
+    pm1b_event_block: u32,
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+    pm2_control_block: u32,
+    pm_timer_block: u32,
+    gpe0_block: u32,
+    gpe1_block: u32,
+    pm1_event_length: u8,
+    pm1_control_length: u8,
+    pm2_control_length: u8,
+    pm_timer_length: u8,
+    gpe0_block_length: u8,
+    gpe1_block_length: u8,
+    gpe1_base: u8,
+    cstate_control: u8,
+    worst_c2_latency: u16,
+    worst_c3_latency: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alarm: u8,
+    month_alarm: u8,
+    century: u8,
+    iapc_boot_arch: u16,
+    reserved1: u8,
+    flags: u32,
+}
+
+unsafe impl Table for Fadt {
+    const SIGNATURE: &'static [u8; 4] = b"FACP";
+
+    fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+}
+
+impl Fadt {
+    /// CMOS index of the RTC century register, `None` if there is none.
+    pub fn century_register(&self) -> Option<u8> {
+        match self.century {
+            0 => None,
+            index => Some(index),
+        }
+    }
+
+    /// The IA-PC boot architecture flags, always 0 before ACPI 2.0.
+    pub fn iapc_boot_arch(&self) -> u16 {
+        if self.header.revision() >= 2 {
+            self.iapc_boot_arch
+        } else {
+            0
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! The high precision event timer description table (signature `HPET`).
+
+use super::{GenericAddress, SdtHeader, Table};
+
+/// The fixed size HPET table.
+#[allow(dead_code)]
+#[repr(C, packed)]
+pub struct Hpet {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+unsafe impl Table for Hpet {
+    const SIGNATURE: &'static [u8; 4] = b"HPET";
+
+    fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+}
+
+impl Hpet {
+    /// Physical address of the register block, `None` if it is not memory mapped.
+    pub fn base_address(&self) -> Option<u64> {
+        let base = self.base_address;
+        if base.address_space == GenericAddress::SYSTEM_MEMORY {
+            Some(base.address)
+        } else {
+            None
+        }
+    }
+}
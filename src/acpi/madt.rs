@@ -0,0 +1,153 @@
+//! The multiple APIC description table (signature `APIC`).
+//!
+//! Lists the local APICs of all processors, the I/O APICs and how the legacy ISA
+//! IRQs are wired to global system interrupts.
+
+use core::mem;
+use super::{SdtHeader, Table};
+
+/// `flags` bit: the system also has dual 8259 PICs.
+pub const PCAT_COMPAT: u32 = 1 << 0;
+
+/// Local APIC entry flag: the processor is usable.
+pub const LAPIC_ENABLED: u32 = 1 << 0;
+/// Local APIC entry flag: the processor can be brought online later.
+pub const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// The fixed part of the MADT, followed by variable length entries.
+#[repr(C, packed)]
+pub struct Madt {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+unsafe impl Table for Madt {
+    const SIGNATURE: &'static [u8; 4] = b"APIC";
+
+    fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+}
+
+impl Madt {
+    /// Physical address of the local APIC registers, unless overridden by an entry.
+    pub fn local_apic_address(&self) -> u32 {
+        self.local_apic_address
+    }
+
+    /// The `PCAT_COMPAT` flag.
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Iterates over the interrupt controller entries.
+    pub fn entries(&self) -> MadtIter<'_> {
+        MadtIter {
+            data: &self.header.data()[mem::size_of::<Madt>() - mem::size_of::<SdtHeader>()..],
+        }
+    }
+}
+
+/// A decoded MADT entry. Entry types the kernel does not use are returned as `Unknown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    /// A processor with an xAPIC id.
+    LocalApic { processor_id: u8, apic_id: u8, flags: u32 },
+    /// An I/O APIC and the first GSI it serves.
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    /// An ISA IRQ that is not identity mapped to a GSI, or uses non-ISA polarity/trigger.
+    InterruptSourceOverride { bus: u8, source: u8, gsi: u32, flags: u16 },
+    /// A GSI that has to be programmed as NMI.
+    NmiSource { flags: u16, gsi: u32 },
+    /// A LINT pin wired to NMI, `processor_id` 0xFF means all processors.
+    LocalApicNmi { processor_id: u8, flags: u16, lint: u8 },
+    /// A 64-bit local APIC address replacing the one in the table header.
+    LocalApicAddressOverride { address: u64 },
+    /// A processor with an x2APIC id.
+    LocalX2Apic { x2apic_id: u32, flags: u32, processor_uid: u32 },
+    /// Any other entry type.
+    Unknown(u8),
+}
+
+/// Polarity bits of the MPS INTI `flags` in overrides and NMI entries.
+pub const INTI_POLARITY_MASK: u16 = 0b11;
+pub const INTI_POLARITY_ACTIVE_HIGH: u16 = 0b01;
+pub const INTI_POLARITY_ACTIVE_LOW: u16 = 0b11;
+/// Trigger mode bits of the MPS INTI `flags`.
+pub const INTI_TRIGGER_MASK: u16 = 0b11 << 2;
+pub const INTI_TRIGGER_EDGE: u16 = 0b01 << 2;
+pub const INTI_TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+/// Iterator over the entries of a `Madt`.
+pub struct MadtIter<'a> {
+    data: &'a [u8],
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(bytes, offset)) | u64::from(read_u32(bytes, offset + 4)) << 32
+}
+
+impl<'a> Iterator for MadtIter<'a> {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.data.len() < 2 {
+            return None;
+        }
+        let (kind, length) = (self.data[0], self.data[1] as usize);
+        if length < 2 || length > self.data.len() {
+            // malformed table, stop instead of reading past the end
+            self.data = &[];
+            return None;
+        }
+        let e = &self.data[..length];
+        self.data = &self.data[length..];
+
+        let entry = match (kind, length) {
+            (0, 8) => MadtEntry::LocalApic {
+                processor_id: e[2],
+                apic_id: e[3],
+                flags: read_u32(e, 4),
+            },
+            (1, 12) => MadtEntry::IoApic {
+                id: e[2],
+                address: read_u32(e, 4),
+                gsi_base: read_u32(e, 8),
+            },
+            (2, 10) => MadtEntry::InterruptSourceOverride {
+                bus: e[2],
+                source: e[3],
+                gsi: read_u32(e, 4),
+                flags: read_u16(e, 8),
+            },
+            (3, 8) => MadtEntry::NmiSource {
+                flags: read_u16(e, 2),
+                gsi: read_u32(e, 4),
+            },
+            (4, 6) => MadtEntry::LocalApicNmi {
+                processor_id: e[2],
+                flags: read_u16(e, 3),
+                lint: e[5],
+            },
+            (5, 12) => MadtEntry::LocalApicAddressOverride {
+                address: read_u64(e, 4),
+            },
+            (9, 16) => MadtEntry::LocalX2Apic {
+                x2apic_id: read_u32(e, 4),
+                flags: read_u32(e, 8),
+                processor_uid: read_u32(e, 12),
+            },
+            (kind, _) => MadtEntry::Unknown(kind),
+        };
+        Some(entry)
+    }
+}
@@ -0,0 +1,260 @@
+//! Discovery of the ACPI system description tables.
+//!
+//! `init` locates the RSDP in the BIOS areas of low memory, follows it to the RSDT
+//! or XSDT and identity maps every table it lists. Drivers then look up the table
+//! they need with `find`. Only the static tables are parsed, there is no AML
+//! interpreter.
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+
+pub use self::fadt::Fadt;
+pub use self::hpet::Hpet;
+pub use self::madt::{Madt, MadtEntry};
+
+use alloc::vec::Vec;
+use core::{mem, slice, str};
+use crate::memory::paging::{EntryFlags, PhysicalAddress};
+use crate::memory::MemoryController;
+use spin::Once;
+
+/// Physical address of the segment of the extended BIOS data area, in the BDA.
+const EBDA_SEGMENT_POINTER: PhysicalAddress = 0x40E;
+/// The RSDP is in the first KiB of the EBDA...
+const EBDA_SEARCH_SIZE: u64 = 0x400;
+/// ...or in the BIOS read-only area.
+const BIOS_AREA_START: PhysicalAddress = 0xE0000;
+const BIOS_AREA_END: PhysicalAddress = 0x100000;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// Root system description pointer, version 1 fields followed by the ACPI 2.0 ones.
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // revision 2 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Size of the ACPI 1.0 part of the RSDP, covered by `checksum`.
+const RSDP_V1_SIZE: usize = 20;
+
+/// The header every system description table starts with.
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// The four character signature, e.g. `APIC` for the MADT.
+    pub fn signature(&self) -> &str {
+        str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    /// Length of the table including the header.
+    pub fn length(&self) -> usize {
+        self.length as usize
+    }
+
+    /// Revision of the table layout.
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// The OEM id, usually padded with spaces.
+    pub fn oem_id(&self) -> &str {
+        str::from_utf8(&self.oem_id).unwrap_or("??????")
+    }
+
+    /// The table as bytes, header included.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const SdtHeader as *const u8, self.length()) }
+    }
+
+    /// The bytes following the header.
+    pub fn data(&self) -> &[u8] {
+        &self.as_bytes()[mem::size_of::<SdtHeader>()..]
+    }
+}
+
+/// An address in one of the ACPI address spaces.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    /// 0 for system memory, 1 for system I/O.
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+}
+
+/// Implemented by the typed table structures so `find` can look them up.
+///
+/// The implementing type must be `repr(C, packed)` and start with an `SdtHeader`.
+pub unsafe trait Table {
+    const SIGNATURE: &'static [u8; 4];
+
+    fn header(&self) -> &SdtHeader;
+}
+
+static TABLES: Once<Vec<&'static SdtHeader>> = Once::new();
+
+/// Locates the RSDP and maps all tables listed in the RSDT or XSDT.
+///
+/// Returns false if the firmware provides no (valid) ACPI tables.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    let rsdp = match find_rsdp(memory_controller) {
+        Some(rsdp) => rsdp,
+        None => {
+            println!("ACPI: no RSDP found");
+            return false;
+        }
+    };
+
+    let (root_address, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (u64::from(rsdp.rsdt_address), 4)
+    };
+
+    let root = match map_table(root_address, memory_controller) {
+        Some(root) => root,
+        None => {
+            println!("ACPI: invalid root table at {:#x}", root_address);
+            return false;
+        }
+    };
+
+    println!(
+        "ACPI: revision {}, OEM '{}', {} at {:#x}",
+        { rsdp.revision },
+        str::from_utf8(&{ rsdp.oem_id }).unwrap_or("??????"),
+        root.signature(),
+        root_address
+    );
+
+    let mut tables = Vec::new();
+    for entry in root.data().chunks_exact(entry_size) {
+        let address = if entry_size == 8 {
+            u64::from_le_bytes([entry[0], entry[1], entry[2], entry[3], entry[4], entry[5], entry[6], entry[7]])
+        } else {
+            u64::from(u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]))
+        };
+
+        match map_table(address, memory_controller) {
+            Some(table) => {
+                println!("    {} at {:#x}, {} bytes", table.signature(), address, table.length());
+                tables.push(table);
+            }
+            None => println!("    invalid table at {:#x} ignored", address),
+        }
+    }
+
+    TABLES.call_once(|| tables);
+    true
+}
+
+/// Returns true once `init` found the ACPI tables.
+pub fn is_available() -> bool {
+    TABLES.r#try().is_some()
+}
+
+/// Returns the first table with the signature of `T`.
+pub fn find<T: Table>() -> Option<&'static T> {
+    find_by_signature(T::SIGNATURE).map(|header| unsafe { &*(header as *const SdtHeader as *const T) })
+}
+
+/// Returns the first table with the given signature.
+pub fn find_by_signature(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    TABLES
+        .r#try()?
+        .iter()
+        .find(|table| &table.signature == signature)
+        .cloned()
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Scans the first KiB of the EBDA and the BIOS area for the RSDP signature.
+fn find_rsdp(memory_controller: &mut MemoryController) -> Option<Rsdp> {
+    // the BDA lives in page 0, which stays unmapped to catch null pointers
+    memory_controller.identity_map(0, 0x1000, EntryFlags::NO_EXECUTE);
+    let ebda = u64::from(unsafe { *(EBDA_SEGMENT_POINTER as *const u16) }) << 4;
+    memory_controller.identity_unmap(0, 0x1000);
+
+    let mut areas = [(BIOS_AREA_START, BIOS_AREA_END), (0, 0)];
+    if ebda >= 0x1000 && ebda < BIOS_AREA_START {
+        areas = [(ebda, ebda + EBDA_SEARCH_SIZE), (BIOS_AREA_START, BIOS_AREA_END)];
+    }
+
+    for &(start, end) in areas.iter().filter(|(start, end)| start < end) {
+        memory_controller.identity_map(start, end - start, EntryFlags::NO_EXECUTE);
+
+        // the signature is always on a 16 byte boundary
+        for address in (start..end).step_by(16) {
+            let bytes = unsafe { slice::from_raw_parts(address as *const u8, RSDP_V1_SIZE) };
+            if &bytes[..8] != RSDP_SIGNATURE || !checksum_ok(bytes) {
+                continue;
+            }
+
+            let rsdp = unsafe { *(address as *const Rsdp) };
+            if rsdp.revision >= 2 {
+                let full = unsafe { slice::from_raw_parts(address as *const u8, mem::size_of::<Rsdp>()) };
+                if !checksum_ok(full) {
+                    continue;
+                }
+            } else {
+                // the revision 2 fields are garbage
+                return Some(Rsdp { length: 0, xsdt_address: 0, ..rsdp });
+            }
+            return Some(rsdp);
+        }
+    }
+    None
+}
+
+/// Maps the table at `address` read-only and validates its checksum.
+fn map_table(address: PhysicalAddress, memory_controller: &mut MemoryController) -> Option<&'static SdtHeader> {
+    let header_size = mem::size_of::<SdtHeader>() as u64;
+    memory_controller.identity_map(address, header_size, EntryFlags::NO_EXECUTE);
+
+    let header = unsafe { &*(address as *const SdtHeader) };
+    if (header.length() as u64) < header_size {
+        return None;
+    }
+    memory_controller.identity_map(address, header.length() as u64, EntryFlags::NO_EXECUTE);
+
+    if checksum_ok(header.as_bytes()) {
+        Some(header)
+    } else {
+        None
+    }
+}
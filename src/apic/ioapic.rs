@@ -0,0 +1,226 @@
+//! I/O APIC driver.
+//!
+//! Every I/O APIC listed in the MADT serves a contiguous range of global system
+//! interrupts (GSIs) through its redirection table. Legacy ISA IRQs are identity
+//! mapped to GSIs 0-15 unless the MADT has an interrupt source override for them,
+//! e.g. the PIT on IRQ 0 usually arrives on GSI 2.
+
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::ptr;
+use crate::acpi::{self, madt, Madt, MadtEntry};
+use crate::memory::MemoryController;
+use crate::sync::IrqSafeMutex;
+use spin::Once;
+
+/// Size of the I/O APIC MMIO register window.
+const IOAPIC_WINDOW_SIZE: u64 = 0x1000;
+
+// indirect registers, selected through IOREGSEL
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+/// The offset of the IOWIN data register from IOREGSEL.
+const IOWIN_OFFSET: usize = 0x10;
+
+/// Redirection entry bit 16, masks the interrupt.
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// When a redirected interrupt is signalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// Which pin level is asserted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// An ISA IRQ remapped by a MADT interrupt source override.
+#[derive(Debug, Clone, Copy)]
+struct SourceOverride {
+    irq: u8,
+    gsi: u32,
+    trigger: TriggerMode,
+    polarity: Polarity,
+}
+
+struct IoApic {
+    base: usize,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&mut self, reg: u32) -> u32 {
+        ptr::write_volatile(self.base as *mut u32, reg);
+        ptr::read_volatile((self.base + IOWIN_OFFSET) as *const u32)
+    }
+
+    unsafe fn write(&mut self, reg: u32, value: u32) {
+        ptr::write_volatile(self.base as *mut u32, reg);
+        ptr::write_volatile((self.base + IOWIN_OFFSET) as *mut u32, value);
+    }
+
+    fn handles_gsi(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+    }
+
+    unsafe fn read_redirection(&mut self, index: u32) -> u64 {
+        let low = self.read(IOREDTBL + 2 * index);
+        let high = self.read(IOREDTBL + 2 * index + 1);
+        u64::from(high) << 32 | u64::from(low)
+    }
+
+    unsafe fn write_redirection(&mut self, index: u32, entry: u64) {
+        // mask first so the entry never fires half written
+        self.write(IOREDTBL + 2 * index, REDIRECTION_MASKED as u32);
+        self.write(IOREDTBL + 2 * index + 1, (entry >> 32) as u32);
+        self.write(IOREDTBL + 2 * index, entry as u32);
+    }
+}
+
+static IO_APICS: Once<Vec<IrqSafeMutex<IoApic>>> = Once::new();
+static OVERRIDES: Once<Vec<SourceOverride>> = Once::new();
+
+/// Maps and masks every I/O APIC described in the MADT.
+///
+/// Returns false if there is no MADT or it lists no I/O APIC, the PICs have to stay
+/// in charge then.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    let madt = match acpi::find::<Madt>() {
+        Some(madt) => madt,
+        None => return false,
+    };
+
+    let mut io_apics = Vec::new();
+    let mut overrides = Vec::new();
+
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::IoApic { id, address, gsi_base } => {
+                memory_controller.identity_map_mmio(u64::from(address), IOAPIC_WINDOW_SIZE);
+
+                let mut io_apic = IoApic {
+                    base: address as usize,
+                    gsi_base,
+                    redirection_entries: 0,
+                };
+                unsafe {
+                    io_apic.redirection_entries = io_apic.read(IOAPICVER).get_bits(16..24) + 1;
+                    for index in 0..io_apic.redirection_entries {
+                        io_apic.write_redirection(index, REDIRECTION_MASKED);
+                    }
+                }
+
+                println!(
+                    "I/O APIC id={} at {:#x}, GSIs {}-{}, hw id={}",
+                    id,
+                    address,
+                    gsi_base,
+                    gsi_base + io_apic.redirection_entries - 1,
+                    unsafe { io_apic.read(IOAPICID).get_bits(24..28) }
+                );
+                io_apics.push(IrqSafeMutex::new(io_apic));
+            }
+            MadtEntry::InterruptSourceOverride { bus: 0, source, gsi, flags } => {
+                let polarity = match flags & madt::INTI_POLARITY_MASK {
+                    madt::INTI_POLARITY_ACTIVE_LOW => Polarity::ActiveLow,
+                    _ => Polarity::ActiveHigh,
+                };
+                let trigger = match flags & madt::INTI_TRIGGER_MASK {
+                    madt::INTI_TRIGGER_LEVEL => TriggerMode::Level,
+                    _ => TriggerMode::Edge,
+                };
+                println!(
+                    "    ISA IRQ {} -> GSI {} ({:?}, {:?})",
+                    source, gsi, trigger, polarity
+                );
+                overrides.push(SourceOverride { irq: source, gsi, trigger, polarity });
+            }
+            _ => (),
+        }
+    }
+
+    if io_apics.is_empty() {
+        return false;
+    }
+
+    IO_APICS.call_once(|| io_apics);
+    OVERRIDES.call_once(|| overrides);
+    true
+}
+
+/// Returns true once `init` found at least one I/O APIC.
+pub fn is_initialized() -> bool {
+    IO_APICS.r#try().is_some()
+}
+
+/// Returns the GSI, trigger mode and polarity an ISA IRQ is wired to.
+pub fn isa_irq_to_gsi(irq: u8) -> (u32, TriggerMode, Polarity) {
+    OVERRIDES
+        .r#try()
+        .and_then(|overrides| overrides.iter().find(|o| o.irq == irq))
+        .map(|o| (o.gsi, o.trigger, o.polarity))
+        .unwrap_or((u32::from(irq), TriggerMode::Edge, Polarity::ActiveHigh))
+}
+
+/// Returns whether `gsi` is served by one of the I/O APICs.
+pub fn has_gsi(gsi: u32) -> bool {
+    IO_APICS
+        .r#try()
+        .map(|io_apics| io_apics.iter().any(|io_apic| io_apic.lock().handles_gsi(gsi)))
+        .unwrap_or(false)
+}
+
+/// Delivers `gsi` as `vector` to the CPU with APIC ID `destination` and unmasks it.
+///
+/// Panics if no I/O APIC serves the GSI.
+pub fn route_gsi(gsi: u32, vector: u8, trigger: TriggerMode, polarity: Polarity, destination: u32) {
+    let mut entry: u64 = 0;
+    entry.set_bits(0..8, u64::from(vector));
+    // fixed delivery, physical destination
+    entry.set_bit(13, polarity == Polarity::ActiveLow);
+    entry.set_bit(15, trigger == TriggerMode::Level);
+    entry.set_bits(56..64, u64::from(destination));
+
+    with_gsi(gsi, |io_apic, index| unsafe { io_apic.write_redirection(index, entry) });
+}
+
+/// Routes an ISA IRQ, honouring the interrupt source overrides, to `vector` on the
+/// executing CPU.
+pub fn route_isa_irq(irq: u8, vector: u8) {
+    let (gsi, trigger, polarity) = isa_irq_to_gsi(irq);
+    route_gsi(gsi, vector, trigger, polarity, super::local().id());
+}
+
+/// Masks `gsi`.
+pub fn mask_gsi(gsi: u32) {
+    with_gsi(gsi, |io_apic, index| unsafe {
+        let entry = io_apic.read_redirection(index);
+        io_apic.write_redirection(index, entry | REDIRECTION_MASKED);
+    });
+}
+
+/// Masks the GSI an ISA IRQ is wired to.
+pub fn mask_isa_irq(irq: u8) {
+    mask_gsi(isa_irq_to_gsi(irq).0);
+}
+
+fn with_gsi<F: FnOnce(&mut IoApic, u32)>(gsi: u32, f: F) {
+    let io_apics = IO_APICS.r#try().expect("I/O APIC not initialized");
+    for io_apic in io_apics {
+        let mut io_apic = io_apic.lock();
+        if io_apic.handles_gsi(gsi) {
+            let index = gsi - io_apic.gsi_base;
+            f(&mut io_apic, index);
+            return;
+        }
+    }
+    panic!("no I/O APIC serves GSI {}", gsi);
+}
@@ -0,0 +1,315 @@
+//! Local APIC register access in either xAPIC (MMIO) or x2APIC (MSR) mode.
+
+use bit_field::BitField;
+use core::ptr;
+use crate::x86_64::instructions::interrupts;
+use crate::x86_64::registers::msr::{self, rdmsr, wrmsr};
+
+/// `IA32_APIC_BASE` bit 11, globally enables the local APIC.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// `IA32_APIC_BASE` bit 10, switches the local APIC into x2APIC mode.
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+/// `IA32_APIC_BASE` bits 12..52 hold the physical base of the xAPIC MMIO window.
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Spurious interrupt vector register bit 8, software enables the local APIC.
+const SIVR_APIC_ENABLE: u32 = 1 << 8;
+/// ICR bit 12, set while an IPI is still pending delivery (xAPIC only).
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// LVT bit 16, masks the local vector.
+pub const LVT_MASKED: u32 = 1 << 16;
+
+/// The vector used for spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Local APIC registers used by the kernel.
+///
+/// Each register has a fixed offset into the xAPIC MMIO window, and a matching
+/// `IA32_X2APIC_*` MSR when the APIC runs in x2APIC mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Id,
+    Version,
+    TaskPriority,
+    EndOfInterrupt,
+    LogicalDestination,
+    SpuriousVector,
+    ErrorStatus,
+    LvtCmci,
+    InterruptCommand,
+    LvtTimer,
+    LvtThermal,
+    LvtPerfCounter,
+    LvtLint0,
+    LvtLint1,
+    LvtError,
+    TimerInitialCount,
+    TimerCurrentCount,
+    TimerDivide,
+}
+
+impl Register {
+    /// Byte offset of the register in the xAPIC MMIO window.
+    fn xapic_offset(self) -> usize {
+        match self {
+            Register::Id => 0x20,
+            Register::Version => 0x30,
+            Register::TaskPriority => 0x80,
+            Register::EndOfInterrupt => 0xB0,
+            Register::LogicalDestination => 0xD0,
+            Register::SpuriousVector => 0xF0,
+            Register::ErrorStatus => 0x280,
+            Register::LvtCmci => 0x2F0,
+            Register::InterruptCommand => 0x300,
+            Register::LvtTimer => 0x320,
+            Register::LvtThermal => 0x330,
+            Register::LvtPerfCounter => 0x340,
+            Register::LvtLint0 => 0x350,
+            Register::LvtLint1 => 0x360,
+            Register::LvtError => 0x370,
+            Register::TimerInitialCount => 0x380,
+            Register::TimerCurrentCount => 0x390,
+            Register::TimerDivide => 0x3E0,
+        }
+    }
+
+    /// MSR number of the register in x2APIC mode.
+    fn x2apic_msr(self) -> u32 {
+        match self {
+            Register::Id => msr::IA32_X2APIC_APICID,
+            Register::Version => msr::IA32_X2APIC_VERSION,
+            Register::TaskPriority => msr::IA32_X2APIC_TPR,
+            Register::EndOfInterrupt => msr::IA32_X2APIC_EOI,
+            Register::LogicalDestination => msr::IA32_X2APIC_LDR,
+            Register::SpuriousVector => msr::IA32_X2APIC_SIVR,
+            Register::ErrorStatus => msr::IA32_X2APIC_ESR,
+            Register::LvtCmci => msr::IA32_X2APIC_LVT_CMCI,
+            Register::InterruptCommand => msr::IA32_X2APIC_ICR,
+            Register::LvtTimer => msr::IA32_X2APIC_LVT_TIMER,
+            Register::LvtThermal => msr::IA32_X2APIC_LVT_THERMAL,
+            Register::LvtPerfCounter => msr::IA32_X2APIC_LVT_PMI,
+            Register::LvtLint0 => msr::IA32_X2APIC_LVT_LINT0,
+            Register::LvtLint1 => msr::IA32_X2APIC_LVT_LINT1,
+            Register::LvtError => msr::IA32_X2APIC_LVT_ERROR,
+            Register::TimerInitialCount => msr::IA32_X2APIC_INIT_COUNT,
+            Register::TimerCurrentCount => msr::IA32_X2APIC_CUR_COUNT,
+            Register::TimerDivide => msr::IA32_X2APIC_DIV_CONF,
+        }
+    }
+}
+
+/// How the local APIC registers are accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    /// Memory mapped registers, 8-bit APIC IDs.
+    XApic,
+    /// MSR based registers, 32-bit APIC IDs.
+    X2Apic,
+}
+
+/// The delivery mode of an inter-processor interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+    LowestPriority = 0b001,
+    Smi = 0b010,
+    Nmi = 0b100,
+    Init = 0b101,
+    StartUp = 0b110,
+}
+
+/// The target of an inter-processor interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDestination {
+    /// A single CPU, identified by its APIC ID.
+    Physical(u32),
+    /// The sending CPU only.
+    Current,
+    /// Every CPU including the sender.
+    AllIncludingSelf,
+    /// Every CPU except the sender.
+    AllExcludingSelf,
+}
+
+impl IpiDestination {
+    /// The destination shorthand encoding for ICR bits 18..20.
+    fn shorthand(self) -> u32 {
+        match self {
+            IpiDestination::Physical(_) => 0b00,
+            IpiDestination::Current => 0b01,
+            IpiDestination::AllIncludingSelf => 0b10,
+            IpiDestination::AllExcludingSelf => 0b11,
+        }
+    }
+}
+
+/// A handle to the local APIC of the executing CPU.
+///
+/// All CPUs run their local APIC in the same mode and (in xAPIC mode) at the same
+/// physical address, so a single `LocalApic` is shared between them; every access
+/// always reaches the APIC of the CPU performing it.
+#[derive(Debug)]
+pub struct LocalApic {
+    mode: ApicMode,
+    /// Virtual address of the MMIO window, only meaningful in xAPIC mode.
+    base: usize,
+}
+
+impl LocalApic {
+    /// Creates a handle for the given mode.
+    ///
+    /// This function is unsafe because in xAPIC mode `base` must be the (mapped) virtual
+    /// address of the local APIC register window.
+    pub const unsafe fn new(mode: ApicMode, base: usize) -> LocalApic {
+        LocalApic { mode, base }
+    }
+
+    /// Returns the physical address of the xAPIC register window from `IA32_APIC_BASE`.
+    pub fn physical_base() -> u64 {
+        rdmsr(msr::IA32_APIC_BASE) & APIC_BASE_ADDR_MASK
+    }
+
+    /// Returns the register access mode in use.
+    pub fn mode(&self) -> ApicMode {
+        self.mode
+    }
+
+    /// Reads a 32-bit local APIC register.
+    pub fn read(&self, reg: Register) -> u32 {
+        match self.mode {
+            ApicMode::XApic => unsafe {
+                ptr::read_volatile((self.base + reg.xapic_offset()) as *const u32)
+            },
+            ApicMode::X2Apic => rdmsr(reg.x2apic_msr()) as u32,
+        }
+    }
+
+    /// Writes a 32-bit local APIC register.
+    ///
+    /// This function is unsafe because reprogramming the local APIC can redirect or
+    /// suppress interrupts.
+    pub unsafe fn write(&self, reg: Register, value: u32) {
+        match self.mode {
+            ApicMode::XApic => {
+                ptr::write_volatile((self.base + reg.xapic_offset()) as *mut u32, value)
+            }
+            ApicMode::X2Apic => wrmsr(reg.x2apic_msr(), u64::from(value)),
+        }
+    }
+
+    /// Globally and software enables the local APIC of the executing CPU.
+    ///
+    /// In x2APIC mode the APIC is switched out of xAPIC mode first; the transition must
+    /// pass through xAPIC mode, so the global enable bit is set before the x2APIC bit.
+    pub unsafe fn enable(&self) {
+        let mut base = rdmsr(msr::IA32_APIC_BASE) | APIC_BASE_ENABLE;
+        wrmsr(msr::IA32_APIC_BASE, base);
+
+        if self.mode == ApicMode::X2Apic {
+            base |= APIC_BASE_X2APIC;
+            wrmsr(msr::IA32_APIC_BASE, base);
+        }
+
+        // accept all interrupt priorities and mask the local interrupt pins
+        self.write(Register::TaskPriority, 0);
+        self.write(Register::LvtLint0, LVT_MASKED);
+        self.write(Register::LvtLint1, LVT_MASKED);
+        self.write(Register::LvtError, LVT_MASKED);
+
+        self.write(
+            Register::SpuriousVector,
+            SIVR_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+    }
+
+    /// Returns the APIC ID of the executing CPU.
+    ///
+    /// In xAPIC mode the ID is 8 bits wide, in x2APIC mode it is the full 32 bits.
+    pub fn id(&self) -> u32 {
+        let id = self.read(Register::Id);
+        match self.mode {
+            ApicMode::XApic => id >> 24,
+            ApicMode::X2Apic => id,
+        }
+    }
+
+    /// Returns the version register (version in bits 0..8, max LVT entry in bits 16..24).
+    pub fn version(&self) -> u32 {
+        self.read(Register::Version)
+    }
+
+    /// Reads and clears the error status register.
+    pub fn error_status(&self) -> u32 {
+        unsafe {
+            // the ESR must be written before it is read to latch the current errors
+            self.write(Register::ErrorStatus, 0);
+        }
+        self.read(Register::ErrorStatus)
+    }
+
+    /// Signals the end of the interrupt currently being serviced.
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(Register::EndOfInterrupt, 0) }
+    }
+
+    /// Sends an inter-processor interrupt.
+    ///
+    /// `vector` is ignored by the CPU for `Nmi` and `Init` delivery, and is the start
+    /// page number for `StartUp`.
+    ///
+    /// This function is unsafe because IPIs (`Init` and `StartUp` in particular) can
+    /// reset or redirect other processors.
+    pub unsafe fn send_ipi(&self, vector: u8, mode: DeliveryMode, dest: IpiDestination) {
+        let mut low: u32 = 0;
+        low.set_bits(0..8, u32::from(vector));
+        low.set_bits(8..11, mode as u32);
+        // INIT de-assert is the only IPI with level 0, everything else asserts
+        low.set_bit(14, true);
+        low.set_bits(18..20, dest.shorthand());
+
+        let target = match dest {
+            IpiDestination::Physical(id) => id,
+            _ => 0,
+        };
+
+        match self.mode {
+            ApicMode::XApic => {
+                let icr_low = (self.base + Register::InterruptCommand.xapic_offset()) as *mut u32;
+                let icr_high = (self.base + Register::InterruptCommand.xapic_offset() + 0x10) as *mut u32;
+
+                // an IPI sent by an interrupt handler between the two writes would
+                // replace the destination
+                interrupts::without_interrupts(|| {
+                    ptr::write_volatile(icr_high, target << 24);
+                    // writing the low half sends the IPI
+                    ptr::write_volatile(icr_low, low);
+
+                    while ptr::read_volatile(icr_low) & ICR_DELIVERY_PENDING != 0 {}
+                });
+            }
+            ApicMode::X2Apic => {
+                // a single 64-bit write, the destination lives in the upper half
+                wrmsr(
+                    msr::IA32_X2APIC_ICR,
+                    (u64::from(target) << 32) | u64::from(low),
+                );
+            }
+        }
+    }
+
+    /// Sends a fixed interrupt with the given vector to the executing CPU.
+    ///
+    /// x2APIC mode uses the dedicated `SELF_IPI` register, xAPIC mode falls back to the
+    /// ICR with the self destination shorthand.
+    pub fn send_self_ipi(&self, vector: u8) {
+        unsafe {
+            match self.mode {
+                ApicMode::X2Apic => wrmsr(msr::IA32_X2APIC_SELF_IPI, u64::from(vector)),
+                ApicMode::XApic => {
+                    self.send_ipi(vector, DeliveryMode::Fixed, IpiDestination::Current)
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Local APIC driver.
+//!
+//! The local APIC is run in x2APIC mode whenever CPUID advertises it, with the
+//! memory mapped xAPIC interface as the fallback. Code above this module only
+//! talks to `LocalApic`, which hides the difference between the two.
+//!
+//! External interrupts are routed through the I/O APICs found in the ACPI MADT, the
+//! legacy PICs are only used when there are none.
+
+pub mod ioapic;
+pub mod lapic;
+
+pub use self::lapic::{ApicMode, DeliveryMode, IpiDestination, LocalApic, Register};
+
+use crate::interrupts::pic;
+use crate::memory::MemoryController;
+use raw_cpuid::CpuId;
+use spin::Once;
+
+/// Size of the xAPIC MMIO register window.
+const XAPIC_WINDOW_SIZE: u64 = 0x1000;
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+/// Detects the best supported APIC mode, maps the register window if needed and
+/// enables the local APIC of the bootstrap processor. If the MADT describes I/O
+/// APICs they take over from the PICs.
+///
+/// Panics if the CPU has no local APIC at all.
+pub fn init(memory_controller: &mut MemoryController) {
+    let features = CpuId::new()
+        .get_feature_info()
+        .expect("CPUID leaf 1 not available");
+    assert!(features.has_apic(), "CPU has no local APIC");
+
+    let lapic = LOCAL_APIC.call_once(|| {
+        if features.has_x2apic() {
+            unsafe { LocalApic::new(ApicMode::X2Apic, 0) }
+        } else {
+            let base = LocalApic::physical_base();
+            memory_controller.identity_map_mmio(base, XAPIC_WINDOW_SIZE);
+            unsafe { LocalApic::new(ApicMode::XApic, base as usize) }
+        }
+    });
+
+    unsafe { lapic.enable() };
+
+    println!(
+        "Local APIC enabled in {:?} mode, id={}, version={:#x}",
+        lapic.mode(),
+        lapic.id(),
+        lapic.version() & 0xFF
+    );
+
+    if ioapic::init(memory_controller) {
+        pic::disable();
+    } else {
+        println!("No I/O APIC found, using the legacy PICs");
+    }
+}
+
+/// Enables the local APIC of an application processor in the mode chosen by `init`.
+pub fn init_ap() {
+    unsafe { local().enable() };
+}
+
+/// Returns the local APIC of the executing CPU.
+///
+/// Panics if `init` was not called yet.
+pub fn local() -> &'static LocalApic {
+    LOCAL_APIC.r#try().expect("local APIC not initialized")
+}
+
+/// Returns true once the local APIC has been initialized.
+pub fn is_initialized() -> bool {
+    LOCAL_APIC.r#try().is_some()
+}
@@ -0,0 +1,162 @@
+;;;
+; Application processor startup trampoline.
+;
+; The BSP copies everything between ap_trampoline_start and ap_trampoline_end to
+; AP_TRAMPOLINE_ADDR (below 1 MiB) and fills in the data block at the end before
+; sending the startup IPIs. An AP starts executing at the first byte in real mode,
+; switches through protected mode into long mode on the kernel page tables and
+; calls the Rust entry point on its own stack.
+;
+; The BSP opens the data block for one AP at a time by storing a fresh sequence
+; number, and closes it by storing 0. An AP claims the block, still in real mode,
+; by swapping the sequence number it read for 0, and only if the block names its
+; APIC id. An AP that starts late, after the BSP gave up on it, or that was never
+; asked to start parks in real mode and never touches the kernel page tables.
+;
+; The code never runs at its link address, so every absolute reference goes through
+; the REL macro.
+;;;
+
+AP_TRAMPOLINE_ADDR equ 0x8000
+
+%define REL(label) (AP_TRAMPOLINE_ADDR + (label) - ap_trampoline_start)
+
+global ap_trampoline_start
+global ap_trampoline_end
+global ap_trampoline_cr3
+global ap_trampoline_stack
+global ap_trampoline_entry
+global ap_trampoline_apic_id
+global ap_trampoline_sequence
+
+section .text
+bits 16
+ap_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    mov edi, [REL(ap_trampoline_sequence)]
+    test edi, edi
+    jz .park
+
+    ; the APIC id of this CPU, from the x2APIC topology leaf if there is one
+    xor eax, eax
+    cpuid
+    cmp eax, 0xB
+    jb .xapic_id
+    mov eax, 0xB
+    xor ecx, ecx
+    cpuid
+    test ebx, ebx
+    jz .xapic_id
+    mov esi, edx
+    jmp .check_id
+.xapic_id:
+    mov eax, 1
+    cpuid
+    shr ebx, 24
+    mov esi, ebx
+.check_id:
+    cmp esi, [REL(ap_trampoline_apic_id)]
+    jne .park
+
+    ; fails if the BSP closed the block or reopened it for another AP meanwhile
+    mov eax, edi
+    xor ebx, ebx
+    lock cmpxchg [REL(ap_trampoline_sequence)], ebx
+    jne .park
+
+    o32 lgdt [REL(trampoline_gdt.pointer)]
+
+    ; enable protected mode
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    jmp dword trampoline_gdt.code32:REL(.protected_mode)
+
+.park:
+    cli
+    hlt
+    jmp .park
+
+bits 32
+.protected_mode:
+    mov ax, trampoline_gdt.data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; enable PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    ; the kernel page tables, the BSP made sure they are below 4 GiB
+    mov eax, [REL(ap_trampoline_cr3)]
+    mov cr3, eax
+
+    ; set long mode and no-execute enable in EFER, the kernel tables use NX bits
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    ; enable paging and write protection
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
+
+    jmp trampoline_gdt.code64:REL(.long_mode)
+
+bits 64
+.long_mode:
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [REL(ap_trampoline_stack)]
+    xor rbp, rbp
+    mov rax, [REL(ap_trampoline_entry)]
+    call rax
+
+    ; the entry point never returns
+.halt:
+    cli
+    hlt
+    jmp .halt
+
+align 8
+trampoline_gdt:
+    dq 0
+.code32: equ $ - trampoline_gdt
+    dq 0x00CF9A000000FFFF   ; 32-bit code, base 0, limit 4 GiB
+.data: equ $ - trampoline_gdt
+    dq 0x00CF92000000FFFF   ; 32-bit data, base 0, limit 4 GiB
+.code64: equ $ - trampoline_gdt
+    dq (1<<43) | (1<<44) | (1<<47) | (1<<53) ; 64-bit code segment
+.pointer:
+    dw $ - trampoline_gdt - 1
+    dd REL(trampoline_gdt)
+
+; filled in by the BSP for every AP
+align 8
+ap_trampoline_cr3:
+    dq 0
+ap_trampoline_stack:
+    dq 0
+ap_trampoline_entry:
+    dq 0
+ap_trampoline_apic_id:
+    dd 0
+ap_trampoline_sequence:
+    dd 0
+
+ap_trampoline_end:
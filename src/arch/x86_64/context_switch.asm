@@ -0,0 +1,39 @@
+bits 64
+
+;;;
+; Kernel thread context switch.
+;
+; switch_context(save_rsp: *mut u64, next_rsp: u64, prev_running: *mut AtomicBool)
+;
+; Pushes the callee-saved registers on the current stack, stores the stack pointer
+; in save_rsp and clears prev_running, from then on another CPU may resume the
+; previous thread. It then loads next_rsp and pops the callee-saved registers of the
+; next thread, which were pushed by its own call to switch_context or prepared by
+; the thread creation code, and returns into that thread.
+;
+; Everything else is caller-saved in the System V ABI and already spilled by the
+; compiler around the call.
+;;;
+
+section .text
+global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov [rdi], rsp
+    ; nothing touches the previous stack after this store
+    mov byte [rdx], 0
+
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
@@ -0,0 +1,175 @@
+bits 64
+extern interrupt_dispatch
+
+;;;
+; Common entry trampoline for all 256 interrupt vectors.
+;
+; Every stub pushes a dummy error code (unless the cpu pushed a real one) and its
+; vector number, then jumps to interrupt_common. interrupt_common saves every
+; general purpose register so the stack holds an InterruptContext, calls
+; interrupt_dispatch(&mut InterruptContext) and restores the (possibly modified)
+; registers before returning with iretq.
+;
+; Interrupts from user mode arrive with the user GS base loaded. interrupt_common
+; executes swapgs when the saved CS has a non-zero RPL, so the kernel always runs
+; with GS pointing at the per-CPU area, and swaps back before returning to user mode.
+;
+; The vectors on IST stacks (NMI, double fault and machine check) enter through
+; paranoid_common instead. They can arrive in ring 0 right after a swapgs to the
+; user GS base, on the way out to user mode or on the way in from it, so the saved
+; CS says nothing about the GS base. paranoid_common reads IA32_GS_BASE and takes
+; it for the kernel per-CPU pointer if it is a non-zero address outside of the user
+; part of the address space, the per-CPU areas live on the kernel heap. Otherwise
+; it executes swapgs, and undoes exactly that before returning. This relies on the
+; user GS base always being 0: CR4.FSGSBASE stays clear, so user code can not
+; execute wrgsbase, and no system call sets the base.
+;
+; The table of stub addresses is exported as interrupt_stub_table for the IDT setup.
+;;;
+
+section .text
+%assign vec 0
+%rep 256
+align 16
+interrupt_stub_ %+ vec:
+%if vec == 8 || (vec >= 10 && vec <= 14) || vec == 17 || vec == 21 || vec == 29 || vec == 30
+                            ; the cpu pushed an error code
+%else
+    push 0                  ; dummy error code
+%endif
+    push vec
+%if vec == 2 || vec == 8 || vec == 18
+    jmp paranoid_common
+%else
+    jmp interrupt_common
+%endif
+%assign vec vec + 1
+%endrep
+
+interrupt_common:
+    ; stack: vector, error code, rip, cs, rflags, rsp, ss
+    test qword [rsp + 24], 3    ; RPL of the interrupted code segment
+    jz .from_kernel
+    swapgs
+.from_kernel:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    ; the cpu aligned rsp to 16 bytes before pushing the frame, the frame, the
+    ; error code, the vector and 15 registers (176 bytes) keep it aligned
+    mov rdi, rsp            ; &mut InterruptContext
+    cld
+    call interrupt_dispatch
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    add rsp, 16             ; drop the vector number and error code
+    test qword [rsp + 8], 3
+    jz .to_kernel
+    swapgs
+.to_kernel:
+    iretq
+
+; GS bases from USER_SPACE_START up to USER_SPACE_END and 0 are not kernel
+; per-CPU pointers
+%define USER_SPACE_START 0x0000008000000000
+%define USER_SPACE_END 0x0000800000000000
+
+paranoid_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov ecx, 0xC0000101         ; IA32_GS_BASE
+    rdmsr
+    shl rdx, 32
+    or rax, rdx
+    xor ebx, ebx                ; callee saved, set if swapgs was executed
+    test rax, rax
+    jz .user_gs
+    mov rdx, USER_SPACE_START
+    cmp rax, rdx
+    jb .kernel_gs
+    mov rdx, USER_SPACE_END
+    cmp rax, rdx
+    jae .kernel_gs
+.user_gs:
+    swapgs
+    mov ebx, 1
+.kernel_gs:
+
+    mov rdi, rsp                ; &mut InterruptContext
+    cld
+    call interrupt_dispatch
+
+    test ebx, ebx
+    jz .gs_restored
+    swapgs
+.gs_restored:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    add rsp, 16                 ; drop the vector number and error code
+    iretq
+
+section .rodata
+global interrupt_stub_table
+align 8
+interrupt_stub_table:
+%assign vec 0
+%rep 256
+    dq interrupt_stub_ %+ vec
+%assign vec vec + 1
+%endrep
@@ -0,0 +1,61 @@
+bits 64
+extern syscall_dispatch
+
+; GS offsets of CpuLocal::kernel_stack and CpuLocal::user_stack, see percpu.rs
+%define KERNEL_STACK 8
+%define USER_STACK 16
+
+;;;
+; Entry point of the SYSCALL instruction, IA32_LSTAR points here.
+;
+; The CPU arrives in ring 0 with the user RIP in rcx, the user RFLAGS in r11 and
+; IF cleared by IA32_FMASK, but still on the user stack and with the user GS base.
+; syscall_entry swaps to the per-CPU area, saves the user stack pointer in its
+; scratch slot and switches to the kernel stack of the running thread. It then
+; pushes a SyscallFrame, moves the user stack pointer into it before interrupts
+; are enabled (another thread may use the scratch slot once this one is switched
+; out), and calls syscall_dispatch(&mut SyscallFrame).
+;
+; The argument registers are restored from the frame, so user code sees every
+; register except rax, rcx and r11 unchanged. syscall_dispatch makes sure the
+; return RIP is canonical, SYSRET would fault in ring 0 on the user stack otherwise.
+;;;
+
+section .text
+global syscall_entry
+syscall_entry:
+    swapgs
+    mov [gs:USER_STACK], rsp
+    mov rsp, [gs:KERNEL_STACK]
+
+    push qword [gs:USER_STACK]  ; rsp
+    push r11                    ; rflags
+    push rcx                    ; rip
+    push rax                    ; number, the return value on the way out
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+
+    ; the kernel stack top is 16 byte aligned, so is the 80 byte frame
+    mov rdi, rsp                ; &mut SyscallFrame
+    sti
+    cld
+    call syscall_dispatch
+    cli
+
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop rcx
+    pop r11
+    pop rsp
+
+    swapgs
+    o64 sysret
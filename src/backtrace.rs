@@ -0,0 +1,40 @@
+//! Stack walking over the saved frame pointer chain.
+//!
+//! Relies on `-C force-frame-pointers=yes` (see `.cargo/config.toml`), every frame
+//! then starts with the caller's `rbp` followed by the return address.
+
+const MAX_FRAMES: usize = 32;
+
+/// Prints the return addresses reachable from `rbp`, starting with `rip`.
+///
+/// Uses unlocked serial output so it can be called from NMI context.
+pub fn print(rip: u64, rbp: u64) {
+    emergency_println!("backtrace:");
+    emergency_println!("    #0  {:#018x}", rip);
+
+    let mut rbp = rbp;
+    for depth in 1..MAX_FRAMES {
+        if !is_plausible_frame(rbp) {
+            break;
+        }
+
+        let frame = rbp as *const u64;
+        let (next_rbp, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            break;
+        }
+        emergency_println!("    #{:<2} {:#018x}", depth, return_address);
+
+        // stacks grow down, so the caller's frame must be above this one
+        if next_rbp <= rbp {
+            break;
+        }
+        rbp = next_rbp;
+    }
+}
+
+/// Rejects frame pointers that can not point at a kernel stack frame.
+fn is_plausible_frame(rbp: u64) -> bool {
+    let canonical = rbp < 0x0000_8000_0000_0000 || rbp >= 0xffff_8000_0000_0000;
+    rbp != 0 && rbp % 8 == 0 && canonical
+}
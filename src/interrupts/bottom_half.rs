@@ -0,0 +1,152 @@
+//! Bottom halves, the deferred part of interrupt handling.
+//!
+//! An interrupt handler, the top half, runs with interrupts disabled and should only
+//! do what can not wait: acknowledge the device, read its status and save data that
+//! would otherwise be lost. The rest goes into a `BottomHalf` the handler schedules.
+//! Scheduled bottom halves run on the CPU that scheduled them when the dispatcher
+//! returns from the outermost interrupt, with interrupts enabled and before the
+//! interrupted thread may be preempted.
+//!
+//! Bottom halves still run in interrupt context: they must not block, and data they
+//! share with threads needs an `IrqSafeMutex`. Work that has to sleep belongs on a
+//! `workqueue`.
+//!
+//! A bottom half scheduled several times before it runs, runs once. It never runs
+//! on two CPUs at the same time, scheduling it while it runs makes it run again
+//! afterwards. An interrupt exit makes at most `MAX_ROUNDS` passes over the pending
+//! bottom halves, what is scheduled after that waits for the next interrupt or the
+//! idle loop.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::sync::IrqSafeMutex;
+use crate::thread::scheduler;
+use crate::x86_64::instructions::interrupts;
+
+/// Passes over the pending bottom halves per interrupt exit.
+const MAX_ROUNDS: usize = 10;
+
+/// Deferred interrupt work, scheduled by a top half.
+pub struct BottomHalf {
+    name: &'static str,
+    handler: Box<dyn Fn() + Send + Sync>,
+    /// Whether it is in a pending list.
+    pending: AtomicBool,
+    /// Set while a CPU runs the handler.
+    running: AtomicBool,
+    runs: AtomicU64,
+}
+
+percpu! {
+    static PENDING: IrqSafeMutex<Vec<Arc<BottomHalf>>> = IrqSafeMutex::new(Vec::new());
+    /// Set while the CPU runs bottom halves, interrupts arriving meanwhile leave the
+    /// bottom halves they schedule to it.
+    static ACTIVE: AtomicBool = AtomicBool::new(false);
+}
+
+impl BottomHalf {
+    /// Creates a bottom half running `handler`, `name` shows up in debug output.
+    pub fn new<F>(name: &'static str, handler: F) -> Arc<BottomHalf>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Arc::new(BottomHalf {
+            name,
+            handler: Box::new(handler),
+            pending: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            runs: AtomicU64::new(0),
+        })
+    }
+
+    /// Schedules the bottom half on the executing CPU.
+    ///
+    /// Returns false if it was already pending. Can be called in interrupt context.
+    pub fn schedule(self: &Arc<Self>) -> bool {
+        if self.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        interrupts::without_interrupts(|| PENDING.get().lock().push(self.clone()));
+        true
+    }
+
+    /// The name given to `new`.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns whether the bottom half is scheduled and did not run yet.
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// How often the handler ran.
+    pub fn runs(&self) -> u64 {
+        self.runs.load(Ordering::Relaxed)
+    }
+
+    /// Runs the handler, or puts the bottom half back if another CPU runs it.
+    fn run(self: Arc<Self>) {
+        if self.running.swap(true, Ordering::Acquire) {
+            // still pending, the next round tries again
+            interrupts::without_interrupts(|| PENDING.get().lock().push(self));
+            return;
+        }
+        self.pending.store(false, Ordering::Release);
+        (self.handler)();
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.running.store(false, Ordering::Release);
+    }
+}
+
+impl fmt::Debug for BottomHalf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BottomHalf")
+            .field("name", &self.name)
+            .field("pending", &self.is_pending())
+            .field("runs", &self.runs())
+            .finish()
+    }
+}
+
+/// Returns whether bottom halves are pending on the executing CPU.
+pub fn has_pending() -> bool {
+    !PENDING.get().lock().is_empty()
+}
+
+/// Runs the bottom halves pending on the executing CPU.
+///
+/// Must be called with interrupts disabled, which are enabled while the handlers
+/// run. Called by the interrupt dispatcher after the end-of-interrupt and by the
+/// idle loop; does nothing if the CPU already runs bottom halves further up the
+/// stack.
+pub fn run_pending() {
+    debug_assert!(!interrupts::are_enabled());
+    let active = ACTIVE.get();
+    if active.load(Ordering::Relaxed) || !has_pending() {
+        return;
+    }
+    active.store(true, Ordering::Relaxed);
+    // a nested interrupt must not switch threads in the middle of a handler
+    scheduler::preempt_disable();
+
+    for _ in 0..MAX_ROUNDS {
+        let pending = mem::replace(&mut *PENDING.get().lock(), Vec::new());
+        if pending.is_empty() {
+            break;
+        }
+        unsafe { interrupts::enable() };
+        for bottom_half in pending {
+            bottom_half.run();
+        }
+        unsafe { interrupts::disable() };
+    }
+
+    // interrupts are disabled, this does not switch threads either
+    scheduler::preempt_enable();
+    active.store(false, Ordering::Relaxed);
+}
@@ -0,0 +1,74 @@
+//! The register state saved by the interrupt entry trampoline.
+
+use core::fmt;
+use crate::x86_64::structures::idt::ExceptionStackFrame;
+
+/// General purpose registers in the order `interrupt_entry.asm` saves them
+/// (lowest address first).
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct GeneralRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// The complete state of the interrupted code as laid out on the stack by the
+/// entry trampoline.
+///
+/// Every field can be modified by a handler, the trampoline restores the registers
+/// and returns through `frame` with `iretq`.
+#[repr(C)]
+pub struct InterruptContext {
+    /// The interrupted general purpose registers.
+    pub registers: GeneralRegisters,
+    /// The vector that was raised.
+    pub vector: u64,
+    /// The error code pushed by the CPU, or 0 for vectors without one.
+    pub error_code: u64,
+    /// The frame pushed by the CPU.
+    pub frame: ExceptionStackFrame,
+}
+
+impl InterruptContext {
+    /// The vector that was raised.
+    pub fn vector(&self) -> u8 {
+        self.vector as u8
+    }
+
+    /// Returns whether the interrupted code ran in user mode.
+    pub fn from_user(&self) -> bool {
+        self.frame.code_segment & 3 == 3
+    }
+}
+
+impl fmt::Debug for InterruptContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.registers;
+        writeln!(f, "vector {} error code {:#x}", self.vector, self.error_code)?;
+        writeln!(f, "rax={:#018x} rbx={:#018x} rcx={:#018x} rdx={:#018x}", r.rax, r.rbx, r.rcx, r.rdx)?;
+        writeln!(f, "rsi={:#018x} rdi={:#018x} rbp={:#018x} rsp={:#018x}", r.rsi, r.rdi, r.rbp, self.frame.stack_pointer.0)?;
+        writeln!(f, "r8 ={:#018x} r9 ={:#018x} r10={:#018x} r11={:#018x}", r.r8, r.r9, r.r10, r.r11)?;
+        writeln!(f, "r12={:#018x} r13={:#018x} r14={:#018x} r15={:#018x}", r.r12, r.r13, r.r14, r.r15)?;
+        write!(
+            f,
+            "rip={:#018x} cs={:#x} rflags={:#x} ss={:#x}",
+            self.frame.instruction_pointer.0,
+            self.frame.code_segment,
+            self.frame.cpu_flags,
+            self.frame.stack_segment
+        )
+    }
+}
@@ -0,0 +1,87 @@
+//! CPU exception handlers (vectors 0-31).
+
+use super::context::InterruptContext;
+use crate::x86_64::registers::control_regs;
+use crate::x86_64::structures::idt::PageFaultErrorCode;
+
+pub const DIVIDE_BY_ZERO: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const MACHINE_CHECK: u8 = 18;
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "DIVIDE BY ZERO",
+    "DEBUG",
+    "NON MASKABLE INTERRUPT",
+    "BREAKPOINT",
+    "OVERFLOW",
+    "BOUND RANGE EXCEEDED",
+    "INVALID OPCODE",
+    "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT",
+    "COPROCESSOR SEGMENT OVERRUN",
+    "INVALID TSS",
+    "SEGMENT NOT PRESENT",
+    "STACK SEGMENT FAULT",
+    "GENERAL PROTECTION FAULT",
+    "PAGE FAULT",
+    "RESERVED",
+    "X87 FLOATING POINT",
+    "ALIGNMENT CHECK",
+    "MACHINE CHECK",
+    "SIMD FLOATING POINT",
+    "VIRTUALIZATION",
+    "CONTROL PROTECTION",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "HYPERVISOR INJECTION",
+    "VMM COMMUNICATION",
+    "SECURITY EXCEPTION",
+    "RESERVED",
+];
+
+/// Returns the name of an exception vector.
+pub fn name(vector: u8) -> &'static str {
+    EXCEPTION_NAMES
+        .get(usize::from(vector))
+        .cloned()
+        .unwrap_or("UNKNOWN")
+}
+
+/// Handles the exception described by `context`, panics for unrecoverable ones.
+///
+/// Exceptions raised by user code end the thread that raised them instead.
+pub fn handle(context: &mut InterruptContext) {
+    match context.vector() {
+        NON_MASKABLE_INTERRUPT => super::nmi::handle(context),
+        MACHINE_CHECK => crate::mce::handle(context),
+        _ if context.from_user() => crate::user::handle_fault(context),
+        BREAKPOINT => {
+            println!("EXCEPTION: BREAKPOINT\n{:#?}", context);
+        }
+        PAGE_FAULT => {
+            let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+            panic!(
+                "EXCEPTION: PAGE FAULT accessing {:#x}, {:?}\n{:#?}",
+                control_regs::cr2(),
+                error_code,
+                context
+            );
+        }
+        vector => {
+            panic!(
+                "EXCEPTION: {}\n{:#?}",
+                EXCEPTION_NAMES[vector as usize], context
+            );
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Global descriptor table and task state segment of each CPU.
+//!
+//! Long mode ignores almost everything in the segment descriptors, but the CPU still
+//! needs a code segment to run in and a TSS to find the interrupt stacks. Every CPU
+//! gets its own GDT and TSS, since the TSS descriptor is marked busy when loaded and
+//! the interrupt stacks must not be shared.
+//!
+//! The user segments follow the kernel segments, data before code, in the order
+//! `sysret` expects them. Interrupts from user mode switch to the stack in
+//! `TSS.privilege_stack_table[0]`, the kernel stack of the running thread.
+
+use alloc::boxed::Box;
+use bit_field::BitField;
+use core::mem::size_of;
+use crate::memory::MemoryController;
+use crate::x86_64::instructions::segmentation::{load_ds, load_es, load_ss, set_cs};
+use crate::x86_64::instructions::tables::{lgdt, load_tss};
+use crate::x86_64::structures::gdt::SegmentSelector;
+use crate::x86_64::structures::tss::TaskStateSegment;
+use crate::x86_64::structures::DescriptorTablePointer;
+use crate::x86_64::{PrivilegeLevel, VirtualAddress};
+
+/// IST slot used by the double fault handler, a kernel stack overflow must not
+/// fault again while pushing the exception frame.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// IST slot used by the NMI handler, an NMI can arrive in the middle of a stack switch.
+pub const NMI_IST_INDEX: u16 = 1;
+/// IST slot used by the machine check handler.
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const IST_STACK_PAGES: usize = 4;
+
+/// Selectors of the kernel and user segments, the same in every GDT.
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+const GDT_ENTRIES: usize = 8;
+
+pub struct Gdt {
+    table: [u64; GDT_ENTRIES],
+    next_free: usize,
+}
+
+impl Gdt {
+    pub fn new() -> Gdt {
+        Gdt {
+            table: [0; GDT_ENTRIES],
+            next_free: 1,
+        }
+    }
+
+    pub fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
+        let index = match entry {
+            Descriptor::UserSegment(value) => self.push(value),
+            Descriptor::SystemSegment(value_low, value_high) => {
+                let index = self.push(value_low);
+                self.push(value_high);
+                index
+            }
+        };
+
+        let dpl = match entry {
+            Descriptor::UserSegment(value) => PrivilegeLevel::from_u16(value.get_bits(45..47) as u16),
+            Descriptor::SystemSegment(..) => PrivilegeLevel::Ring0,
+        };
+        SegmentSelector::new(index as u16, dpl)
+    }
+
+    fn push(&mut self, value: u64) -> usize {
+        if self.next_free < self.table.len() {
+            let index = self.next_free;
+            self.table[index] = value;
+            self.next_free += 1;
+            index
+        } else {
+            panic!("GDT full");
+        }
+    }
+
+    pub fn load(&'static self) {
+        let ptr = DescriptorTablePointer {
+            base: self.table.as_ptr() as u64,
+            limit: (self.table.len() * size_of::<u64>() - 1) as u16,
+        };
+
+        unsafe { lgdt(&ptr) };
+    }
+}
+
+pub enum Descriptor {
+    UserSegment(u64),
+    SystemSegment(u64, u64),
+}
+
+bitflags! {
+    struct DescriptorFlags: u64 {
+        const WRITABLE          = 1 << 41;
+        const CONFORMING        = 1 << 42;
+        const EXECUTABLE        = 1 << 43;
+        const USER_SEGMENT      = 1 << 44;
+        const DPL_RING_3        = 3 << 45;
+        const PRESENT           = 1 << 47;
+        const LONG_MODE         = 1 << 53;
+    }
+}
+
+impl Descriptor {
+    pub fn kernel_code_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT
+            | DescriptorFlags::PRESENT
+            | DescriptorFlags::EXECUTABLE
+            | DescriptorFlags::LONG_MODE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn kernel_data_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT | DescriptorFlags::WRITABLE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_code_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT
+            | DescriptorFlags::PRESENT
+            | DescriptorFlags::EXECUTABLE
+            | DescriptorFlags::LONG_MODE
+            | DescriptorFlags::DPL_RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_data_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT
+            | DescriptorFlags::PRESENT
+            | DescriptorFlags::WRITABLE
+            | DescriptorFlags::DPL_RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
+        let ptr = tss as *const _ as u64;
+
+        let mut low = DescriptorFlags::PRESENT.bits();
+        // base
+        low.set_bits(16..40, ptr.get_bits(0..24));
+        low.set_bits(56..64, ptr.get_bits(24..32));
+        // limit (the `-1` in needed since the bound is inclusive)
+        low.set_bits(0..16, (size_of::<TaskStateSegment>() - 1) as u64);
+        // type (0b1001 = available 64-bit tss)
+        low.set_bits(40..44, 0b1001);
+
+        let mut high = 0;
+        high.set_bits(0..32, ptr.get_bits(32..64));
+
+        Descriptor::SystemSegment(low, high)
+    }
+}
+
+/// Creates a TSS with freshly allocated interrupt stacks.
+pub fn new_tss(memory_controller: &mut MemoryController) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    for &index in &[DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX] {
+        let stack = memory_controller
+            .alloc_stack(IST_STACK_PAGES)
+            .expect("could not allocate an interrupt stack");
+        tss.interrupt_stack_table[index as usize] = VirtualAddress(stack.top() as usize);
+    }
+    tss
+}
+
+/// Loads a new GDT with the kernel and user segments and `tss` on the executing CPU
+/// and reloads the segment registers.
+///
+/// The TSS is returned so the CPU can update its stack pointers later.
+pub fn load(tss: TaskStateSegment) -> &'static mut TaskStateSegment {
+    let tss: &'static mut TaskStateSegment = Box::leak(Box::new(tss));
+
+    let mut gdt = Box::new(Gdt::new());
+    let code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_ptr: *const TaskStateSegment = tss;
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss_ptr }));
+    assert_eq!(code.0, KERNEL_CODE_SELECTOR.0);
+    assert_eq!(data.0, KERNEL_DATA_SELECTOR.0);
+    assert_eq!(user_data.0, USER_DATA_SELECTOR.0);
+    assert_eq!(user_code.0, USER_CODE_SELECTOR.0);
+    assert_eq!(tss_selector.0, TSS_SELECTOR.0);
+
+    let gdt: &'static Gdt = Box::leak(gdt);
+    gdt.load();
+
+    unsafe {
+        set_cs(code);
+        load_ss(SegmentSelector(data.0));
+        load_ds(SegmentSelector(data.0));
+        load_es(SegmentSelector(data.0));
+        load_tss(tss_selector);
+    }
+    tss
+}
@@ -0,0 +1,165 @@
+//! Dynamic interrupt handler registration for vectors 32-255.
+//!
+//! The entry trampoline hands every vector in that range to `dispatch`. It runs all
+//! handlers registered for the vector, which allows several devices to share a line,
+//! counts the interrupt and sends the end-of-interrupt to the right controller.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::context::InterruptContext;
+use crate::percpu;
+use crate::x86_64::instructions::interrupts;
+use spin::RwLock;
+
+use lazy_static::lazy_static;
+
+/// The first vector that can be registered, everything below is a CPU exception.
+pub const FIRST_VECTOR: u8 = 32;
+const VECTOR_COUNT: usize = 256 - FIRST_VECTOR as usize;
+
+/// Tells the dispatcher whether a handler serviced the interrupt.
+///
+/// On a shared line every handler runs in registration order, even after one of them
+/// returned `Handled`: on an edge triggered line several devices may have raised the
+/// one interrupt, and an event a handler skips is lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The interrupt was raised by this handler's device and has been serviced.
+    Handled,
+    /// The interrupt did not come from this handler's device.
+    NotHandled,
+}
+
+/// A plain handler function. `data` is the value passed to `register_irq`.
+///
+/// Changes to the registers in `context` are restored when the interrupt returns.
+pub type IrqHandler = fn(context: &mut InterruptContext, data: usize) -> IrqReturn;
+
+type BoxedHandler = Box<dyn Fn(&mut InterruptContext) -> IrqReturn + Send + Sync>;
+
+/// Errors returned by the registration functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The vector is a CPU exception and can not be registered.
+    ReservedVector(u8),
+    /// No handler with the given id is registered.
+    NotRegistered,
+}
+
+/// Identifies a registered handler, needed to unregister it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqId {
+    vector: u8,
+    id: u64,
+}
+
+impl IrqId {
+    /// The vector the handler is registered for.
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+struct IrqAction {
+    id: u64,
+    handler: BoxedHandler,
+}
+
+struct IrqLine {
+    actions: RwLock<Vec<IrqAction>>,
+    count: AtomicU64,
+    unhandled: AtomicU64,
+}
+
+lazy_static! {
+    static ref IRQ_LINES: Vec<IrqLine> = (0..VECTOR_COUNT)
+        .map(|_| IrqLine {
+            actions: RwLock::new(Vec::new()),
+            count: AtomicU64::new(0),
+            unhandled: AtomicU64::new(0),
+        })
+        .collect();
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn line(vector: u8) -> Result<&'static IrqLine, IrqError> {
+    if vector < FIRST_VECTOR {
+        return Err(IrqError::ReservedVector(vector));
+    }
+    Ok(&IRQ_LINES[(vector - FIRST_VECTOR) as usize])
+}
+
+/// Registers a handler closure for the vector.
+///
+/// Handlers run in interrupt context with interrupts disabled and must not block.
+/// The end-of-interrupt is sent by the dispatcher, handlers must not send it themselves.
+pub fn register_irq_with<F>(vector: u8, handler: F) -> Result<IrqId, IrqError>
+where
+    F: Fn(&mut InterruptContext) -> IrqReturn + Send + Sync + 'static,
+{
+    let line = line(vector)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let action = IrqAction {
+        id,
+        handler: Box::new(handler),
+    };
+
+    // a local interrupt on this vector would spin on the read lock forever
+    interrupts::without_interrupts(|| line.actions.write().push(action));
+    Ok(IrqId { vector, id })
+}
+
+/// Registers `handler` for the vector, `data` is passed back on every call.
+pub fn register_irq(vector: u8, handler: IrqHandler, data: usize) -> Result<IrqId, IrqError> {
+    register_irq_with(vector, move |context| handler(context, data))
+}
+
+/// Removes a previously registered handler.
+pub fn unregister_irq(irq: IrqId) -> Result<(), IrqError> {
+    let line = line(irq.vector)?;
+
+    let removed = interrupts::without_interrupts(|| {
+        let mut actions = line.actions.write();
+        match actions.iter().position(|a| a.id == irq.id) {
+            Some(index) => Some(actions.remove(index)),
+            None => None,
+        }
+    });
+
+    // drop the handler outside of the interrupt free section
+    match removed {
+        Some(_) => Ok(()),
+        None => Err(IrqError::NotRegistered),
+    }
+}
+
+/// Returns how often the vector was raised since boot.
+pub fn count(vector: u8) -> u64 {
+    line(vector).map(|l| l.count.load(Ordering::Relaxed)).unwrap_or(0)
+}
+
+/// Returns how often the vector was raised without any handler servicing it.
+pub fn unhandled_count(vector: u8) -> u64 {
+    line(vector).map(|l| l.unhandled.load(Ordering::Relaxed)).unwrap_or(0)
+}
+
+/// Runs the handlers of an interrupt from vector 32 to 255.
+pub(super) fn dispatch(context: &mut InterruptContext) {
+    let vector = context.vector();
+    let line = &IRQ_LINES[(vector - FIRST_VECTOR) as usize];
+    line.count.fetch_add(1, Ordering::Relaxed);
+    percpu::local().interrupts.fetch_add(1, Ordering::Relaxed);
+
+    let mut handled = false;
+    for action in line.actions.read().iter() {
+        handled |= (action.handler)(context) == IrqReturn::Handled;
+    }
+
+    if !handled {
+        line.unhandled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    super::end_of_interrupt(vector);
+}
@@ -0,0 +1,122 @@
+//! Interrupt descriptor table, CPU exception handlers and end-of-interrupt routing.
+//!
+//! Every vector enters through the trampoline in `interrupt_entry.asm`, which saves
+//! the complete register state as an `InterruptContext` before calling
+//! `interrupt_dispatch`. Device interrupts are followed by the pending bottom halves
+//! and a possible thread switch.
+
+pub mod bottom_half;
+pub mod context;
+pub mod exceptions;
+pub mod gdt;
+pub mod irq;
+pub mod nmi;
+pub mod pic;
+pub mod vectors;
+
+pub use self::bottom_half::BottomHalf;
+pub use self::context::{GeneralRegisters, InterruptContext};
+pub use self::irq::{register_irq, register_irq_with, unregister_irq, IrqError, IrqId, IrqReturn};
+
+use crate::apic;
+use crate::memory::MemoryController;
+use crate::x86_64::structures::idt::Idt;
+use crate::x86_64::structures::tss::TaskStateSegment;
+
+use lazy_static::lazy_static;
+
+extern "C" {
+    /// Entry stub addresses for all 256 vectors, defined in `interrupt_entry.asm`.
+    static interrupt_stub_table: [u64; 256];
+}
+
+lazy_static! {
+    static ref IDT: Idt = {
+        let mut idt = Idt::new();
+        for vector in 0..256 {
+            unsafe {
+                idt.set_handler_addr(vector, interrupt_stub_table[vector]);
+            }
+        }
+        unsafe {
+            let ist_vectors = [
+                (exceptions::DOUBLE_FAULT, gdt::DOUBLE_FAULT_IST_INDEX),
+                (exceptions::NON_MASKABLE_INTERRUPT, gdt::NMI_IST_INDEX),
+                (exceptions::MACHINE_CHECK, gdt::MACHINE_CHECK_IST_INDEX),
+            ];
+            for &(vector, ist_index) in ist_vectors.iter() {
+                let vector = usize::from(vector);
+                idt.set_handler_addr(vector, interrupt_stub_table[vector])
+                    .set_stack_index(ist_index);
+            }
+        }
+        idt
+    };
+}
+
+/// Loads the GDT and TSS, remaps the PICs and loads the IDT on the bootstrap processor.
+///
+/// Interrupts stay disabled, drivers register their handlers before they are enabled.
+/// Returns the loaded TSS for the per-CPU area.
+pub fn init(memory_controller: &mut MemoryController) -> &'static mut TaskStateSegment {
+    let tss = gdt::load(gdt::new_tss(memory_controller));
+    pic::init();
+    IDT.load();
+    tss
+}
+
+/// Loads a GDT with `tss` and the shared IDT on an application processor.
+pub fn init_ap(tss: TaskStateSegment) -> &'static mut TaskStateSegment {
+    let tss = gdt::load(tss);
+    IDT.load();
+    tss
+}
+
+/// Sends the end-of-interrupt for the vector to the controller that raised it.
+pub fn end_of_interrupt(vector: u8) {
+    if pic::is_enabled() && pic::handles_interrupt(vector) {
+        pic::end_of_interrupt(vector);
+    } else if vector != apic::lapic::SPURIOUS_VECTOR && apic::is_initialized() {
+        apic::local().end_of_interrupt();
+    }
+}
+
+/// Returns the vector a legacy ISA IRQ is delivered on, with either controller.
+pub fn isa_vector(irq: u8) -> u8 {
+    pic::PIC1_OFFSET + irq
+}
+
+/// Unmasks a legacy ISA IRQ on the I/O APIC, or on the PICs if there is none.
+///
+/// The interrupt arrives on `isa_vector(irq)`.
+pub fn enable_isa_irq(irq: u8) {
+    if apic::ioapic::is_initialized() {
+        apic::ioapic::route_isa_irq(irq, isa_vector(irq));
+    } else {
+        pic::unmask(irq);
+    }
+}
+
+/// Masks a legacy ISA IRQ.
+pub fn disable_isa_irq(irq: u8) {
+    if apic::ioapic::is_initialized() {
+        apic::ioapic::mask_isa_irq(irq);
+    } else {
+        pic::mask(irq);
+    }
+}
+
+/// Called by `interrupt_common` or, for the IST vectors, `paranoid_common` in
+/// `interrupt_entry.asm` for every vector.
+#[no_mangle]
+pub extern "C" fn interrupt_dispatch(context: &mut InterruptContext) {
+    if context.vector() < irq::FIRST_VECTOR {
+        exceptions::handle(context);
+    } else {
+        irq::dispatch(context);
+        bottom_half::run_pending();
+        // the interrupt is acknowledged, the thread may be switched out right here and
+        // returns from the interrupt when it is scheduled again
+        crate::thread::scheduler::preempt_on_interrupt_return();
+    }
+}
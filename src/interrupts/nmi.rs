@@ -0,0 +1,22 @@
+//! Non-maskable interrupt handling.
+//!
+//! NMIs can arrive while the interrupted code holds any lock, including the console
+//! lock, so everything in here prints with `emergency_println!`.
+
+use super::context::InterruptContext;
+use crate::{backtrace, watchdog};
+
+/// Handles an NMI: watchdog NMIs are passed to the watchdog, anything else (e.g. the
+/// QEMU monitor `nmi` command or a hardware error) is reported with the interrupted
+/// context and a backtrace.
+pub fn handle(context: &mut InterruptContext) {
+    if watchdog::handle_nmi(context) {
+        return;
+    }
+
+    emergency_println!("!!! [NMI] !!!\n{:#?}", context);
+    backtrace::print(
+        context.frame.instruction_pointer.0 as u64,
+        context.registers.rbp,
+    );
+}
@@ -0,0 +1,172 @@
+//! Driver for the legacy pair of cascaded 8259 programmable interrupt controllers.
+//!
+//! The PICs are remapped to vectors 32-47 so they do not collide with CPU exceptions,
+//! and every line starts out masked. Drivers unmask the lines they registered for.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::x86_64::instructions::interrupts;
+use crate::x86_64::instructions::port::Port;
+use spin::Mutex;
+
+/// Vector of IRQ 0 on the master PIC.
+pub const PIC1_OFFSET: u8 = 32;
+/// Vector of IRQ 8 on the slave PIC.
+pub const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
+
+/// The master IRQ line the slave PIC is cascaded on.
+const CASCADE_IRQ: u8 = 2;
+
+const CMD_INIT: u8 = 0x11;
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+const CMD_READ_ISR: u8 = 0x0B;
+const MODE_8086: u8 = 0x01;
+
+struct Pic {
+    offset: u8,
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    fn handles_interrupt(&self, vector: u8) -> bool {
+        self.offset <= vector && vector < self.offset + 8
+    }
+
+    unsafe fn end_of_interrupt(&mut self) {
+        self.command.write(CMD_END_OF_INTERRUPT);
+    }
+
+    unsafe fn in_service(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read()
+    }
+}
+
+struct ChainedPics {
+    pics: [Pic; 2],
+}
+
+impl ChainedPics {
+    const unsafe fn new(offset1: u8, offset2: u8) -> ChainedPics {
+        ChainedPics {
+            pics: [
+                Pic {
+                    offset: offset1,
+                    command: Port::new(0x20),
+                    data: Port::new(0x21),
+                },
+                Pic {
+                    offset: offset2,
+                    command: Port::new(0xA0),
+                    data: Port::new(0xA1),
+                },
+            ],
+        }
+    }
+
+    unsafe fn initialize(&mut self) {
+        // writes to an unused port give the PICs time to react on old hardware
+        let mut wait_port: Port<u8> = Port::new(0x80);
+        let mut wait = || wait_port.write(0);
+
+        // start the initialization sequence (ICW1)
+        self.pics[0].command.write(CMD_INIT);
+        wait();
+        self.pics[1].command.write(CMD_INIT);
+        wait();
+
+        // ICW2: vector offsets
+        self.pics[0].data.write(self.pics[0].offset);
+        wait();
+        self.pics[1].data.write(self.pics[1].offset);
+        wait();
+
+        // ICW3: cascade wiring
+        self.pics[0].data.write(1 << CASCADE_IRQ);
+        wait();
+        self.pics[1].data.write(CASCADE_IRQ);
+        wait();
+
+        // ICW4: 8086 mode
+        self.pics[0].data.write(MODE_8086);
+        wait();
+        self.pics[1].data.write(MODE_8086);
+        wait();
+
+        // mask everything but the cascade line
+        self.pics[0].data.write(!(1 << CASCADE_IRQ));
+        self.pics[1].data.write(0xFF);
+    }
+
+    unsafe fn set_masked(&mut self, irq: u8, masked: bool) {
+        let pic = &mut self.pics[(irq / 8) as usize];
+        let bit = 1 << (irq % 8);
+        let mask = pic.data.read();
+        pic.data.write(if masked { mask | bit } else { mask & !bit });
+    }
+}
+
+static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC1_OFFSET, PIC2_OFFSET) });
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Remaps both PICs to `PIC1_OFFSET`/`PIC2_OFFSET` with every line masked.
+pub fn init() {
+    interrupts::without_interrupts(|| unsafe { PICS.lock().initialize() });
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Masks every line, used once the I/O APIC takes over interrupt routing.
+pub fn disable() {
+    interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        pics.pics[0].data.write(0xFF);
+        pics.pics[1].data.write(0xFF);
+    });
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// Returns whether the PICs are in use.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Unmasks the given IRQ line (0-15).
+pub fn unmask(irq: u8) {
+    assert!(irq < 16, "invalid PIC irq {}", irq);
+    interrupts::without_interrupts(|| unsafe { PICS.lock().set_masked(irq, false) });
+}
+
+/// Masks the given IRQ line (0-15).
+pub fn mask(irq: u8) {
+    assert!(irq < 16, "invalid PIC irq {}", irq);
+    interrupts::without_interrupts(|| unsafe { PICS.lock().set_masked(irq, true) });
+}
+
+/// Returns whether the vector belongs to one of the PIC lines.
+pub fn handles_interrupt(vector: u8) -> bool {
+    vector >= PIC1_OFFSET && vector < PIC2_OFFSET + 8
+}
+
+/// Acknowledges the interrupt on the PIC(s) that raised it.
+///
+/// Spurious IRQ 7 and IRQ 15 (the in-service bit is not set) are detected here; the
+/// function returns false for them and only acknowledges the cascade where required.
+pub fn end_of_interrupt(vector: u8) -> bool {
+    // called from interrupt context, the lock is only held with interrupts disabled
+    let mut pics = PICS.lock();
+    unsafe {
+        if vector == PIC1_OFFSET + 7 && pics.pics[0].in_service() & 0x80 == 0 {
+            return false;
+        }
+        if vector == PIC2_OFFSET + 7 && pics.pics[1].in_service() & 0x80 == 0 {
+            pics.pics[0].end_of_interrupt();
+            return false;
+        }
+
+        if pics.pics[1].handles_interrupt(vector) {
+            pics.pics[1].end_of_interrupt();
+        }
+        pics.pics[0].end_of_interrupt();
+    }
+    true
+}
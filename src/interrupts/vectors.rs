@@ -0,0 +1,13 @@
+//! Interrupt vectors with a fixed assignment.
+
+/// The local APIC timer of every CPU.
+pub const LAPIC_TIMER: u8 = 0xF0;
+
+/// Corrected machine check interrupt.
+pub const CMCI: u8 = 0xF1;
+
+/// Inter-processor interrupt asking a CPU to invalidate TLB entries.
+pub const TLB_SHOOTDOWN: u8 = 0xF2;
+
+/// Inter-processor interrupt asking a CPU to reschedule.
+pub const RESCHEDULE: u8 = 0xF3;
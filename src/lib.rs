@@ -33,14 +33,32 @@ extern crate lazy_static;
 
 pub mod x86_64;
 
+pub mod sync;
+
 #[macro_use]
 pub mod serial;
 
 pub mod memory;
 
+#[macro_use]
+pub mod percpu;
+
 #[macro_use]
 pub mod vga_buffer;
 
+pub mod acpi;
+pub mod apic;
+pub mod backtrace;
+pub mod interrupts;
+pub mod mce;
+pub mod smp;
+pub mod task;
+pub mod thread;
+pub mod time;
+pub mod user;
+pub mod watchdog;
+pub mod workqueue;
+
 // external crates in scope
 use core::panic::PanicInfo;
 use raw_cpuid::CpuId;
@@ -48,7 +66,6 @@ use raw_cpuid::CpuId;
 // internal crates in scope
 use memory::heap_allocator::BCAlloc;
 
-// This is not safe for multithreaded heap allocations
 #[global_allocator]
 static KALLOC: BCAlloc = BCAlloc::new();
 
@@ -62,7 +79,7 @@ pub extern "C" fn rust_main(mb2_header: u32) {
     println!("Booting in x64 long mode from multiboot...");
 
     let boot_info = unsafe { multiboot2::load(mb2_header as usize) };
-    memory::init(&boot_info);
+    let mut memory_controller = memory::init(&boot_info);
 
     let cpuid = CpuId::new();
     match cpuid.get_vendor_info() {
@@ -75,12 +92,241 @@ pub extern "C" fn rust_main(mb2_header: u32) {
     }
     /* TODO: maybe more infomation about cacheline or cache topology here */
 
+    let tss = interrupts::init(&mut memory_controller);
+    acpi::init(&mut memory_controller);
+    apic::init(&mut memory_controller);
+    percpu::init(0, apic::local().id(), tss);
+    thread::init();
+    user::syscall::init_cpu();
+    mce::init();
+
+    if watchdog::WATCHDOG_ENABLE {
+        watchdog::init(watchdog::WATCHDOG_TIMEOUT_SECS);
+    }
+
     heap_test();
-    
-    
-    // jump to real rust main
 
-    // returns back to start function (start64)
+    time::init(&mut memory_controller);
+    smp::init(&mut memory_controller);
+    memory::install_controller(memory_controller);
+    workqueue::init();
+    unsafe { x86_64::instructions::interrupts::enable() };
+
+    thread_test();
+    task_test();
+    deferred_test();
+    user_test();
+    elf_test();
+
+    // this is the idle thread from here on, everything else runs in threads or is
+    // driven by interrupts
+    thread::idle_loop();
+}
+
+fn thread_test() {
+    println!("Thread test running");
+
+    let handles: alloc::vec::Vec<_> = (0..3)
+        .map(|n| {
+            thread::spawn("test", move || {
+                for step in 0..3 {
+                    println!("thread {} step {} on cpu {}", n, step, percpu::cpu_index());
+                    thread::yield_now();
+                }
+                n * 10
+            })
+        })
+        .collect();
+
+    // pin the first thread to the last CPU
+    if let Some(last) = thread::online_cpus().iter().last() {
+        thread::set_affinity(handles[0].thread(), smp::CpuSet::single(last));
+    }
+
+    for handle in handles {
+        let thread = handle.thread().clone();
+        let result = handle.join();
+        println!(
+            "thread {:?} returned {} after {:?} of CPU time",
+            thread.id(),
+            result,
+            thread.cpu_time()
+        );
+    }
+
+    println!("Thread test completed");
+}
+
+fn task_test() {
+    println!("Task test running");
+
+    let (sender, mut receiver) = task::channel();
+    let producer = task::spawn(async move {
+        for n in 1..4 {
+            task::sleep(time::Duration::from_millis(10)).await;
+            sender.send(n).expect("receiver dropped");
+        }
+    });
+
+    let sum = task::block_on(async move {
+        let mut sum = 0;
+        while let Some(n) = receiver.recv().await {
+            sum += n;
+        }
+        producer.await;
+        sum
+    });
+
+    println!("Task test completed, received sum {}", sum);
+}
+
+fn deferred_test() {
+    println!("Deferred work test running");
+
+    let done = alloc::sync::Arc::new(sync::Event::new());
+    let signal = done.clone();
+    // runs after the next interrupt and hands the part that sleeps to a worker
+    let bottom_half = interrupts::BottomHalf::new("test", move || {
+        let signal = signal.clone();
+        workqueue::queue_work(move || {
+            thread::sleep(time::Duration::from_millis(10));
+            signal.set();
+        });
+    });
+    bottom_half.schedule();
+
+    done.wait();
+    workqueue::system().flush();
+    println!(
+        "Deferred work test completed, bottom half ran {} times",
+        bottom_half.runs()
+    );
+}
+
+fn user_test() {
+    use memory::paging::{EntryFlags, USER_SPACE_START};
+    use memory::PAGE_SIZE;
+
+    println!("User mode test running");
+
+    let code = USER_SPACE_START;
+    let data = code + PAGE_SIZE;
+    let stack_top = data + 2 * PAGE_SIZE;
+    // inc qword [rip + 0xff9], the counter at the start of the data page; then hlt,
+    // which is privileged and kills the thread
+    let faulting: [u8; 8] = [0x48, 0xff, 0x05, 0xf9, 0x0f, 0x00, 0x00, 0xf4];
+    // write(1, message, 21) and exit(0), followed by the message
+    let hello_offset = 0x100;
+    let mut hello = vec![
+        0x48, 0x8d, 0x35, 0x1a, 0x00, 0x00, 0x00, // lea rsi, [rip + 0x1a]
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+        0xba, 0x15, 0x00, 0x00, 0x00, // mov edx, 21
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, WRITE
+        0x0f, 0x05, // syscall
+        0x31, 0xff, // xor edi, edi
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0x0f, 0x05, // syscall
+    ];
+    hello.extend_from_slice(b"Hello from user mode\n");
+
+    {
+        let mut controller = memory::controller();
+        controller.map_user(code, PAGE_SIZE, EntryFlags::WRITABLE);
+        unsafe {
+            core::ptr::copy_nonoverlapping(faulting.as_ptr(), code as *mut u8, faulting.len());
+            let target = (code + hello_offset) as *mut u8;
+            core::ptr::copy_nonoverlapping(hello.as_ptr(), target, hello.len());
+        }
+        controller.protect_user(code, PAGE_SIZE, EntryFlags::empty());
+        controller.map_user(data, 2 * PAGE_SIZE, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+    }
+
+    let faulting = thread::spawn("user", move || user::enter_user(code, stack_top));
+    faulting.wait();
+    let hello = thread::spawn("user", move || user::enter_user(code + hello_offset, stack_top));
+    hello.wait();
+    println!(
+        "User mode test completed, counter {}",
+        unsafe { *(data as *const u64) }
+    );
+}
+
+fn elf_test() {
+    use user::elf::{ET_DYN, EM_X86_64, PT_DYNAMIC, PT_LOAD, R_X86_64_RELATIVE};
+
+    println!("ELF loader test running");
+
+    // a static PIE linked at 0: a read only segment with the headers, code and
+    // message, and a writable one with a pointer to the message, fixed up by a
+    // relative relocation, the dynamic section, the relocation and .bss
+    let mut image = vec![0u8; 0x1060];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    put(0, b"\x7fELF\x02\x01\x01");
+    put(16, &ET_DYN.to_le_bytes());
+    put(18, &EM_X86_64.to_le_bytes());
+    put(20, &1u32.to_le_bytes());
+    put(24, &0x100u64.to_le_bytes()); // entry
+    put(32, &0x40u64.to_le_bytes()); // program headers
+    put(52, &64u16.to_le_bytes());
+    put(54, &56u16.to_le_bytes());
+    put(56, &3u16.to_le_bytes());
+    // kind, flags, offset, address, file size, memory size and alignment
+    let segments: [(u32, u32, u64, u64, u64); 3] = [
+        (PT_LOAD, 5, 0, 0x19a, 0x19a),
+        (PT_LOAD, 6, 0x1000, 0x60, 0x2000),
+        (PT_DYNAMIC, 6, 0x1008, 0x40, 0x40),
+    ];
+    for (index, &(kind, flags, offset, file_size, mem_size)) in segments.iter().enumerate() {
+        let header = 0x40 + index * 56;
+        put(header, &kind.to_le_bytes());
+        put(header + 4, &flags.to_le_bytes());
+        let fields = [offset, offset, offset, file_size, mem_size, 0x1000];
+        for (field, value) in fields.iter().enumerate() {
+            put(header + 8 + field * 8, &value.to_le_bytes());
+        }
+    }
+    let code: &[u8] = &[
+        0x48, 0x8b, 0x35, 0xf9, 0x0e, 0x00, 0x00, // mov rsi, [rip + 0xef9], the pointer
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+        0xba, 0x1a, 0x00, 0x00, 0x00, // mov edx, 26
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, WRITE
+        0x0f, 0x05, // syscall
+        0x48, 0x8b, 0x3c, 0x24, // mov rdi, [rsp], argc
+        0x48, 0x03, 0x3d, 0xdd, 0x16, 0x00, 0x00, // add rdi, [rip + 0x16dd], zero in .bss
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0x0f, 0x05, // syscall
+    ];
+    put(0x100, code);
+    put(0x180, b"Hello from an ELF program\n");
+    // DT_RELA, DT_RELASZ, DT_RELAENT and DT_NULL, then the relocation of the pointer
+    for (index, value) in [7u64, 0x1048, 8, 24, 9, 24, 0, 0].iter().enumerate() {
+        put(0x1008 + index * 8, &value.to_le_bytes());
+    }
+    put(0x1048, &0x1000u64.to_le_bytes());
+    put(0x1050, &u64::from(R_X86_64_RELATIVE).to_le_bytes());
+    put(0x1058, &0x180u64.to_le_bytes());
+
+    // exits with argc, 2
+    let init = thread::spawn("init", move || {
+        let program = match user::loader::load(&image, &["init", "test"], &["TERM=vga"]) {
+            Ok(program) => program,
+            Err(error) => {
+                println!("ELF loader test failed: {:?}", error);
+                return;
+            }
+        };
+        println!(
+            "ELF program loaded, entry {:#x}, stack {:#x}",
+            program.entry(),
+            program.stack_pointer()
+        );
+        drop(image);
+        program.start()
+    });
+    init.wait();
+    println!("ELF loader test completed");
 }
 
 fn heap_test() {
@@ -145,6 +391,9 @@ fn __test_stack_probes() {
 #[lang = "panic_impl"]
 #[no_mangle]
 pub extern "C" fn kernel_panic(info: &PanicInfo) -> ! {
+    // the panicking code may hold the console lock, nothing else runs after this anyway
+    unsafe { serial::SERIAL1.force_unlock() };
+
     println!("!!! [OOPS] !!!\n\nPANIC at {:?}", info.location());
     println!("    {:#?}", info.message());
     println!("!!! [OOPS] !!!");
@@ -0,0 +1,265 @@
+//! Machine-check architecture support.
+//!
+//! `init` enables reporting in every bank and sets `CR4.MCE`. Uncorrected errors
+//! raise `#MC`, whose handler logs every valid bank and panics unless execution can
+//! safely continue. Corrected errors are only logged: they are either signalled
+//! through CMCI, whose handler leaves the logging to a bottom half, or picked up by
+//! `poll()`, which the kernel tick of every CPU runs periodically on its own banks.
+//!
+//! The QEMU monitor command `mce <cpu> <bank> <status> <mcg_status> <addr> <misc>`
+//! injects errors for testing.
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::apic::{self, Register};
+use crate::interrupts::{self, vectors, BottomHalf, InterruptContext, IrqReturn};
+use crate::serial;
+use crate::time;
+use crate::x86_64::registers::control_regs::{cr4, cr4_write, Cr4};
+use crate::x86_64::registers::msr::{self, rdmsr, wrmsr};
+use raw_cpuid::CpuId;
+
+use lazy_static::lazy_static;
+
+// IA32_MCG_CAP
+const MCG_CAP_COUNT_MASK: u64 = 0xFF;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+const MCG_CAP_CMCI_P: u64 = 1 << 10;
+
+// IA32_MCG_STATUS
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+// IA32_MCi_STATUS
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_EN: u64 = 1 << 60;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+
+// IA32_MCi_CTL2
+const MCI_CTL2_CMCI_EN: u64 = 1 << 30;
+const MCI_CTL2_THRESHOLD_MASK: u64 = 0x7FFF;
+
+/// Kernel ticks between two checks of a CPU's banks for corrected errors.
+const POLL_INTERVAL_TICKS: u64 = 5 * time::TICK_HZ as u64;
+
+/// Number of banks reported by `IA32_MCG_CAP`, 0 until `init` ran.
+static BANK_COUNT: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+    /// Logs the errors a CMCI signalled, on the CPU that received it.
+    static ref CMCI_POLL: Arc<BottomHalf> = BottomHalf::new("cmci", || {
+        poll();
+    });
+}
+
+fn bank_ctl(bank: u32) -> u32 {
+    msr::IA32_MC0_CTL + 4 * bank
+}
+
+fn bank_status(bank: u32) -> u32 {
+    msr::IA32_MC0_STATUS + 4 * bank
+}
+
+fn bank_addr(bank: u32) -> u32 {
+    msr::IA32_MC0_ADDR + 4 * bank
+}
+
+fn bank_misc(bank: u32) -> u32 {
+    msr::IA32_MC0_MISC + 4 * bank
+}
+
+fn bank_ctl2(bank: u32) -> u32 {
+    msr::IA32_MC0_CTL2 + bank
+}
+
+/// Enables machine-check reporting on the bootstrap processor.
+///
+/// Does nothing if the CPU lacks MCE or MCA support.
+pub fn init() {
+    let features = match CpuId::new().get_feature_info() {
+        Some(features) => features,
+        None => return,
+    };
+    if !features.has_mce() || !features.has_mca() {
+        println!("Machine check architecture not supported");
+        return;
+    }
+
+    let cap = rdmsr(msr::IA32_MCG_CAP);
+    let banks = (cap & MCG_CAP_COUNT_MASK) as u32;
+    BANK_COUNT.store(banks, Ordering::SeqCst);
+
+    if cap & MCG_CAP_CMCI_P != 0 {
+        interrupts::register_irq_with(vectors::CMCI, |_| {
+            CMCI_POLL.schedule();
+            IrqReturn::Handled
+        })
+        .expect("failed to register the CMCI handler");
+    }
+
+    init_ap();
+
+    println!(
+        "Machine check architecture enabled, {} banks{}",
+        banks,
+        if cap & MCG_CAP_CMCI_P != 0 { ", CMCI" } else { "" }
+    );
+}
+
+/// Enables machine-check reporting on the executing CPU, after `init` ran on the
+/// bootstrap processor.
+pub fn init_ap() {
+    let banks = BANK_COUNT.load(Ordering::SeqCst);
+    if banks == 0 {
+        return;
+    }
+    let cap = rdmsr(msr::IA32_MCG_CAP);
+
+    unsafe {
+        if cap & MCG_CAP_CTL_P != 0 {
+            wrmsr(msr::IA32_MCG_CTL, !0);
+        }
+
+        for bank in 0..banks {
+            wrmsr(bank_ctl(bank), !0);
+            // errors logged before the kernel took over are stale
+            wrmsr(bank_status(bank), 0);
+
+            if cap & MCG_CAP_CMCI_P != 0 {
+                let ctl2 = rdmsr(bank_ctl2(bank)) & !MCI_CTL2_THRESHOLD_MASK;
+                wrmsr(bank_ctl2(bank), ctl2 | MCI_CTL2_CMCI_EN | 1);
+            }
+        }
+
+        if cap & MCG_CAP_CMCI_P != 0 {
+            apic::local().write(Register::LvtCmci, u32::from(vectors::CMCI));
+        }
+
+        cr4_write(cr4() | Cr4::ENABLE_MACHINE_CHECK);
+    }
+}
+
+/// Polls the banks of the executing CPU every `POLL_INTERVAL_TICKS`, for CPUs
+/// without CMCI and errors that do not reach the CMCI threshold. Called from the
+/// tick of every CPU with the number of ticks the CPU has seen.
+pub fn tick(ticks: u64) {
+    if ticks % POLL_INTERVAL_TICKS == 0 && BANK_COUNT.load(Ordering::Relaxed) != 0 {
+        poll();
+    }
+}
+
+/// Logs and clears every corrected error in the banks of the executing CPU.
+///
+/// Returns the number of errors found.
+pub fn poll() -> usize {
+    let mut found = 0;
+    for bank in 0..BANK_COUNT.load(Ordering::Relaxed) {
+        let status = rdmsr(bank_status(bank));
+        if status & MCI_STATUS_VAL == 0 || status & MCI_STATUS_UC != 0 {
+            continue;
+        }
+
+        println!("[MCE] corrected error in bank {}", bank);
+        log_bank(bank, status, false);
+        unsafe { wrmsr(bank_status(bank), 0) };
+        found += 1;
+    }
+    found
+}
+
+/// The `#MC` exception handler.
+///
+/// Logs every valid bank and panics if the processor context is corrupt or the
+/// interrupted code can not be restarted; otherwise clears the banks and returns.
+pub fn handle(context: &mut InterruptContext) {
+    let mcg_status = rdmsr(msr::IA32_MCG_STATUS);
+    let mut fatal = mcg_status & MCG_STATUS_RIPV == 0;
+
+    emergency_println!(
+        "!!! [MACHINE CHECK] !!! mcg_status={:#x} (ripv={} eipv={})",
+        mcg_status,
+        mcg_status & MCG_STATUS_RIPV != 0,
+        mcg_status & MCG_STATUS_EIPV != 0
+    );
+
+    for bank in 0..BANK_COUNT.load(Ordering::Relaxed) {
+        let status = rdmsr(bank_status(bank));
+        if status & MCI_STATUS_VAL == 0 {
+            continue;
+        }
+
+        log_bank(bank, status, true);
+        if status & MCI_STATUS_PCC != 0 {
+            fatal = true;
+        }
+        unsafe { wrmsr(bank_status(bank), 0) };
+    }
+
+    if fatal {
+        panic!("unrecoverable machine check\n{:#?}", context);
+    }
+
+    unsafe { wrmsr(msr::IA32_MCG_STATUS, mcg_status & !MCG_STATUS_MCIP) };
+}
+
+/// Prints STATUS and, when valid, ADDR and MISC of a bank.
+fn log_bank(bank: u32, status: u64, unlocked: bool) {
+    let flag = |bit: u64, name: &'static str| if status & bit != 0 { name } else { "" };
+
+    let mut addr = None;
+    let mut misc = None;
+    if status & MCI_STATUS_ADDRV != 0 {
+        addr = Some(rdmsr(bank_addr(bank)));
+    }
+    if status & MCI_STATUS_MISCV != 0 {
+        misc = Some(rdmsr(bank_misc(bank)));
+    }
+
+    let print: fn(fmt::Arguments) = if unlocked {
+        serial::print_unlocked
+    } else {
+        serial::print
+    };
+
+    print(format_args!(
+        "    bank {}: status={:#018x} [{}] {}{}{}{}{} model specific={:#x}\n    addr={:x?} misc={:x?}\n",
+        bank,
+        status,
+        describe_error_code(status as u16),
+        flag(MCI_STATUS_UC, "UC "),
+        flag(MCI_STATUS_OVER, "OVER "),
+        flag(MCI_STATUS_EN, "EN "),
+        flag(MCI_STATUS_PCC, "PCC "),
+        flag(MCI_STATUS_ADDRV, "ADDRV"),
+        (status >> 16) as u16,
+        addr,
+        misc
+    ));
+}
+
+/// Classifies the architectural MCA error code (bits 0..16 of `IA32_MCi_STATUS`).
+fn describe_error_code(code: u16) -> &'static str {
+    match code {
+        0x0000 => "no error",
+        0x0001 => "unclassified",
+        0x0002 => "microcode ROM parity error",
+        0x0003 => "external error",
+        0x0004 => "FRC error",
+        0x0005 => "internal parity error",
+        0x0006 => "SMM handler code access violation",
+        0x0400 => "internal timer error",
+        0x0401..=0x04FF => "internal unclassified",
+        c if c & 0xEFFC == 0x000C => "generic cache hierarchy error",
+        c if c & 0xEFF0 == 0x0010 => "TLB error",
+        c if c & 0xEF80 == 0x0080 => "memory controller error",
+        c if c & 0xEF00 == 0x0100 => "cache hierarchy error",
+        c if c & 0xE800 == 0x0800 => "bus or interconnect error",
+        _ => "unknown error code",
+    }
+}
@@ -1,27 +1,144 @@
 use core::alloc::{GlobalAlloc, Layout};
-use core::sync::atomic::{AtomicUsize, Ordering};
 use core::ptr;
+use crate::sync::IrqSafeMutex;
 
 pub const HEAP_START: usize = 0x4000_0000;
 pub const HEAP_SIZE: usize = 32 * (1024 * 1024); // 32MB
 
-pub struct BCAlloc {
-    #[allow(dead_code)]
-    heap_start: usize,
+/// Granularity of the heap. Every block starts and ends at a multiple of it, so a
+/// freed block, and whatever is left of one, can always hold a `FreeBlock`.
+const BLOCK_SIZE: usize = 16;
+
+/// Header written into the first bytes of every free block.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
 
-    heap_end: usize,
-    next: AtomicUsize,
+struct Heap {
+    /// Free blocks below `next`, sorted by address. No two of them are adjacent and
+    /// none ends at `next`, adjacent blocks are merged when they are freed.
+    free: *mut FreeBlock,
+    /// Start of the part of the heap that was never allocated, or given back.
+    next: usize,
+    end: usize,
+}
+
+// the free blocks are only reached through the lock
+unsafe impl Send for Heap {}
+
+impl Heap {
+    /// Takes `size` bytes aligned to `align` from the first free block that holds them.
+    unsafe fn allocate_free(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut block = self.free;
+        while !block.is_null() {
+            let start = block as usize;
+            let end = start + (*block).size;
+            let alloc_start = BCAlloc::align_up(start, align);
+            if alloc_start <= end && end - alloc_start >= size {
+                self.set_next(prev, (*block).next);
+                // the padding in front and the rest behind stay free
+                self.deallocate(start, alloc_start - start);
+                self.deallocate(alloc_start + size, end - alloc_start - size);
+                return Some(alloc_start);
+            }
+            prev = block;
+            block = (*block).next;
+        }
+        None
+    }
+
+    /// Takes `size` bytes aligned to `align` from the never allocated part.
+    unsafe fn allocate_top(&mut self, size: usize, align: usize) -> Option<usize> {
+        let start = BCAlloc::align_up(self.next, align);
+        let end = start.checked_add(size)?;
+        if end > self.end {
+            return None;
+        }
+        let padding = self.next;
+        self.next = end;
+        self.deallocate(padding, start - padding);
+        Some(start)
+    }
+
+    /// Returns a block to the heap, merged with the free blocks next to it.
+    unsafe fn deallocate(&mut self, start: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
+
+        // find the free blocks around the block, and the one in front of `prev`
+        let mut before: *mut FreeBlock = ptr::null_mut();
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.free;
+        while !next.is_null() && (next as usize) < start {
+            before = prev;
+            prev = next;
+            next = (*next).next;
+        }
+
+        let mut end = start + size;
+        if !next.is_null() && end == next as usize {
+            end += (*next).size;
+            next = (*next).next;
+        }
+        let (link, start) = if !prev.is_null() && prev as usize + (*prev).size == start {
+            (before, prev as usize)
+        } else {
+            (prev, start)
+        };
+
+        if end == self.next {
+            // the last block, the never allocated part grows down over it
+            self.next = start;
+            self.set_next(link, next);
+        } else {
+            let block = start as *mut FreeBlock;
+            block.write(FreeBlock {
+                size: end - start,
+                next,
+            });
+            self.set_next(link, block);
+        }
+    }
+
+    /// Points the free block `prev`, or the list head if it is null, at `block`.
+    unsafe fn set_next(&mut self, prev: *mut FreeBlock, block: *mut FreeBlock) {
+        if prev.is_null() {
+            self.free = block;
+        } else {
+            (*prev).next = block;
+        }
+    }
+}
+
+/// The kernel heap: a first fit allocator over an address ordered list of free
+/// blocks, in front of a bump pointer into the part of the heap never used so far.
+///
+/// The heap is locked with interrupts disabled, interrupt handlers and bottom
+/// halves allocate and free memory as well.
+pub struct BCAlloc {
+    heap: IrqSafeMutex<Heap>,
 }
 
 impl BCAlloc {
     pub const fn new() -> Self {
         Self {
-            heap_start: HEAP_START,
-            heap_end: (HEAP_START + HEAP_SIZE),
-            next: AtomicUsize::new(HEAP_START),
+            heap: IrqSafeMutex::new(Heap {
+                free: ptr::null_mut(),
+                next: HEAP_START,
+                end: HEAP_START + HEAP_SIZE,
+            }),
         }
     }
 
+    /// Size and alignment of the block that serves `layout`.
+    fn block_layout(layout: Layout) -> (usize, usize) {
+        let size = BCAlloc::align_up(layout.size().max(1), BLOCK_SIZE);
+        (size, layout.align().max(BLOCK_SIZE))
+    }
+
     /// Align upwards. Returns the smallest x with alignment `align`
     /// so that x >= addr. The alignment must be a power of 2.
     fn align_up(addr: usize, align: usize) -> usize {
@@ -43,27 +160,19 @@ impl BCAlloc {
 
 unsafe impl GlobalAlloc for BCAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        loop {
-            let this_next = self.next.load(Ordering::Relaxed);
-            let alloc_start = BCAlloc::align_up(this_next, layout.align());
-            let alloc_end = alloc_start.saturating_add(layout.size());
-
-            if alloc_end <= self.heap_end {
-                let new_next = self
-                    .next
-                    .compare_and_swap(this_next, alloc_end, Ordering::Relaxed);
-                if new_next == this_next {
-                    return this_next as *mut u8;
-                }
-            } else {
-                // heap exhuasted
-                return ptr::null_mut(); // return null?
-            }
-        }
+        let (size, align) = BCAlloc::block_layout(layout);
+
+        let mut heap = self.heap.lock();
+        let start = match heap.allocate_free(size, align) {
+            Some(start) => Some(start),
+            None => heap.allocate_top(size, align),
+        };
+        // null if the heap is exhausted
+        start.map_or(ptr::null_mut(), |start| start as *mut u8)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // Leak memory for now
-        println!("[FIXME] Leaked {} bytes!", layout.size());
+        let (size, _) = BCAlloc::block_layout(layout);
+        self.heap.lock().deallocate(ptr as usize, size);
     }
 }
@@ -3,29 +3,43 @@
 // export submodules
 pub mod heap_allocator;
 pub mod paging;
+pub mod stack_allocator;
 
 // re-exports
 pub use self::paging::kernel_remap;
+pub use self::stack_allocator::Stack;
 
 // imports
 use self::paging::PhysicalAddress;
-use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use crate::memory;
 use multiboot2::{BootInformation, MemoryArea, MemoryAreaIter};
 use crate::x86_64::instructions::memory as x86mem;
+use spin::{Mutex, MutexGuard, Once};
 
 // TODO: This file needs to be refactored into sub modules
 
 pub const PAGE_SIZE: u64 = 4096;
 
+/// Frames below 1 MiB are never handed out, they hold the BIOS data, the ACPI
+/// RSDP and the AP startup trampoline.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Kernel stacks are allocated from this many pages right after the heap.
+const STACK_AREA_PAGES: u64 = 4096;
+
 // Debuging toggles
 pub const PRINT_DETAILED_KSYMS: bool = false;
 pub const FRAME_ALLOC_TEST: bool = false;
 pub const PAGING_TEST: bool = false;
 
 static INIT_CALLED: AtomicBool = AtomicBool::new(false);
+static CONTROLLER: Once<Mutex<MemoryController>> = Once::new();
+/// The P4 table set up by `kernel_remap`, kernel threads run on it.
+static KERNEL_P4: AtomicU64 = AtomicU64::new(0);
 
-pub fn init(mb_info: &BootInformation) {
+pub fn init(mb_info: &BootInformation) -> MemoryController {
     // make sure init() is only called once...this will panic but thats better than tainting the kernel.
     assert!(!INIT_CALLED.load(Ordering::Relaxed));
     INIT_CALLED.store(true, Ordering::Relaxed);
@@ -110,6 +124,7 @@ pub fn init(mb_info: &BootInformation) {
     x86mem::enable_nxe();
     let mut active_table = memory::kernel_remap(&mut frame_allocator, &mb_info);
     x86mem::enable_write_protect();
+    KERNEL_P4.store(active_table.address_space(), Ordering::Relaxed);
 
     // map heap
     let heap_start_page = Page::containing_address(HEAP_START as u64);
@@ -124,6 +139,257 @@ pub fn init(mb_info: &BootInformation) {
     }
 
     println!("Initial kernel heap @ {:#x}, size={}", HEAP_START, HEAP_SIZE/1024);
+
+    let stack_alloc_start = heap_end_page + 1;
+    let stack_alloc_end = stack_alloc_start + STACK_AREA_PAGES;
+    let stack_allocator = {
+        let stack_alloc_range = Page::range_inclusive(stack_alloc_start, stack_alloc_end);
+        stack_allocator::StackAllocator::new(stack_alloc_range)
+    };
+    // the page after the stack area gives access to frames that are not mapped
+    let temp_page = paging::TemporaryPage::new(stack_alloc_end + 1, &mut frame_allocator);
+
+    MemoryController {
+        active_table,
+        frame_allocator,
+        stack_allocator,
+        temp_page,
+    }
+}
+
+/// Returns the physical address of the kernel's P4 table.
+pub fn kernel_address_space() -> PhysicalAddress {
+    KERNEL_P4.load(Ordering::Relaxed)
+}
+
+/// Hands the memory controller to the rest of the kernel once the boot code that
+/// takes it by reference is done.
+pub fn install_controller(controller: MemoryController) {
+    let mut controller = Some(controller);
+    CONTROLLER.call_once(|| controller.take().unwrap());
+    assert!(controller.is_none(), "memory controller installed twice");
+}
+
+/// Locks the memory controller installed with `install_controller`.
+///
+/// The lock keeps interrupts enabled, so other CPUs waiting for it still answer TLB
+/// shootdowns. It must not be taken in interrupt context.
+pub fn controller() -> MutexGuard<'static, MemoryController> {
+    CONTROLLER
+        .r#try()
+        .expect("memory controller not installed")
+        .lock()
+}
+
+/// Owns the active page table and the frame allocator once the kernel has been remapped,
+/// so that drivers can map additional memory after `init`.
+pub struct MemoryController {
+    active_table: paging::ActivePageTable,
+    frame_allocator: AreaFrameAllocator,
+    stack_allocator: stack_allocator::StackAllocator,
+    temp_page: paging::TemporaryPage,
+}
+
+impl MemoryController {
+    /// Allocates a kernel stack of `size_in_pages` pages below a guard page.
+    pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
+        let MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut stack_allocator,
+            ..
+        } = *self;
+        stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages)
+    }
+
+    /// Returns a stack from `alloc_stack` once nothing runs on it anymore.
+    pub fn free_stack(&mut self, stack: Stack) {
+        self.stack_allocator.free_stack(stack);
+    }
+
+    /// Returns the physical address of the active P4 table.
+    pub fn p4_address(&self) -> PhysicalAddress {
+        crate::x86_64::registers::control_regs::cr3().0
+    }
+
+    /// Returns the physical address `address` is mapped to, if it is mapped.
+    pub fn translate(&self, address: paging::VirtualAddress) -> Option<PhysicalAddress> {
+        self.active_table.translate(address)
+    }
+
+    /// Identity maps `size` bytes of device memory starting at `phys` as uncached and
+    /// non executable. Frames that are already mapped are left untouched.
+    pub fn identity_map_mmio(&mut self, phys: PhysicalAddress, size: u64) {
+        use self::paging::entry::EntryFlags;
+
+        self.identity_map(
+            phys,
+            size,
+            EntryFlags::WRITABLE
+                | EntryFlags::WRITE_THROUGH
+                | EntryFlags::NO_CACHE
+                | EntryFlags::NO_EXECUTE,
+        );
+    }
+
+    /// Identity maps `size` bytes starting at `phys` with `flags`. Frames that are
+    /// already mapped are left untouched.
+    pub fn identity_map(&mut self, phys: PhysicalAddress, size: u64, flags: paging::EntryFlags) {
+        let start_frame = Frame::containing_addr(phys);
+        let end_frame = Frame::containing_addr(phys + size - 1);
+
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            if self.active_table.translate(frame.start_address()).is_some() {
+                continue;
+            }
+            self.active_table.identity_map(frame, flags, &mut self.frame_allocator);
+        }
+    }
+
+    /// Maps `size` bytes at the user address `start` to zeroed frames, accessible
+    /// from user mode with `flags`.
+    ///
+    /// Panics if the range leaves the user part of the address space or is already
+    /// mapped.
+    pub fn map_user(&mut self, start: paging::VirtualAddress, size: u64, flags: paging::EntryFlags) {
+        use self::paging::EntryFlags;
+
+        for page in user_pages(start, size) {
+            // writable until the frame is cleared, it may hold another user's data
+            let writable = flags | EntryFlags::USER_ACCESSIBLE | EntryFlags::WRITABLE;
+            self.active_table.map(page, writable, &mut self.frame_allocator);
+            unsafe {
+                core::ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE as usize);
+            }
+        }
+        if !flags.contains(EntryFlags::WRITABLE) {
+            self.protect_user(start, size, flags);
+        }
+    }
+
+    /// Replaces the flags of a range mapped with `map_user`, `USER_ACCESSIBLE` is
+    /// added by default.
+    pub fn protect_user(&mut self, start: paging::VirtualAddress, size: u64, flags: paging::EntryFlags) {
+        use self::paging::EntryFlags;
+
+        self.active_table
+            .update_flags_range(user_pages(start, size), flags | EntryFlags::USER_ACCESSIBLE);
+    }
+
+    /// Creates the page table of a new address space, with the kernel part of the
+    /// active table and an empty user part.
+    ///
+    /// The kernel part is shared through the P4 entries present now, P4 entry 0 holds
+    /// all kernel mappings. The table and its frames are never freed.
+    pub fn new_address_space(&mut self) -> paging::InactivePageTable {
+        let frame = self.frame_allocator.allocate_frame().expect("out of memory");
+        let mut table =
+            paging::InactivePageTable::new(frame, &mut self.active_table, &mut self.temp_page);
+
+        // a P4 entry covers 512 GiB, the recursive entry 511 already points to the
+        // new table
+        let first_user = (paging::USER_SPACE_START >> 39) as usize;
+        let end_user = (paging::USER_SPACE_END >> 39) as usize;
+        let kernel_entries: Vec<_> = (0..511)
+            .filter(|&index| index < first_user || index >= end_user)
+            .filter_map(|index| {
+                let entry = &self.active_table.p4()[index];
+                entry.pointed_frame().map(|frame| (index, frame, entry.flags()))
+            })
+            .collect();
+        self.active_table.with(&mut table, &mut self.temp_page, |mapper| {
+            for (index, frame, flags) in kernel_entries {
+                mapper.p4_mut()[index].set(frame, flags);
+            }
+        });
+        table
+    }
+
+    /// Maps `size` bytes at the user address `start` of the inactive `table` to new
+    /// zeroed frames, accessible from user mode with `flags`.
+    ///
+    /// `fill` initializes the pages, it is called with the address of every page
+    /// and its contents.
+    ///
+    /// Panics if the range leaves the user part of the address space or is already
+    /// mapped.
+    pub fn map_user_in<F>(
+        &mut self,
+        table: &mut paging::InactivePageTable,
+        start: paging::VirtualAddress,
+        size: u64,
+        flags: paging::EntryFlags,
+        mut fill: F,
+    ) where
+        F: FnMut(paging::VirtualAddress, &mut [u8]),
+    {
+        use self::paging::EntryFlags;
+        use crate::smp::tlb::FlushBatch;
+
+        // the frames are filled through the temporary page, the CPU can not reach
+        // them through `table` while it is inactive; other CPUs drop the temporary
+        // page from their TLBs once, after the last page
+        let mut batch = FlushBatch::new(self.active_table.address_space());
+        let mut frames = Vec::new();
+        for page in user_pages(start, size) {
+            let frame = self.frame_allocator.allocate_frame().expect("out of memory");
+            let address = self.temp_page.map(frame.clone(), &mut self.active_table);
+            unsafe {
+                core::ptr::write_bytes(address as *mut u8, 0, PAGE_SIZE as usize);
+                fill(
+                    page.start_address(),
+                    core::slice::from_raw_parts_mut(address as *mut u8, PAGE_SIZE as usize),
+                );
+            }
+            self.temp_page.unmap_batched(&mut self.active_table, &mut batch);
+            frames.push((page, frame));
+        }
+        batch.flush();
+
+        let flags = flags | EntryFlags::USER_ACCESSIBLE;
+        let MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut temp_page,
+            ..
+        } = *self;
+        active_table.with(table, temp_page, |mapper| {
+            for (page, frame) in frames {
+                mapper.map_to(page, frame, flags, frame_allocator);
+            }
+        });
+    }
+
+    /// Removes an identity mapping made with `identity_map`. The frames are not freed.
+    pub fn identity_unmap(&mut self, phys: PhysicalAddress, size: u64) {
+        use self::paging::Page;
+
+        let start_page = Page::containing_address(phys);
+        let end_page = Page::containing_address(phys + size - 1);
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            if self.active_table.translate(page.start_address()).is_some() {
+                self.active_table.unmap(page, &mut self.frame_allocator);
+            }
+        }
+    }
+}
+
+/// Returns the pages of a range, panics if it leaves the user part of the address
+/// space.
+fn user_pages(start: paging::VirtualAddress, size: u64) -> paging::PageIter {
+    use self::paging::Page;
+
+    assert!(
+        size > 0 && paging::is_user_address(start) && paging::is_user_address(start + size - 1),
+        "{:#x}+{:#x} is not a user range",
+        start,
+        size
+    );
+    Page::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + size - 1),
+    )
 }
 
 struct FrameIter {
@@ -219,7 +485,7 @@ impl AreaFrameAllocator {
         memory_areas: MemoryAreaIter,
     ) -> AreaFrameAllocator {
         let mut allocator = AreaFrameAllocator {
-            next_free_frame: Frame::containing_addr(0),
+            next_free_frame: Frame::containing_addr(LOW_MEMORY_END),
             current_area: None,
             areas: memory_areas,
             kernel_start: Frame::containing_addr(kernel_start),
@@ -1,4 +1,5 @@
 use crate::memory::Frame;
+use crate::user::elf::SegmentFlags;
 use multiboot2::ElfSection;
 
 pub struct Entry(u64);
@@ -37,6 +38,20 @@ impl EntryFlags {
 
         flags
     }
+
+    /// Returns the flags for the pages of a loadable segment of a user program.
+    pub fn from_elf_segment_flags(segment: SegmentFlags) -> EntryFlags {
+        let mut flags = EntryFlags::PRESENT | EntryFlags::USER_ACCESSIBLE;
+
+        if segment.contains(SegmentFlags::WRITE) {
+            flags |= EntryFlags::WRITABLE;
+        }
+        if !segment.contains(SegmentFlags::EXECUTE) {
+            flags |= EntryFlags::NO_EXECUTE;
+        }
+
+        flags
+    }
 }
 
 impl Entry {
@@ -1,8 +1,10 @@
 use super::entry::*;
-use super::table::{self, Level4, Table};
-use super::{Page, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
+use super::table::{self, Level1, Level4, Table};
+use super::{Page, PageIter, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
 use core::ptr::Unique;
 use crate::memory::{Frame, FrameAllocator, PAGE_SIZE};
+use crate::smp::tlb::FlushBatch;
+use crate::x86_64::instructions::tlb;
 
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
@@ -65,15 +67,16 @@ impl Mapper {
     }
 
     // Maps the page to the frame with the provided flags.
-    /// The `PRESENT` flag is added by default. Needs a
-    /// `FrameAllocator` as it might need to create new page tables.
+    /// The `PRESENT` flag is added by default, `USER_ACCESSIBLE` is also set on the
+    /// page tables above the entry. Needs a `FrameAllocator` as it might need to
+    /// create new page tables.
     pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, alloc: &mut A)
     where
         A: FrameAllocator,
     {
-        let p3 = self.p4_mut().next_table_create(page.p4_index(), alloc);
-        let p2 = p3.next_table_create(page.p3_index(), alloc);
-        let p1 = p2.next_table_create(page.p2_index(), alloc);
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), flags, alloc);
+        let p2 = p3.next_table_create(page.p3_index(), flags, alloc);
+        let p1 = p2.next_table_create(page.p2_index(), flags, alloc);
 
         assert!(p1[page.p1_index() as usize].is_unused());
         p1[page.p1_index() as usize].set(frame, flags | EntryFlags::PRESENT);
@@ -99,33 +102,110 @@ impl Mapper {
 
     /// Unmaps the given page and adds all freed frames to the given
     /// `FrameAllocator`.
+    ///
+    /// The page is flushed from the TLB of every CPU that may cache it.
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
     where
         A: FrameAllocator,
     {
-        assert!(self.translate(page.start_address()).is_some());
+        let mut batch = FlushBatch::new(self.address_space());
+        self.unmap_entry(page, allocator);
+        batch.add(page.start_address());
+        batch.flush();
+    }
+
+    /// Unmaps the given page and flushes it from the TLB of the executing CPU only,
+    /// the page is added to `batch` for the other CPUs.
+    ///
+    /// The caller flushes `batch` before another CPU may use the page.
+    pub fn unmap_local<A>(&mut self, page: Page, allocator: &mut A, batch: &mut FlushBatch)
+    where
+        A: FrameAllocator,
+    {
+        self.unmap_entry(page, allocator);
+        tlb::flush(crate::x86_64::VirtualAddress(page.start_address() as usize));
+        batch.add(page.start_address());
+    }
+
+    /// Unmaps all pages of the range with a single TLB shootdown.
+    pub fn unmap_range<A>(&mut self, pages: PageIter, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let mut batch = FlushBatch::new(self.address_space());
+        for page in pages {
+            self.unmap_entry(page, allocator);
+            batch.add(page.start_address());
+        }
+        batch.flush();
+    }
+
+    /// Replaces the flags of a mapped page, the `PRESENT` flag is added by default.
+    ///
+    /// The page is flushed from the TLB of every CPU that may cache it, the new
+    /// flags may grant less access than the old ones.
+    pub fn update_flags(&mut self, page: Page, flags: EntryFlags) {
+        let mut batch = FlushBatch::new(self.address_space());
+        self.update_entry_flags(page, flags);
+        batch.add(page.start_address());
+        batch.flush();
+    }
 
-        let p1 = self
-            .p4_mut()
+    /// Replaces the flags of all pages of the range with a single TLB shootdown.
+    pub fn update_flags_range(&mut self, pages: PageIter, flags: EntryFlags) {
+        let mut batch = FlushBatch::new(self.address_space());
+        for page in pages {
+            self.update_entry_flags(page, flags);
+            batch.add(page.start_address());
+        }
+        batch.flush();
+    }
+
+    /// Returns the physical address of the P4 table this mapper modifies.
+    pub fn address_space(&self) -> PhysicalAddress {
+        // the recursive entry of a P4 table points to the table itself
+        self.p4()[511]
+            .pointed_frame()
+            .expect("P4 table without recursive mapping")
+            .start_address()
+    }
+
+    /// Returns the P1 table containing the entry of a page mapped with 4KiB pages.
+    fn p1_mut(&mut self, page: Page) -> &mut Table<Level1> {
+        self.p4_mut()
             .next_table_mut(page.p4_index())
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
             .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("mapping code does not support huge pages");
+            .expect("mapping code does not support huge pages")
+    }
+
+    /// Clears the entry of a page without flushing the TLB.
+    fn unmap_entry<A>(&mut self, page: Page, _allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        assert!(self.translate(page.start_address()).is_some());
 
-        let frame = p1[page.p1_index() as usize].pointed_frame().unwrap();
+        let p1 = self.p1_mut(page);
+        let _frame = p1[page.p1_index() as usize].pointed_frame().unwrap();
 
         p1[page.p1_index() as usize].set_unused();
 
-        // TLB needs to be flushed after page table updates
-        use crate::x86_64::instructions::tlb;
-        use crate::x86_64::VirtualAddress;
-        tlb::flush(VirtualAddress(page.start_address() as usize));
-
         // TODO free p(1,2,3) table if empty
         // TODO: deallocate implementation
         //allocator.deallocate_frame(frame);
     }
 
+    /// Replaces the flags of a page without flushing the TLB.
+    fn update_entry_flags(&mut self, page: Page, flags: EntryFlags) {
+        let p1 = self.p1_mut(page);
+        let entry = &mut p1[page.p1_index() as usize];
+        let frame = entry
+            .pointed_frame()
+            .expect("changing the flags of an unmapped page");
+        entry.set(frame, flags | EntryFlags::PRESENT);
+    }
+
     /// Identity map the the given frame with the provided flags.
     /// The `FrameAllocator` is used to create new page tables if needed.
     pub fn identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, allocator: &mut A)
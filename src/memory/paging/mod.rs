@@ -5,9 +5,10 @@ mod temporary_page;
 
 pub use self::entry::*;
 pub use self::mapper::Mapper;
-use self::temporary_page::TemporaryPage;
-use core::ops::{Deref, DerefMut};
+pub use self::temporary_page::TemporaryPage;
+use core::ops::{Add, Deref, DerefMut};
 use crate::memory::{Frame, FrameAllocator, PAGE_SIZE};
+use crate::smp::tlb::FlushBatch;
 use multiboot2::BootInformation;
 
 const ENTRY_COUNT: u64 = 512;
@@ -15,6 +16,33 @@ const ENTRY_COUNT: u64 = 512;
 pub type PhysicalAddress = u64;
 pub type VirtualAddress = u64;
 
+/// Start of the user part of an address space, P4 entries 1 to 255.
+///
+/// P4 entry 0 (the kernel image, heap and stacks) and the higher half are the kernel
+/// part, shared by every page table.
+pub const USER_SPACE_START: VirtualAddress = 0x0000_0080_0000_0000;
+/// End (exclusive) of the user part of an address space.
+pub const USER_SPACE_END: VirtualAddress = 0x0000_8000_0000_0000;
+
+/// Returns whether `address` lies in the user part of an address space.
+pub fn is_user_address(address: VirtualAddress) -> bool {
+    address >= USER_SPACE_START && address < USER_SPACE_END
+}
+
+/// Makes the P4 table at `p4` the active table of the executing CPU, unless it
+/// already is.
+///
+/// Every table shares the kernel part, so the kernel keeps running across the
+/// switch. Must be called with interrupts disabled.
+pub fn activate(p4: PhysicalAddress) {
+    use crate::x86_64::registers::control_regs;
+
+    if control_regs::cr3().0 != p4 {
+        crate::smp::tlb::set_active_address_space(p4);
+        unsafe { control_regs::cr3_write(crate::x86_64::PhysicalAddress(p4)) };
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
     number: u64,
@@ -60,6 +88,17 @@ impl Page {
     }
 }
 
+impl Add<u64> for Page {
+    type Output = Page;
+
+    fn add(self, rhs: u64) -> Page {
+        Page {
+            number: self.number + rhs,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct PageIter {
     start: Page,
     end: Page,
@@ -173,8 +212,13 @@ impl ActivePageTable {
             tlb::flush_all();
         }
 
-        // unmap temp page outside of scope where it is used
-        temp_page.unmap(self);
+        // other CPUs on the active table may have cached translations through the
+        // rewritten recursive entry, they flush everything together with the temp
+        // page, which is unmapped outside of scope where it is used
+        let mut batch = FlushBatch::new(self.address_space());
+        batch.add_all();
+        temp_page.unmap_batched(self, &mut batch);
+        batch.flush();
     }
 
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
@@ -185,6 +229,7 @@ impl ActivePageTable {
             p4_frame: Frame::containing_addr(control_regs::cr3().0),
         };
 
+        crate::smp::tlb::set_active_address_space(new_table.p4_frame.start_address());
         unsafe {
             control_regs::cr3_write(PhysicalAddress(new_table.p4_frame.start_address() as u64));
         }
@@ -214,6 +259,12 @@ impl InactivePageTable {
         temp_page.unmap(active_table);
         InactivePageTable { p4_frame: frame }
     }
+
+    /// Returns the physical address of the P4 table, the value `cr3` holds while
+    /// the table is active.
+    pub fn p4_address(&self) -> PhysicalAddress {
+        self.p4_frame.start_address()
+    }
 }
 
 pub fn kernel_remap<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable
@@ -75,14 +75,20 @@ where
             .map(|address| unsafe { &mut *(address as *mut _) })
     }
 
+    /// Returns the next level table, allocating it if the entry is unused.
+    ///
+    /// The CPU checks `USER_ACCESSIBLE` at every level, so the entry gets it if
+    /// `flags` contains it.
     pub fn next_table_create<A>(
         &mut self,
         index: u64,
+        flags: EntryFlags,
         allocator: &mut A,
     ) -> &mut Table<L::NextLevel>
     where
         A: FrameAllocator,
     {
+        let user = flags & EntryFlags::USER_ACCESSIBLE;
         if self.next_table(index).is_none() {
             assert!(
                 !self.entries[index as usize]
@@ -92,8 +98,15 @@ where
             );
 
             let frame = allocator.allocate_frame().expect("no frames available");
-            self.entries[index as usize].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | user;
+            self.entries[index as usize].set(frame, flags);
             self.next_table_mut(index).unwrap().zero();
+        } else if !self.entries[index as usize].flags().contains(user) {
+            // widening the access needs no TLB flush, a fault walks the tables again
+            let entry = &mut self.entries[index as usize];
+            let frame = entry.pointed_frame().unwrap();
+            let flags = entry.flags() | user;
+            entry.set(frame, flags);
         }
 
         self.next_table_mut(index).unwrap()
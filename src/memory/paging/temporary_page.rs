@@ -2,6 +2,7 @@ use super::table::{Level1, Table};
 use super::Page;
 use super::{ActivePageTable, VirtualAddress};
 use crate::memory::{Frame, FrameAllocator};
+use crate::smp::tlb::FlushBatch;
 
 struct TinyAllocator([Option<Frame>; 3]);
 impl FrameAllocator for TinyAllocator {
@@ -60,6 +61,13 @@ impl TemporaryPage {
         active_table.unmap(self.page, &mut self.allocator)
     }
 
+    /// Unmaps the temp page in the currently active table and leaves the flush on
+    /// other CPUs to `batch`, so the page can be mapped again right away. Only the
+    /// holder of the temp page uses it, other CPUs merely may have cached it.
+    pub fn unmap_batched(&mut self, active_table: &mut ActivePageTable, batch: &mut FlushBatch) {
+        active_table.unmap_local(self.page, &mut self.allocator, batch)
+    }
+
     /// Maps the temporary page to the given page table frame in the active
     /// table. Returns a reference to the newly mapped table
     pub fn map_table_frame(
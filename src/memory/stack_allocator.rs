@@ -0,0 +1,105 @@
+use alloc::vec::Vec;
+use crate::memory::paging::{self, ActivePageTable, Page, PageIter};
+use crate::memory::{FrameAllocator, PAGE_SIZE};
+
+/// Hands out kernel stacks from a reserved range of virtual memory.
+///
+/// Every stack is preceded by an unmapped guard page, so an overflow page faults
+/// instead of silently corrupting the memory below it. Freed stacks stay mapped and
+/// are handed out again for requests of the same size.
+pub struct StackAllocator {
+    range: PageIter,
+    free: Vec<Stack>,
+}
+
+impl StackAllocator {
+    pub fn new(page_range: PageIter) -> StackAllocator {
+        StackAllocator {
+            range: page_range,
+            free: Vec::new(),
+        }
+    }
+
+    /// Returns a stack that is no longer used, it may be handed out again.
+    pub fn free_stack(&mut self, stack: Stack) {
+        self.free.push(stack);
+    }
+
+    /// Allocates a stack of `size_in_pages` pages plus a guard page, returns `None`
+    /// if the stack area is exhausted.
+    pub fn alloc_stack<A>(
+        &mut self,
+        active_table: &mut ActivePageTable,
+        frame_allocator: &mut A,
+        size_in_pages: usize,
+    ) -> Option<Stack>
+    where
+        A: FrameAllocator,
+    {
+        if size_in_pages == 0 {
+            return None;
+        }
+
+        if let Some(index) = self.free.iter().position(|s| s.pages() == size_in_pages) {
+            return Some(self.free.swap_remove(index));
+        }
+
+        // clone the range, so it is only updated on success
+        let mut range = self.range.clone();
+
+        let guard_page = range.next();
+        let stack_start = range.next();
+        let stack_end = if size_in_pages == 1 {
+            stack_start
+        } else {
+            range.nth(size_in_pages - 2)
+        };
+
+        match (guard_page, stack_start, stack_end) {
+            (Some(_), Some(start), Some(end)) => {
+                self.range = range;
+
+                for page in Page::range_inclusive(start, end) {
+                    active_table.map(
+                        page,
+                        paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE,
+                        frame_allocator,
+                    );
+                }
+
+                let top_of_stack = end.start_address() + PAGE_SIZE;
+                Some(Stack::new(top_of_stack, start.start_address()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A kernel stack, growing down from `top` to `bottom`.
+#[derive(Debug)]
+pub struct Stack {
+    top: u64,
+    bottom: u64,
+}
+
+impl Stack {
+    fn new(top: u64, bottom: u64) -> Stack {
+        assert!(top > bottom);
+        Stack { top, bottom }
+    }
+
+    /// The initial stack pointer, one past the highest usable address.
+    pub fn top(&self) -> u64 {
+        self.top
+    }
+
+    /// The lowest usable address, the guard page is right below it.
+    pub fn bottom(&self) -> u64 {
+        self.bottom
+    }
+
+    /// The size in pages, without the guard page.
+    pub fn pages(&self) -> usize {
+        ((self.top - self.bottom) / PAGE_SIZE) as usize
+    }
+}
@@ -0,0 +1,272 @@
+//! Per-CPU data areas addressed through the `GS` segment base.
+//!
+//! Every CPU owns one heap allocated area. It starts with a `CpuLocal` header that
+//! holds a pointer to itself, the CPU number, the APIC ID, scratch slots for the
+//! system call entry and statistics, followed by a private copy of all variables
+//! declared with `percpu!`. `IA32_GS_BASE` points at the header while the CPU runs
+//! kernel code, so the header fields are at fixed `GS` offsets and the entry code
+//! can reach them without touching a general purpose register.
+//!
+//! `IA32_KERNEL_GS_BASE` holds the user value (0) in the kernel. Entry points
+//! reachable from ring 3 execute `swapgs` when they come from user mode and again
+//! before returning, see `interrupt_entry.asm`.
+//!
+//! The initial values of `percpu!` variables live in the `.percpu` linker section,
+//! a variable is found at the same offset from the start of the section in every
+//! area.
+
+use alloc::alloc::{alloc_zeroed, Layout};
+use core::cell::UnsafeCell;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use crate::smp::MAX_CPUS;
+use crate::x86_64::instructions::interrupts;
+use crate::x86_64::registers::msr::{self, rdmsr, wrmsr};
+use crate::x86_64::structures::tss::TaskStateSegment;
+use crate::x86_64::VirtualAddress;
+
+/// Alignment of an area and offset of the variables behind the header.
+///
+/// `percpu!` variables must not need a larger alignment.
+const AREA_ALIGN: usize = 64;
+
+/// `GS` offset of `CpuLocal::kernel_stack`.
+pub const KERNEL_STACK_OFFSET: usize = 8;
+/// `GS` offset of `CpuLocal::user_stack`.
+pub const USER_STACK_OFFSET: usize = 16;
+
+extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+}
+
+/// The header of a per-CPU area, `GS:0` points at it.
+///
+/// The layout is fixed because assembly code accesses the first fields directly.
+#[repr(C)]
+pub struct CpuLocal {
+    /// Points at this header, reading `GS:0` yields the linear address of the area.
+    #[allow(dead_code)]
+    self_ptr: *const CpuLocal,
+    /// Top of the stack the system call entry switches to.
+    kernel_stack: AtomicU64,
+    /// Scratch slot the system call entry saves the user stack pointer in.
+    user_stack: AtomicU64,
+    cpu: usize,
+    apic_id: u32,
+    tss: *mut TaskStateSegment,
+    /// Interrupts from vector 32 upwards handled on this CPU.
+    pub interrupts: AtomicU64,
+    /// Kernel ticks seen by this CPU.
+    pub ticks: AtomicU64,
+}
+
+/// Areas of all initialized CPUs, indexed by CPU number.
+static AREAS: [AtomicPtr<CpuLocal>; MAX_CPUS] = [AREA_INIT; MAX_CPUS];
+const AREA_INIT: AtomicPtr<CpuLocal> = AtomicPtr::new(ptr::null_mut());
+
+/// Allocates the area of the executing CPU and points `IA32_GS_BASE` at it.
+///
+/// Must run before interrupts are enabled on the CPU, interrupt handlers rely on
+/// the area. `tss` is the TSS loaded on this CPU.
+pub fn init(cpu: usize, apic_id: u32, tss: &'static mut TaskStateSegment) {
+    assert!(cpu < MAX_CPUS, "CPU number {} out of range", cpu);
+    assert!(!is_initialized(), "per-CPU area of CPU {} initialized twice", cpu);
+
+    let template = template();
+    let size = vars_offset() + template.len();
+    let layout = Layout::from_size_align(size, AREA_ALIGN).unwrap();
+
+    unsafe {
+        let area = alloc_zeroed(layout);
+        assert!(!area.is_null(), "could not allocate the per-CPU area");
+
+        let local = area as *mut CpuLocal;
+        ptr::write(
+            local,
+            CpuLocal {
+                self_ptr: local,
+                kernel_stack: AtomicU64::new(0),
+                user_stack: AtomicU64::new(0),
+                cpu,
+                apic_id,
+                tss,
+                interrupts: AtomicU64::new(0),
+                ticks: AtomicU64::new(0),
+            },
+        );
+        ptr::copy_nonoverlapping(template.as_ptr(), area.add(vars_offset()), template.len());
+
+        AREAS[cpu].store(local, Ordering::SeqCst);
+        wrmsr(msr::IA32_GS_BASE, local as u64);
+        wrmsr(msr::IA32_KERNEL_GS_BASE, 0);
+    }
+}
+
+/// Returns whether the executing CPU has its per-CPU area set up.
+pub fn is_initialized() -> bool {
+    rdmsr(msr::IA32_GS_BASE) != 0
+}
+
+/// Returns the header of the executing CPU's area.
+#[inline]
+pub fn local() -> &'static CpuLocal {
+    let local: *const CpuLocal;
+    unsafe {
+        llvm_asm!("mov %gs:0, $0" : "=r" (local) ::: "volatile");
+        &*local
+    }
+}
+
+/// Returns the header of another CPU's area, if that CPU is initialized.
+pub fn for_cpu(cpu: usize) -> Option<&'static CpuLocal> {
+    let local = AREAS.get(cpu)?.load(Ordering::Acquire);
+    unsafe { local.as_ref() }
+}
+
+/// Returns the number of the executing CPU.
+#[inline]
+pub fn cpu_index() -> usize {
+    let cpu: usize;
+    unsafe {
+        llvm_asm!("mov %gs:24, $0" : "=r" (cpu) ::: "volatile");
+    }
+    cpu
+}
+
+impl CpuLocal {
+    /// The CPU number, 0 is the bootstrap processor.
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    /// The local APIC ID of the CPU.
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// The stack the system call entry switches to.
+    pub fn kernel_stack(&self) -> u64 {
+        self.kernel_stack.load(Ordering::Relaxed)
+    }
+
+    /// The user stack pointer saved by the last system call entry.
+    pub fn user_stack(&self) -> u64 {
+        self.user_stack.load(Ordering::Relaxed)
+    }
+
+    /// Sets the stack used on entry from user mode, both for system calls and for
+    /// interrupts through `TSS.privilege_stack_table[0]`.
+    ///
+    /// Only the owning CPU may call this, the TSS is not shared.
+    pub fn set_kernel_stack(&self, top: u64) {
+        debug_assert_eq!(self.cpu, cpu_index());
+        self.kernel_stack.store(top, Ordering::Relaxed);
+        unsafe {
+            (*self.tss).privilege_stack_table[0] = VirtualAddress(top as usize);
+        }
+    }
+
+    /// Returns the address of the area's copy of a `percpu!` variable.
+    fn var_ptr<T>(&self, var: &PerCpu<T>) -> *const T {
+        let base = self as *const CpuLocal as usize + vars_offset();
+        (base + var.offset()) as *const T
+    }
+}
+
+/// Offset of the variables from the start of an area.
+fn vars_offset() -> usize {
+    (mem::size_of::<CpuLocal>() + AREA_ALIGN - 1) & !(AREA_ALIGN - 1)
+}
+
+/// The initial values of all `percpu!` variables.
+fn template() -> &'static [u8] {
+    unsafe {
+        let start = &__percpu_start as *const u8;
+        let len = &__percpu_end as *const u8 as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// The initial value of a `percpu!` variable, placed in the `.percpu` section.
+///
+/// Never accessed in place, only copied into the areas. The `UnsafeCell` keeps all
+/// templates in a writable section so their section flags agree.
+#[doc(hidden)]
+#[repr(transparent)]
+pub struct Template<T>(pub UnsafeCell<T>);
+
+unsafe impl<T> Sync for Template<T> {}
+
+/// A variable with one instance per CPU, declared with `percpu!`.
+pub struct PerCpu<T: 'static> {
+    template: &'static Template<T>,
+}
+
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(template: &'static Template<T>) -> PerCpu<T> {
+        PerCpu { template }
+    }
+
+    fn offset(&self) -> usize {
+        let start = unsafe { &__percpu_start as *const u8 as usize };
+        self.template as *const Template<T> as usize - start
+    }
+
+    /// Runs `f` on the executing CPU's instance with interrupts disabled.
+    ///
+    /// Neither an interrupt handler nor a migration to another CPU can observe the
+    /// instance while `f` runs, so it can be used for types that are not `Sync`.
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        interrupts::without_interrupts(|| f(unsafe { &*local().var_ptr(self) }))
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// Returns the executing CPU's instance.
+    ///
+    /// The reference stays valid if the caller is moved to another CPU, it then
+    /// refers to the instance of the CPU it was taken on.
+    pub fn get(&'static self) -> &'static T {
+        unsafe { &*local().var_ptr(self) }
+    }
+
+    /// Returns the instance of another CPU, if that CPU is initialized.
+    pub fn get_for(&'static self, cpu: usize) -> Option<&'static T> {
+        for_cpu(cpu).map(|local| unsafe { &*local.var_ptr(self) })
+    }
+}
+
+/// Declares variables with one instance per CPU.
+///
+/// ```ignore
+/// percpu! {
+///     static EVENTS: AtomicU64 = AtomicU64::new(0);
+/// }
+///
+/// EVENTS.get().fetch_add(1, Ordering::Relaxed);
+/// ```
+///
+/// Every CPU starts with a copy of the initial value when its area is created.
+/// The initializer must be a constant expression.
+#[macro_export]
+macro_rules! percpu {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])*
+        $vis static $name: $crate::percpu::PerCpu<$ty> = {
+            #[link_section = ".percpu"]
+            static TEMPLATE: $crate::percpu::Template<$ty> =
+                $crate::percpu::Template(::core::cell::UnsafeCell::new($init));
+            $crate::percpu::PerCpu::new(&TEMPLATE)
+        };
+        $crate::percpu!($($rest)*);
+    };
+}
@@ -17,8 +17,8 @@
 
 use bitflags::bitflags;
 use core::fmt;
+use crate::sync::IrqSafeMutex;
 use crate::x86_64::instructions::port::Port;
-use spin::Mutex;
 
 #[macro_use]
 use lazy_static::lazy_static;
@@ -122,11 +122,11 @@ impl fmt::Write for SerialPort {
 
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqSafeMutex<SerialPort> = {
         unsafe { 
             let mut serial_port = SerialPort::new(SERIAL_IO_PORT); 
             serial_port.init();
-            Mutex::new(serial_port)
+            IrqSafeMutex::new(serial_port)
         }
     };
 }
@@ -137,6 +137,17 @@ pub fn print(args: ::core::fmt::Arguments) {
     SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
 }
 
+/// Prints without taking the `SERIAL1` lock.
+///
+/// Used for NMI and watchdog reports, where the interrupted code may hold the lock
+/// and would never release it. Output can interleave with other CPUs.
+#[doc(hidden)]
+pub fn print_unlocked(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    let mut serial_port = unsafe { SerialPort::new(SERIAL_IO_PORT) };
+    serial_port.write_fmt(args).ok();
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! print {
@@ -152,4 +163,13 @@ macro_rules! println {
     ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::print!(
         concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+
+/// Prints to the host through the serial interface without locking, appending a newline.
+#[macro_export]
+macro_rules! emergency_println {
+    () => ($crate::serial::print_unlocked(format_args!("\n")));
+    ($fmt:expr) => ($crate::serial::print_unlocked(format_args!(concat!($fmt, "\n"))));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial::print_unlocked(format_args!(
+        concat!($fmt, "\n"), $($arg)*)));
+}
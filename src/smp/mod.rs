@@ -0,0 +1,333 @@
+//! Bring-up of the application processors.
+//!
+//! The APs are taken from the ACPI MADT and started one at a time with the
+//! INIT-SIPI-SIPI sequence. Each one runs the real mode trampoline from
+//! `ap_trampoline.asm`, which is copied below 1 MiB, switches to long mode on the
+//! kernel page tables and calls `ap_entry` on a stack allocated by the BSP. The AP
+//! then loads its own GDT, TSS and the shared IDT, enables its local APIC, sets up
+//! its per-CPU area and idles.
+//!
+//! The trampoline data is handed over with a sequence number: the BSP publishes it
+//! for one APIC id, the AP claims it before leaving real mode, and the BSP revokes
+//! it when the AP does not claim it in time. A late AP finds the data revoked or
+//! meant for another CPU and parks in the trampoline.
+
+pub mod tlb;
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+use crate::acpi::{self, madt, Madt, MadtEntry};
+use crate::apic::{self, ApicMode, DeliveryMode, IpiDestination};
+use crate::interrupts::{self, gdt};
+use crate::memory::paging::EntryFlags;
+use crate::memory::MemoryController;
+use crate::x86_64::registers::msr::{self, rdmsr};
+use crate::x86_64::structures::tss::TaskStateSegment;
+use crate::{mce, percpu, thread, time, user, watchdog};
+use spin::{Mutex, Once};
+
+/// Upper bound for the number of CPUs the kernel manages.
+pub const MAX_CPUS: usize = 64;
+
+/// A set of CPU numbers.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CpuSet(u64);
+
+impl CpuSet {
+    /// Every CPU, including those that are not online yet.
+    pub const ALL: CpuSet = CpuSet(u64::max_value());
+
+    /// The empty set.
+    pub const fn empty() -> CpuSet {
+        CpuSet(0)
+    }
+
+    /// The set holding only `cpu`.
+    pub fn single(cpu: usize) -> CpuSet {
+        let mut set = CpuSet::empty();
+        set.insert(cpu);
+        set
+    }
+
+    /// Creates a set from a bit mask, bit n stands for CPU n.
+    pub const fn from_bits(bits: u64) -> CpuSet {
+        CpuSet(bits)
+    }
+
+    /// The bit mask of the set.
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Adds `cpu` to the set.
+    pub fn insert(&mut self, cpu: usize) {
+        assert!(cpu < MAX_CPUS, "CPU number {} out of range", cpu);
+        self.0 |= 1 << cpu;
+    }
+
+    /// Removes `cpu` from the set.
+    pub fn remove(&mut self, cpu: usize) {
+        if cpu < MAX_CPUS {
+            self.0 &= !(1 << cpu);
+        }
+    }
+
+    /// Returns whether `cpu` is in the set.
+    pub fn contains(&self, cpu: usize) -> bool {
+        cpu < MAX_CPUS && self.0 & (1 << cpu) != 0
+    }
+
+    /// Returns whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The number of CPUs in the set.
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// The CPUs in both sets.
+    pub fn intersection(&self, other: CpuSet) -> CpuSet {
+        CpuSet(self.0 & other.0)
+    }
+
+    /// Iterates over the CPU numbers in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        let bits = self.0;
+        (0..MAX_CPUS).filter(move |&cpu| bits & (1 << cpu) != 0)
+    }
+}
+
+impl fmt::Debug for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// Physical address the trampoline is copied to, must match `ap_trampoline.asm`.
+const AP_TRAMPOLINE_ADDR: u64 = 0x8000;
+const AP_STACK_PAGES: usize = 4;
+
+/// `IA32_APIC_BASE` bit 8, set on the bootstrap processor.
+const APIC_BASE_BSP: u64 = 1 << 8;
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_apic_id: u8;
+    static ap_trampoline_sequence: u8;
+}
+
+/// Handed from the BSP to the AP it is starting.
+struct ApBootInfo {
+    cpu: usize,
+    apic_id: u32,
+    tss: TaskStateSegment,
+}
+
+static AP_BOOT_INFO: Mutex<Option<ApBootInfo>> = Mutex::new(None);
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+/// The sequence number of the next AP start, 0 marks the trampoline data revoked.
+static NEXT_SEQUENCE: AtomicU32 = AtomicU32::new(1);
+
+/// APIC IDs of all usable CPUs, the BSP first. The index is the CPU number.
+static APIC_IDS: Once<Vec<u32>> = Once::new();
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Starts every enabled AP listed in the MADT. Returns the number of CPUs online.
+///
+/// Requires the local APIC, a calibrated delay source and the kernel heap.
+pub fn init(memory_controller: &mut MemoryController) -> usize {
+    tlb::init();
+
+    let bsp_id = apic::local().id();
+    let mut apic_ids = vec![bsp_id];
+
+    if let Some(madt) = acpi::find::<Madt>() {
+        for entry in madt.entries() {
+            let (id, flags) = match entry {
+                MadtEntry::LocalApic { apic_id, flags, .. } => (u32::from(apic_id), flags),
+                MadtEntry::LocalX2Apic { x2apic_id, flags, .. } => (x2apic_id, flags),
+                _ => continue,
+            };
+            if flags & madt::LAPIC_ENABLED == 0 || apic_ids.contains(&id) {
+                continue;
+            }
+            if id > 0xFF && apic::local().mode() == ApicMode::XApic {
+                println!("SMP: APIC id {} not reachable in xAPIC mode", id);
+                continue;
+            }
+            if apic_ids.len() == MAX_CPUS {
+                println!("SMP: more than {} CPUs, ignoring the rest", MAX_CPUS);
+                break;
+            }
+            apic_ids.push(id);
+        }
+    }
+
+    let apic_ids = APIC_IDS.call_once(|| apic_ids);
+    if apic_ids.len() == 1 {
+        println!("SMP: single processor");
+        return 1;
+    }
+
+    install_trampoline(memory_controller);
+
+    for (cpu, &apic_id) in apic_ids.iter().enumerate().skip(1) {
+        if !start_ap(cpu, apic_id, memory_controller) {
+            println!("SMP: CPU {} (APIC id {}) did not start", cpu, apic_id);
+        }
+    }
+
+    memory_controller.identity_unmap(AP_TRAMPOLINE_ADDR, 0x1000);
+
+    let online = online_count();
+    println!("SMP: {} of {} CPUs online", online, apic_ids.len());
+    online
+}
+
+/// Returns the number of usable CPUs, online or not.
+pub fn cpu_count() -> usize {
+    APIC_IDS.r#try().map(|ids| ids.len()).unwrap_or(1)
+}
+
+/// Returns the number of CPUs that completed their startup.
+pub fn online_count() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// Returns the APIC IDs of all usable CPUs, indexed by CPU number.
+pub fn apic_ids() -> &'static [u32] {
+    APIC_IDS.r#try().map(|ids| &ids[..]).unwrap_or(&[])
+}
+
+/// Returns whether the executing CPU is the bootstrap processor.
+pub fn is_bsp() -> bool {
+    rdmsr(msr::IA32_APIC_BASE) & APIC_BASE_BSP != 0
+}
+
+/// Copies the trampoline below 1 MiB and stores the kernel page table and entry point.
+fn install_trampoline(memory_controller: &mut MemoryController) {
+    let cr3 = memory_controller.p4_address();
+    assert!(cr3 < 0x1_0000_0000, "the trampoline needs the P4 table below 4 GiB");
+
+    // executable, the APs run the trampoline through this mapping once paging is on
+    memory_controller.identity_map(AP_TRAMPOLINE_ADDR, 0x1000, EntryFlags::WRITABLE);
+
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let size = &ap_trampoline_end as *const u8 as usize - start as usize;
+        assert!(size <= 0x1000, "AP trampoline larger than a page");
+
+        ptr::copy_nonoverlapping(start, AP_TRAMPOLINE_ADDR as *mut u8, size);
+        ptr::write_volatile(trampoline_field(&ap_trampoline_cr3), cr3);
+        ptr::write_volatile(trampoline_field(&ap_trampoline_entry), ap_entry as u64);
+    }
+}
+
+/// Returns the address of a trampoline data field in the copy below 1 MiB.
+unsafe fn trampoline_field<T>(label: &u8) -> *mut T {
+    let offset = label as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64;
+    (AP_TRAMPOLINE_ADDR + offset) as *mut T
+}
+
+/// The sequence number of the trampoline data, shared with the starting AP.
+fn trampoline_sequence() -> &'static AtomicU32 {
+    unsafe { &*(trampoline_field::<u32>(&ap_trampoline_sequence) as *const AtomicU32) }
+}
+
+/// Runs the INIT-SIPI-SIPI sequence for one AP and waits for it to check in.
+fn start_ap(cpu: usize, apic_id: u32, memory_controller: &mut MemoryController) -> bool {
+    let stack = memory_controller
+        .alloc_stack(AP_STACK_PAGES)
+        .expect("could not allocate an AP stack");
+    let tss = gdt::new_tss(memory_controller);
+
+    *AP_BOOT_INFO.lock() = Some(ApBootInfo { cpu, apic_id, tss });
+    AP_STARTED.store(false, Ordering::SeqCst);
+    unsafe {
+        ptr::write_volatile(trampoline_field(&ap_trampoline_stack), stack.top());
+        ptr::write_volatile(trampoline_field(&ap_trampoline_apic_id), apic_id);
+    }
+    // publish the data written above to exactly this AP
+    let sequence = NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    trampoline_sequence().store(sequence, Ordering::SeqCst);
+
+    let lapic = apic::local();
+    let dest = IpiDestination::Physical(apic_id);
+    let start_page = (AP_TRAMPOLINE_ADDR >> 12) as u8;
+
+    unsafe {
+        lapic.send_ipi(0, DeliveryMode::Init, dest);
+        time::delay(Duration::from_millis(10));
+
+        // the second SIPI is only needed if the first one got lost
+        for _ in 0..2 {
+            lapic.send_ipi(start_page, DeliveryMode::StartUp, dest);
+            time::delay(Duration::from_micros(200));
+            if AP_STARTED.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+    }
+
+    for _ in 0..100 {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        time::delay(Duration::from_millis(1));
+    }
+
+    if trampoline_sequence()
+        .compare_exchange(sequence, 0, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+    {
+        // revoked before the AP claimed it, if it still starts it parks in the
+        // trampoline and nothing refers to the boot information anymore
+        AP_BOOT_INFO.lock().take();
+        return false;
+    }
+
+    // the AP claimed the trampoline data just now and is on its way to `ap_entry`
+    while !AP_STARTED.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// The Rust entry point of the APs, called by the trampoline.
+extern "C" fn ap_entry() -> ! {
+    let info = AP_BOOT_INFO
+        .lock()
+        .take()
+        .expect("AP started without boot information");
+
+    let tss = interrupts::init_ap(info.tss);
+    apic::init_ap();
+    percpu::init(info.cpu, apic::local().id(), tss);
+    tlb::init_cpu();
+    thread::init();
+    user::syscall::init_cpu();
+    mce::init_ap();
+    watchdog::init_ap();
+
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+    AP_STARTED.store(true, Ordering::SeqCst);
+    println!(
+        "CPU {} (APIC id {}) online",
+        info.cpu,
+        apic::local().id()
+    );
+    debug_assert_eq!(apic::local().id(), info.apic_id);
+
+    time::init_ap();
+    unsafe { crate::x86_64::instructions::interrupts::enable() };
+    thread::idle_loop()
+}
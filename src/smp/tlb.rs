@@ -0,0 +1,258 @@
+//! TLB shootdown across CPUs.
+//!
+//! Page table changes that remove a mapping or reduce its permissions are only
+//! visible to other CPUs once they dropped their cached translations. `Mapper`
+//! collects the changed pages of one operation in a `FlushBatch` and `flush` sends
+//! them to every CPU that may cache them:
+//!
+//! - pages in the kernel part of the address space are shared by all page tables,
+//!   every online CPU is interrupted
+//! - user pages only concern the CPUs that currently run on the same P4 table
+//!
+//! One shootdown runs at a time. The initiator publishes the request, sets a pending
+//! bit per target CPU and sends `vectors::TLB_SHOOTDOWN`. Each target invalidates the
+//! pages, or its whole TLB if the batch overflowed, and clears its bit. The initiator
+//! spins until all bits are clear. CPUs waiting to start their own shootdown serve
+//! requests for themselves meanwhile, so two initiators never wait on each other.
+//!
+//! Callers must not hold a lock that other CPUs may spin on with interrupts
+//! disabled, those CPUs could never acknowledge the request.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{self, AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use crate::apic::{self, DeliveryMode, IpiDestination};
+use crate::interrupts::{self, vectors, IrqReturn};
+use crate::memory::paging;
+use crate::percpu;
+use crate::time;
+use crate::x86_64::instructions::{interrupts as cpu_interrupts, tlb};
+use crate::x86_64::registers::control_regs;
+use crate::x86_64::VirtualAddress;
+use spin::Mutex;
+
+/// Pages a batch can hold before it falls back to a full flush.
+pub const MAX_BATCH: usize = 32;
+
+/// Time after which a waiting initiator reports the CPUs that did not respond.
+const STALL_WARNING: Duration = Duration::from_secs(1);
+
+/// The pages to invalidate in one shootdown.
+#[derive(Clone)]
+pub struct FlushBatch {
+    /// Physical address of the P4 table the pages were changed in.
+    address_space: u64,
+    /// Whether a page in the shared kernel part is included.
+    kernel: bool,
+    /// Set once more than `MAX_BATCH` pages were added.
+    full: bool,
+    count: usize,
+    pages: [u64; MAX_BATCH],
+}
+
+impl FlushBatch {
+    /// Creates an empty batch for the page table at `address_space`.
+    pub fn new(address_space: u64) -> FlushBatch {
+        FlushBatch {
+            address_space,
+            kernel: false,
+            full: false,
+            count: 0,
+            pages: [0; MAX_BATCH],
+        }
+    }
+
+    /// Adds the page at `address`, pages already in the batch are not added again.
+    pub fn add(&mut self, address: paging::VirtualAddress) {
+        if !paging::is_user_address(address) {
+            self.kernel = true;
+        }
+        if self.pages[..self.count].contains(&address) {
+            return;
+        }
+        if self.count < MAX_BATCH {
+            self.pages[self.count] = address;
+            self.count += 1;
+        } else {
+            self.full = true;
+        }
+    }
+
+    /// Requests a full flush instead of single pages.
+    pub fn add_all(&mut self) {
+        self.full = true;
+    }
+
+    /// Returns whether nothing was added.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0 && !self.full
+    }
+
+    /// Invalidates the pages on the executing CPU and every CPU that may cache them.
+    ///
+    /// Runs with interrupts disabled throughout, the caller must not move to another
+    /// CPU between the local flush and the selection of the other targets.
+    pub fn flush(self) {
+        if self.is_empty() {
+            return;
+        }
+        cpu_interrupts::without_interrupts(|| {
+            if self.address_space == control_regs::cr3().0 {
+                self.flush_local();
+            }
+            if ENABLED.load(Ordering::Acquire) {
+                shootdown(&self);
+            }
+        });
+    }
+
+    fn flush_local(&self) {
+        if self.full {
+            tlb::flush_all();
+        } else {
+            for &address in &self.pages[..self.count] {
+                tlb::flush(VirtualAddress(address as usize));
+            }
+        }
+    }
+}
+
+/// The published request, only written by the holder of `LOCK` while no CPU has a
+/// pending bit set.
+struct Request(UnsafeCell<Option<FlushBatch>>);
+
+unsafe impl Sync for Request {}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOCK: Mutex<()> = Mutex::new(());
+static REQUEST: Request = Request(UnsafeCell::new(None));
+/// One bit per CPU number that still has to process `REQUEST`.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+percpu! {
+    /// Physical address of the P4 table the CPU runs on.
+    static ACTIVE_P4: AtomicU64 = AtomicU64::new(0);
+    static SHOOTDOWNS: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Installs the shootdown handler, called on the bootstrap processor before the
+/// APs are started. Flushes stay local until then.
+pub fn init() {
+    interrupts::register_irq_with(vectors::TLB_SHOOTDOWN, |_| {
+        handle_pending();
+        IrqReturn::Handled
+    })
+    .expect("could not register the TLB shootdown handler");
+
+    init_cpu();
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Records the page table of the executing CPU, called once on every CPU after its
+/// per-CPU area is set up.
+pub fn init_cpu() {
+    set_active_address_space(control_regs::cr3().0);
+}
+
+/// Records that the executing CPU switches to the P4 table at `p4`.
+///
+/// Must be called before `cr3` is written, so a concurrent shootdown either sees
+/// the new table or finished its page table updates before the switch.
+pub fn set_active_address_space(p4: u64) {
+    if percpu::is_initialized() {
+        ACTIVE_P4.get().store(p4, Ordering::SeqCst);
+    }
+}
+
+/// Returns how many shootdown requests the CPU processed.
+pub fn shootdown_count(cpu: usize) -> u64 {
+    SHOOTDOWNS
+        .get_for(cpu)
+        .map(|count| count.load(Ordering::Relaxed))
+        .unwrap_or(0)
+}
+
+/// Sends `batch` to all other CPUs that may cache its pages and waits for them.
+///
+/// Must be called with interrupts disabled.
+fn shootdown(batch: &FlushBatch) {
+    // the page table writes have to be visible before the targets are selected,
+    // see `set_active_address_space`
+    atomic::fence(Ordering::SeqCst);
+
+    let this_cpu = percpu::cpu_index();
+    let mut targets = 0u64;
+    for cpu in 0..super::cpu_count() {
+        if cpu == this_cpu {
+            continue;
+        }
+        let active = match ACTIVE_P4.get_for(cpu) {
+            Some(active) => active.load(Ordering::SeqCst),
+            None => continue,
+        };
+        if active != 0 && (batch.kernel || active == batch.address_space) {
+            targets |= 1 << cpu;
+        }
+    }
+    if targets == 0 {
+        return;
+    }
+
+    let _guard = loop {
+        if let Some(guard) = LOCK.try_lock() {
+            break guard;
+        }
+        handle_pending();
+        core::hint::spin_loop();
+    };
+
+    unsafe { *REQUEST.0.get() = Some(batch.clone()) };
+    PENDING.store(targets, Ordering::Release);
+
+    let lapic = apic::local();
+    let apic_ids = super::apic_ids();
+    for cpu in 0..apic_ids.len() {
+        if targets & (1 << cpu) != 0 {
+            unsafe {
+                lapic.send_ipi(
+                    vectors::TLB_SHOOTDOWN,
+                    DeliveryMode::Fixed,
+                    IpiDestination::Physical(apic_ids[cpu]),
+                )
+            };
+        }
+    }
+
+    let start = time::monotonic_ns();
+    let mut warned = false;
+    loop {
+        let pending = PENDING.load(Ordering::Acquire);
+        if pending == 0 {
+            break;
+        }
+        if !warned && time::monotonic_ns() - start > STALL_WARNING.as_nanos() as u64 {
+            println!("TLB shootdown: waiting for CPUs {:#x}", pending);
+            warned = true;
+        }
+        // never wait on a bit of the executing CPU, the IPI to it is masked
+        handle_pending();
+        core::hint::spin_loop();
+    }
+
+    unsafe { *REQUEST.0.get() = None };
+}
+
+/// Processes the published request if it is pending for the executing CPU.
+fn handle_pending() {
+    let bit = 1 << percpu::cpu_index();
+    if PENDING.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+
+    // the initiator keeps the request alive until every pending bit is clear
+    if let Some(batch) = unsafe { &*REQUEST.0.get() } {
+        batch.flush_local();
+    }
+    SHOOTDOWNS.get().fetch_add(1, Ordering::Relaxed);
+    PENDING.fetch_and(!bit, Ordering::Release);
+}
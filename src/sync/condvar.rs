@@ -0,0 +1,114 @@
+//! Condition variables for the blocking `Mutex`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use super::{MutexGuard, WaitQueue};
+use crate::time::Instant;
+
+/// Lets threads wait for a condition on data protected by a `Mutex`.
+///
+/// Wakeups may be spurious, callers check their condition in a loop or use
+/// `wait_while`.
+pub struct Condvar {
+    /// Bumped by every notification, a waiter returns once it changed.
+    sequence: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    /// Creates a condition variable.
+    pub const fn new() -> Condvar {
+        Condvar {
+            sequence: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex, blocks until notified and locks the mutex again.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        // read before the unlock, a notification after it changes the sequence
+        let sequence = self.sequence.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters.wait_until(|| self.notified_since(sequence));
+        mutex.lock()
+    }
+
+    /// Like `wait`, but gives up after `timeout`. Also returns whether it timed out.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex();
+        let sequence = self.sequence.load(Ordering::Acquire);
+        drop(guard);
+        let notified = self
+            .waiters
+            .wait_until_timeout(timeout, || self.notified_since(sequence));
+        (mutex.lock(), notified.is_none())
+    }
+
+    /// Waits as long as `condition` returns true for the protected data.
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Like `wait_while`, but gives up after `timeout`. Also returns whether it
+    /// timed out with the condition still true.
+    pub fn wait_timeout_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Duration,
+        mut condition: F,
+    ) -> (MutexGuard<'a, T>, bool)
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        while condition(&mut *guard) {
+            let now = Instant::now();
+            if now >= deadline {
+                return (guard, true);
+            }
+            guard = self.wait_timeout(guard, deadline - now).0;
+        }
+        (guard, false)
+    }
+
+    /// Wakes one waiting thread.
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    /// Wakes all waiting threads.
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.notify_all();
+    }
+
+    fn notified_since(&self, sequence: u64) -> Option<()> {
+        if self.sequence.load(Ordering::Acquire) != sequence {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
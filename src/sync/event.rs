@@ -0,0 +1,63 @@
+//! One-shot events.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use super::WaitQueue;
+
+/// A flag that is set once, threads block until it is.
+///
+/// Setting it wakes every waiting thread, later waits return right away.
+pub struct Event {
+    set: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Event {
+    /// Creates an event that is not set.
+    pub const fn new() -> Event {
+        Event {
+            set: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Sets the event and wakes all waiting threads. Setting it again has no effect.
+    ///
+    /// Can be called in interrupt context.
+    pub fn set(&self) {
+        if !self.set.swap(true, Ordering::Release) {
+            self.waiters.notify_all();
+        }
+    }
+
+    /// Returns whether the event is set.
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+
+    /// Blocks until the event is set.
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.check())
+    }
+
+    /// Like `wait`, but gives up after `timeout`. Returns whether the event is set.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.waiters
+            .wait_until_timeout(timeout, || self.check())
+            .is_some()
+    }
+
+    fn check(&self) -> Option<()> {
+        if self.is_set() {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Event {
+    fn default() -> Event {
+        Event::new()
+    }
+}
@@ -0,0 +1,157 @@
+//! A spinlock that keeps interrupts disabled while it is held.
+//!
+//! A plain `spin::Mutex` taken by both normal code and an interrupt handler deadlocks
+//! as soon as the interrupt arrives while the normal code holds the lock. `IrqSafeMutex`
+//! saves the `IF` flag, disables interrupts before spinning and restores the flag when
+//! the guard is dropped.
+//!
+//! With `debug_assertions` enabled the lock remembers the CPU that holds it and panics
+//! on a recursive acquisition from the same CPU instead of deadlocking silently.
+
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::x86_64::instructions::interrupts;
+use spin::{Mutex, MutexGuard};
+
+const NO_OWNER: u32 = u32::max_value();
+
+/// A mutual exclusion primitive that disables interrupts while it is held.
+pub struct IrqSafeMutex<T: ?Sized> {
+    owner: AtomicU32,
+    inner: Mutex<T>,
+}
+
+/// A guard for an `IrqSafeMutex`, the lock is released and the previous interrupt
+/// state restored when it is dropped.
+pub struct IrqSafeMutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a IrqSafeMutex<T>,
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    were_enabled: bool,
+}
+
+/// Returns an identifier of the executing CPU for the recursion check.
+///
+/// The APIC ID from the per-CPU area, or the one from CPUID early during boot.
+fn current_cpu() -> u32 {
+    if crate::percpu::is_initialized() {
+        return crate::percpu::local().apic_id();
+    }
+    raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map(|f| u32::from(f.initial_local_apic_id()))
+        .unwrap_or(0)
+}
+
+impl<T> IrqSafeMutex<T> {
+    /// Creates a new unlocked mutex.
+    pub const fn new(data: T) -> IrqSafeMutex<T> {
+        IrqSafeMutex {
+            owner: AtomicU32::new(NO_OWNER),
+            inner: Mutex::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    /// Disables interrupts and spins until the lock is acquired.
+    pub fn lock(&self) -> IrqSafeMutexGuard<T> {
+        let were_enabled = interrupts::are_enabled();
+        unsafe { interrupts::disable() };
+
+        if cfg!(debug_assertions) {
+            let cpu = current_cpu();
+            if self.owner.load(Ordering::Relaxed) == cpu {
+                // the holder can never run again on this cpu, release it so the
+                // panic message can get through if this is a console lock
+                self.owner.store(NO_OWNER, Ordering::Relaxed);
+                unsafe { self.inner.force_unlock() };
+                panic!("IrqSafeMutex: recursive lock acquisition on cpu {}", cpu);
+            }
+            let guard = self.inner.lock();
+            self.owner.store(cpu, Ordering::Relaxed);
+            return IrqSafeMutexGuard {
+                lock: self,
+                guard: ManuallyDrop::new(guard),
+                were_enabled,
+            };
+        }
+
+        IrqSafeMutexGuard {
+            lock: self,
+            guard: ManuallyDrop::new(self.inner.lock()),
+            were_enabled,
+        }
+    }
+
+    /// Tries to acquire the lock once, without spinning.
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<T>> {
+        let were_enabled = interrupts::are_enabled();
+        unsafe { interrupts::disable() };
+
+        match self.inner.try_lock() {
+            Some(guard) => {
+                if cfg!(debug_assertions) {
+                    self.owner.store(current_cpu(), Ordering::Relaxed);
+                }
+                Some(IrqSafeMutexGuard {
+                    lock: self,
+                    guard: ManuallyDrop::new(guard),
+                    were_enabled,
+                })
+            }
+            None => {
+                if were_enabled {
+                    unsafe { interrupts::enable() };
+                }
+                None
+            }
+        }
+    }
+
+    /// Forcibly releases the lock, used by the panic handler to reach the console.
+    ///
+    /// This is unsafe because the current holder still has a guard and will keep
+    /// accessing the data.
+    pub unsafe fn force_unlock(&self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.inner.force_unlock();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqSafeMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSafeMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSafeMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) {
+            self.lock.owner.store(NO_OWNER, Ordering::Relaxed);
+        }
+        // release the spinlock before interrupts can come back
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.were_enabled {
+            unsafe { interrupts::enable() };
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSafeMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "IrqSafeMutex {{ data: {:?} }}", &*guard),
+            None => write!(f, "IrqSafeMutex {{ <locked> }}"),
+        }
+    }
+}
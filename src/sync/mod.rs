@@ -0,0 +1,22 @@
+//! Synchronization primitives for kernel code.
+//!
+//! `IrqSafeMutex` spins with interrupts disabled and may be taken in interrupt
+//! context. The other primitives build on `WaitQueue` and park waiting threads
+//! through the scheduler; only their notifying side (`release`, `set`, the
+//! `notify_*` methods of `WaitQueue`) may be used in interrupt context.
+
+pub mod condvar;
+pub mod event;
+pub mod irq_mutex;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod wait_queue;
+
+pub use self::condvar::Condvar;
+pub use self::event::Event;
+pub use self::irq_mutex::{IrqSafeMutex, IrqSafeMutexGuard};
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::{Semaphore, SemaphoreGuard};
+pub use self::wait_queue::WaitQueue;
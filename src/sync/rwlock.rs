@@ -0,0 +1,192 @@
+//! A reader-writer lock that blocks waiting threads.
+//!
+//! Writers are preferred: once a writer waits, new readers wait behind it, so a
+//! steady stream of readers can not starve writers.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use super::WaitQueue;
+
+/// Set in `state` while a writer holds the lock, the other bits count readers.
+const WRITER: usize = !(usize::max_value() >> 1);
+
+/// A lock that allows many readers or a single writer at a time.
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    /// Writers waiting for the lock, readers do not enter while it is non-zero.
+    writers_waiting: AtomicUsize,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+/// A guard for shared read access, released when it is dropped.
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+}
+
+/// A guard for exclusive write access, released when it is dropped.
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates a new unlocked lock.
+    pub const fn new(data: T) -> RwLock<T> {
+        RwLock {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the lock and returns the protected data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Blocks until shared read access is granted.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.readers.wait_until(|| self.try_read())
+    }
+
+    /// Acquires read access if no writer holds or waits for the lock.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        if self.writers_waiting.load(Ordering::Relaxed) != 0 {
+            return None;
+        }
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & WRITER != 0 {
+                return None;
+            }
+            let previous = self.state.compare_and_swap(state, state + 1, Ordering::Acquire);
+            if previous == state {
+                return Some(RwLockReadGuard { lock: self });
+            }
+            state = previous;
+        }
+    }
+
+    /// Like `read`, but gives up after `timeout`.
+    pub fn read_timeout(&self, timeout: Duration) -> Option<RwLockReadGuard<T>> {
+        self.readers.wait_until_timeout(timeout, || self.try_read())
+    }
+
+    /// Blocks until exclusive write access is granted.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        if let Some(guard) = self.try_write() {
+            return guard;
+        }
+        self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        let guard = self.writers.wait_until(|| self.try_write());
+        self.stop_waiting();
+        guard
+    }
+
+    /// Acquires write access if the lock is free.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if self.state.compare_and_swap(0, WRITER, Ordering::Acquire) == 0 {
+            Some(RwLockWriteGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Like `write`, but gives up after `timeout`.
+    pub fn write_timeout(&self, timeout: Duration) -> Option<RwLockWriteGuard<T>> {
+        if let Some(guard) = self.try_write() {
+            return Some(guard);
+        }
+        self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        let guard = self.writers.wait_until_timeout(timeout, || self.try_write());
+        self.stop_waiting();
+        guard
+    }
+
+    /// Returns a mutable reference to the data, no locking needed.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn stop_waiting(&self) {
+        // the last waiting writer lets the readers in, they wait again while the
+        // lock is write locked
+        if self.writers_waiting.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.readers.notify_all();
+        }
+    }
+
+    fn read_unlock(&self) {
+        if self.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.writers.notify_one();
+        }
+    }
+
+    fn write_unlock(&self) {
+        self.state.store(0, Ordering::Release);
+        if self.writers_waiting.load(Ordering::Relaxed) != 0 && self.writers.notify_one() {
+            return;
+        }
+        self.readers.notify_all();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => write!(f, "RwLock {{ data: {:?} }}", &*guard),
+            None => write!(f, "RwLock {{ <locked> }}"),
+        }
+    }
+}
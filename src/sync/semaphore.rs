@@ -0,0 +1,83 @@
+//! A counting semaphore that blocks waiting threads.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use super::WaitQueue;
+
+/// A counter of available permits, `acquire` blocks while there is none.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with `permits` available permits.
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Blocks until a permit is available and takes it.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire_permit())
+    }
+
+    /// Takes a permit if one is available, without blocking.
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_permit().is_some()
+    }
+
+    /// Like `acquire`, but gives up after `timeout`. Returns whether a permit was
+    /// taken.
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.waiters
+            .wait_until_timeout(timeout, || self.try_acquire_permit())
+            .is_some()
+    }
+
+    /// Returns a permit and wakes a waiting thread.
+    ///
+    /// Can be called in interrupt context.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    /// Takes a permit for the lifetime of the returned guard.
+    pub fn access(&self) -> SemaphoreGuard {
+        self.acquire();
+        SemaphoreGuard { semaphore: self }
+    }
+
+    /// The number of available permits.
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+
+    fn try_acquire_permit(&self) -> Option<()> {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+        while permits != 0 {
+            let previous = self
+                .permits
+                .compare_and_swap(permits, permits - 1, Ordering::Acquire);
+            if previous == permits {
+                return Some(());
+            }
+            permits = previous;
+        }
+        None
+    }
+}
+
+/// A permit taken by `Semaphore::access`, released when it is dropped.
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Drop for SemaphoreGuard<'a> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
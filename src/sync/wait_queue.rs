@@ -0,0 +1,165 @@
+//! Wait queues, the building block of the blocking primitives.
+//!
+//! A thread waits for a condition by calling `wait_until` with a closure that
+//! checks it. The closure runs with the queue locked, the thread only blocks if it
+//! returns `None` and is then woken by `notify_one` or `notify_all`. Code that makes
+//! the condition true must do so before it notifies the queue; since the waiter
+//! checks the condition and enqueues itself under the same lock, no notification
+//! gets lost in between.
+//!
+//! Wakeups may be spurious, the waiter checks the condition again before it
+//! returns. Callers that can not block, like the idle thread, code with interrupts
+//! or preemption disabled and everything before `thread::init`, spin on the
+//! condition instead.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use super::IrqSafeMutex;
+use crate::thread::{self, scheduler, State, Thread};
+use crate::time::{self, Instant, TimerId};
+use crate::x86_64::instructions::interrupts;
+
+/// A queue of threads waiting for a condition.
+pub struct WaitQueue {
+    /// Blocked threads in the order they arrived.
+    waiters: IrqSafeMutex<Vec<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    /// Creates an empty queue.
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: IrqSafeMutex::new(Vec::new()),
+        }
+    }
+
+    /// Blocks until `condition` returns `Some` and returns its value.
+    ///
+    /// `condition` runs with the queue locked and interrupts disabled, it must not
+    /// block or notify this queue.
+    pub fn wait_until<R, F>(&self, mut condition: F) -> R
+    where
+        F: FnMut() -> Option<R>,
+    {
+        loop {
+            if let Some(value) = condition() {
+                return value;
+            }
+            if !scheduler::can_block() {
+                core::hint::spin_loop();
+                continue;
+            }
+
+            let blocked = interrupts::without_interrupts(|| self.block(&mut condition, None));
+            if let Ok(value) = blocked {
+                return value;
+            }
+        }
+    }
+
+    /// Like `wait_until`, but gives up after `timeout`. Returns `None` if the
+    /// condition did not become true in time.
+    pub fn wait_until_timeout<R, F>(&self, timeout: Duration, mut condition: F) -> Option<R>
+    where
+        F: FnMut() -> Option<R>,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(value) = condition() {
+                return Some(value);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            if !scheduler::can_block() {
+                core::hint::spin_loop();
+                continue;
+            }
+
+            let blocked =
+                interrupts::without_interrupts(|| self.block(&mut condition, Some(deadline - now)));
+            match blocked {
+                Ok(value) => return Some(value),
+                Err(timer) => {
+                    // a no-op unless the thread was notified before the deadline
+                    time::cancel_timer(timer.expect("timed wait without a timer"));
+                    // woken by the timer, the thread may still be queued
+                    self.remove(&thread::current());
+                }
+            }
+        }
+    }
+
+    /// Checks the condition with the queue locked and blocks the calling thread if
+    /// it is false, with a timer that wakes it after `timeout` if one is given.
+    ///
+    /// Returns the condition's value if the thread did not block, the timer once it
+    /// was woken.
+    fn block<R, F>(
+        &self,
+        condition: &mut F,
+        timeout: Option<Duration>,
+    ) -> Result<R, Option<TimerId>>
+    where
+        F: FnMut() -> Option<R>,
+    {
+        debug_assert!(!interrupts::are_enabled());
+        let mut waiters = self.waiters.lock();
+        if let Some(value) = condition() {
+            return Ok(value);
+        }
+        let current = thread::current();
+        scheduler::prepare_to_block(State::Blocked);
+        waiters.push(current.clone());
+        drop(waiters);
+
+        // armed only now, a timer that expires early finds the thread blocked
+        let timer = timeout.map(|timeout| {
+            time::add_timer(timeout, move || {
+                thread::wake(&current);
+            })
+        });
+        thread::schedule();
+        Err(timer)
+    }
+
+    fn remove(&self, thread: &Arc<Thread>) {
+        let mut waiters = self.waiters.lock();
+        waiters.retain(|waiter| !Arc::ptr_eq(waiter, thread));
+    }
+
+    /// Wakes the longest waiting thread. Returns false if no thread was waiting.
+    ///
+    /// Can be called in interrupt context.
+    pub fn notify_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        while !waiters.is_empty() {
+            // a waiter that timed out is already awake, skip it
+            if thread::wake(&waiters.remove(0)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Wakes all waiting threads and returns how many were woken.
+    ///
+    /// Can be called in interrupt context.
+    pub fn notify_all(&self) -> usize {
+        let waiters = core::mem::replace(&mut *self.waiters.lock(), Vec::new());
+        waiters.iter().filter(|waiter| thread::wake(waiter)).count()
+    }
+
+    /// Returns whether no thread is waiting.
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> WaitQueue {
+        WaitQueue::new()
+    }
+}
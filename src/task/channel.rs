@@ -0,0 +1,130 @@
+//! Unbounded multi-producer, single-consumer channels.
+//!
+//! Sending never blocks and may happen in interrupt context, which makes channels
+//! the way for interrupt handlers to hand data to a task.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use super::AtomicWaker;
+use crate::sync::IrqSafeMutex;
+
+struct Shared<T> {
+    queue: IrqSafeMutex<VecDeque<T>>,
+    /// The receiver's waker.
+    waker: AtomicWaker,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+/// The sending half of a channel, can be cloned.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a channel.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: IrqSafeMutex::new(VecDeque::new()),
+        waker: AtomicWaker::new(),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+    });
+    let sender = Sender {
+        shared: shared.clone(),
+    };
+    (sender, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    /// Queues `value` and wakes the receiver. Returns the value back if the receiver
+    /// was dropped.
+    ///
+    /// Can be called in interrupt context.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        if !self.shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        self.shared.queue.lock().push_back(value);
+        self.shared.waker.wake();
+        Ok(())
+    }
+
+    /// Returns whether the receiver was dropped.
+    pub fn is_closed(&self) -> bool {
+        !self.shared.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // the receiver sees the channel closed once the last sender is gone
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Returns the next value, or `None` once the channel is empty and all senders
+    /// were dropped.
+    pub fn recv(&mut self) -> Recv<T> {
+        Recv { receiver: self }
+    }
+
+    /// Returns the next value if one is queued, without waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.shared.queue.lock().pop_front()
+    }
+
+    fn poll_recv(&mut self, context: &mut Context) -> Poll<Option<T>> {
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+        self.shared.waker.register(context.waker());
+        // check again, a value or the last sender's drop may have come in between
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+        if self.shared.senders.load(Ordering::Acquire) == 0 {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+    }
+}
+
+/// The future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<'a, T> Future for Recv<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(context)
+    }
+}
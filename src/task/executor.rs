@@ -0,0 +1,260 @@
+//! The kernel task executor.
+//!
+//! Ready tasks wait in a single queue shared by all CPUs. Waking a task puts it
+//! into the queue unless it is already there, `run_ready` pops and polls them. A task
+//! woken while it is being polled is queued again and polled once more afterwards,
+//! by whichever CPU gets to it; its future is locked while it is polled, so the
+//! second CPU waits for the first one.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use super::AtomicWaker;
+use crate::sync::{IrqSafeMutex, WaitQueue};
+use crate::thread::scheduler;
+use spin::Mutex;
+
+use lazy_static::lazy_static;
+
+/// A unique task identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    /// Returns the identifier as a number.
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Task {
+    /// `None` once the future completed.
+    future: Mutex<Option<BoxFuture>>,
+    /// Whether the task is in the ready queue.
+    queued: AtomicBool,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Task>) {
+        EXECUTOR.schedule(self);
+    }
+
+    fn wake_by_ref(self: &Arc<Task>) {
+        EXECUTOR.schedule(self.clone());
+    }
+}
+
+/// Polls the ready tasks.
+pub struct Executor {
+    ready: IrqSafeMutex<VecDeque<Arc<Task>>>,
+    /// Spawned tasks that did not complete yet.
+    live: AtomicUsize,
+}
+
+lazy_static! {
+    static ref EXECUTOR: Executor = Executor {
+        ready: IrqSafeMutex::new(VecDeque::new()),
+        live: AtomicUsize::new(0),
+    };
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl Executor {
+    /// Returns the kernel's executor.
+    pub fn global() -> &'static Executor {
+        &EXECUTOR
+    }
+
+    /// Adds a task running `future` and returns a handle to await its output.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let id = TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        let state = Arc::new(JoinState {
+            result: Mutex::new(None),
+            waker: AtomicWaker::new(),
+        });
+        let output = state.clone();
+        let wrapped = async move {
+            let value = future.await;
+            *output.result.lock() = Some(value);
+            output.waker.wake();
+        };
+
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(wrapped))),
+            queued: AtomicBool::new(false),
+        });
+        self.live.fetch_add(1, Ordering::Relaxed);
+        self.schedule(task);
+        JoinHandle { id, state }
+    }
+
+    fn schedule(&self, task: Arc<Task>) {
+        if !task.queued.swap(true, Ordering::AcqRel) {
+            self.ready.lock().push_back(task);
+        }
+    }
+
+    /// Polls the tasks that are ready right now and returns how many were polled.
+    ///
+    /// Tasks woken while this runs are left for the next call, so a task that keeps
+    /// waking itself can not keep the CPU forever.
+    pub fn run_ready(&self) -> usize {
+        let count = self.ready.lock().len();
+        for polled in 0..count {
+            let task = match self.ready.lock().pop_front() {
+                Some(task) => task,
+                None => return polled,
+            };
+            task.queued.store(false, Ordering::Release);
+            self.poll(task);
+        }
+        count
+    }
+
+    fn poll(&self, task: Arc<Task>) {
+        let waker = Waker::from(task.clone());
+        let mut context = Context::from_waker(&waker);
+
+        let mut slot = task.future.lock();
+        let finished = match *slot {
+            Some(ref mut future) => future.as_mut().poll(&mut context).is_ready(),
+            // woken again after it completed
+            None => false,
+        };
+        if finished {
+            *slot = None;
+            self.live.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns whether a task is ready to be polled.
+    pub fn has_ready(&self) -> bool {
+        !self.ready.lock().is_empty()
+    }
+
+    /// The number of spawned tasks that did not complete yet.
+    pub fn live_tasks(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+}
+
+struct JoinState<T> {
+    result: Mutex<Option<T>>,
+    waker: AtomicWaker,
+}
+
+/// Awaits the output of a spawned task.
+///
+/// Dropping the handle detaches the task, it keeps running.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// The task this handle refers to.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Returns whether the task has completed.
+    pub fn is_finished(&self) -> bool {
+        self.state.result.lock().is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<T> {
+        self.state.waker.register(context.waker());
+        match self.state.result.lock().take() {
+            Some(value) => Poll::Ready(value),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Adds a task running `future` to the kernel's executor.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    EXECUTOR.spawn(future)
+}
+
+/// Polls the ready tasks of the kernel's executor, called by the idle loop.
+pub fn run_ready() -> usize {
+    EXECUTOR.run_ready()
+}
+
+/// Returns whether the kernel's executor has a task ready to be polled.
+pub fn has_ready() -> bool {
+    EXECUTOR.has_ready()
+}
+
+/// Wakes the thread that is blocked in `block_on`.
+struct ThreadWaker {
+    woken: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<ThreadWaker>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<ThreadWaker>) {
+        self.woken.store(true, Ordering::Release);
+        self.waiters.notify_one();
+    }
+}
+
+/// Runs `future` to completion on the calling thread and returns its output.
+///
+/// The thread blocks between polls until the future's waker is woken. Callers that
+/// can not block, like the boot code on the idle thread, run the ready tasks while
+/// they wait instead. Must not be called from a task.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let signal = Arc::new(ThreadWaker {
+        woken: AtomicBool::new(false),
+        waiters: WaitQueue::new(),
+    });
+    let waker = Waker::from(signal.clone());
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(value) = future.as_mut().poll(&mut context) {
+            return value;
+        }
+        if !scheduler::can_block() {
+            while !signal.woken.swap(false, Ordering::AcqRel) {
+                if run_ready() == 0 {
+                    core::hint::spin_loop();
+                }
+            }
+            continue;
+        }
+        signal.waiters.wait_until(|| {
+            if signal.woken.swap(false, Ordering::AcqRel) {
+                Some(())
+            } else {
+                None
+            }
+        });
+    }
+}
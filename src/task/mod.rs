@@ -0,0 +1,20 @@
+//! Cooperative async tasks.
+//!
+//! A task is a boxed `Future` that the `Executor` polls whenever its `Waker` was
+//! woken. Wakers may be woken from anywhere, including interrupt handlers, which is
+//! how driver completions, timers and channel messages resume the task waiting for
+//! them. The idle loop of every CPU runs the ready tasks, so tasks only get CPU time
+//! that no thread wants; a task must never block, it returns `Pending` instead.
+//!
+//! `block_on` drives a single future on the calling thread instead, parking the
+//! thread between polls.
+
+pub mod channel;
+pub mod executor;
+pub mod timer;
+pub mod waker;
+
+pub use self::channel::{channel, Receiver, Sender};
+pub use self::executor::{block_on, has_ready, run_ready, spawn, Executor, JoinHandle, TaskId};
+pub use self::timer::{sleep, sleep_until, timeout, Elapsed, Sleep, Timeout};
+pub use self::waker::AtomicWaker;
@@ -0,0 +1,100 @@
+//! Async timers on top of the kernel timers.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+use super::AtomicWaker;
+use crate::time::{self, Instant, TimerId};
+
+/// A future that completes at a deadline, created by `sleep` and `sleep_until`.
+///
+/// The kernel timer is armed on the first poll and cancelled when the future is
+/// dropped.
+pub struct Sleep {
+    deadline: Instant,
+    waker: Arc<AtomicWaker>,
+    timer: Option<TimerId>,
+}
+
+/// Completes `duration` from now.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Completes at `deadline`.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        waker: Arc::new(AtomicWaker::new()),
+        timer: None,
+    }
+}
+
+impl Sleep {
+    /// The instant the future completes at.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        self.waker.register(context.waker());
+        if self.timer.is_none() {
+            let waker = self.waker.clone();
+            let timer = time::add_timer(self.deadline - now, move || waker.wake());
+            self.timer = Some(timer);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            time::cancel_timer(timer);
+        }
+    }
+}
+
+/// The error of a `Timeout` whose deadline passed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// A future that runs another one with a deadline, created by `timeout`.
+pub struct Timeout<F> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+/// Runs `future` for at most `duration`.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        sleep: sleep(duration),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(value) = self.future.as_mut().poll(context) {
+            return Poll::Ready(Ok(value));
+        }
+        match Pin::new(&mut self.sleep).poll(context) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! A waker slot shared between a future and the code that completes it.
+
+use core::task::Waker;
+use crate::sync::IrqSafeMutex;
+
+/// Holds the waker of the task that waits for an event, the side that produces the
+/// event wakes it.
+///
+/// The waiting future registers its waker and then checks for the event, the
+/// producer records the event and then calls `wake`. Either the check sees the
+/// event or `wake` sees the waker, so no wakeup is lost.
+pub struct AtomicWaker {
+    waker: IrqSafeMutex<Option<Waker>>,
+}
+
+impl AtomicWaker {
+    /// Creates an empty slot.
+    pub const fn new() -> AtomicWaker {
+        AtomicWaker {
+            waker: IrqSafeMutex::new(None),
+        }
+    }
+
+    /// Stores `waker`, replacing the one registered before.
+    pub fn register(&self, waker: &Waker) {
+        let mut slot = self.waker.lock();
+        match *slot {
+            Some(ref registered) if registered.will_wake(waker) => (),
+            _ => *slot = Some(waker.clone()),
+        }
+    }
+
+    /// Wakes and removes the registered waker, if any.
+    ///
+    /// Can be called in interrupt context.
+    pub fn wake(&self) {
+        let waker = self.waker.lock().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for AtomicWaker {
+    fn default() -> AtomicWaker {
+        AtomicWaker::new()
+    }
+}
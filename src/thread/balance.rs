@@ -0,0 +1,102 @@
+//! Load balancing between the per-CPU run queues.
+//!
+//! The load of a CPU is the number of threads queued or running on it. Threads are
+//! placed when they become runnable: a woken thread returns to the CPU it ran on
+//! last, which likely still caches its data, unless that CPU is busy while another
+//! allowed CPU is less loaded. New threads go to the least loaded allowed CPU.
+//!
+//! Placement alone does not keep the CPUs even, threads block and exit unevenly. A
+//! CPU that is about to idle steals a thread from the busiest CPU, and every
+//! `BALANCE_INTERVAL_TICKS` each CPU pulls threads from the busiest CPU until the
+//! two differ by at most one thread.
+
+use alloc::sync::Arc;
+use super::scheduler::{self, lock_cpu, lock_pair, RunQueue};
+use super::Thread;
+use crate::smp::CpuSet;
+
+/// Kernel ticks between two balancing passes of a CPU.
+pub const BALANCE_INTERVAL_TICKS: u64 = 10;
+
+/// The online CPUs `thread` may run on, every online CPU if its affinity allows
+/// none of them.
+pub fn allowed_cpus(thread: &Thread) -> CpuSet {
+    let online = scheduler::online_cpus();
+    let allowed = thread.affinity().intersection(online);
+    if allowed.is_empty() {
+        online
+    } else {
+        allowed
+    }
+}
+
+/// Picks the CPU a thread that becomes runnable is queued on, `prev` is the CPU it
+/// belongs to now.
+pub(super) fn select_cpu(thread: &Thread, prev: usize) -> usize {
+    let allowed = allowed_cpus(thread);
+    if allowed.contains(prev) && scheduler::load(prev) == 0 {
+        return prev;
+    }
+    // ties go to `prev`, then to the lowest CPU number
+    allowed
+        .iter()
+        .min_by_key(|&cpu| (scheduler::load(cpu), cpu != prev))
+        .unwrap_or(prev)
+}
+
+/// Returns the most loaded CPU other than `this` and its load.
+fn busiest(this: usize) -> Option<(usize, usize)> {
+    scheduler::online_cpus()
+        .iter()
+        .filter(|&cpu| cpu != this)
+        .map(|cpu| (cpu, scheduler::load(cpu)))
+        .max_by_key(|&(_, load)| load)
+}
+
+/// Steals a thread for `this` if its run queue is empty, called before the CPU
+/// picks its next thread.
+pub(super) fn pull_if_idle(this: usize) {
+    if !lock_cpu(this).is_empty() {
+        return;
+    }
+    if let Some((busiest, load)) = busiest(this) {
+        // the busiest CPU's own running thread is not up for grabs
+        if load > 1 {
+            pull(busiest, this, 1);
+        }
+    }
+}
+
+/// Evens out the load of `this` and the busiest CPU, called from the tick.
+pub(super) fn rebalance(this: usize) {
+    let (busiest, load) = match busiest(this) {
+        Some(busiest) => busiest,
+        None => return,
+    };
+    let own = scheduler::load(this);
+    if load > own + 1 {
+        pull(busiest, this, (load - own) / 2);
+    }
+}
+
+/// Moves up to `count` queued threads from `source` to `this`, returns how many
+/// were moved.
+fn pull(source: usize, this: usize, count: usize) -> usize {
+    let (mut to, mut from) = lock_pair(this, source);
+    let mut moved = 0;
+    while moved < count {
+        let thread = match from.steal_for(this) {
+            Some(thread) => thread,
+            None => break,
+        };
+        move_queued(thread, &mut from, &mut to);
+        moved += 1;
+    }
+    moved
+}
+
+fn move_queued(thread: Arc<Thread>, from: &mut RunQueue, to: &mut RunQueue) {
+    scheduler::migrate(&thread, from, to);
+    to.enqueue(thread.clone(), super::policy::Enqueue::Wakeup);
+    to.preempt_if_needed(&thread);
+}
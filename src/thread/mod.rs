@@ -0,0 +1,413 @@
+//! Kernel threads.
+//!
+//! A thread runs a closure on its own stack from the kernel stack area, with an
+//! unmapped guard page below it. `switch_context` in `context_switch.asm` saves the
+//! callee-saved registers on the old stack and restores them from the new one, the
+//! `scheduler` decides when and to which thread a CPU switches.
+//!
+//! Every CPU adopts its boot context as its idle thread in `init`. The idle thread
+//! never enters the run queue, a CPU runs it whenever there is nothing else to do.
+//!
+//! A thread that returned from its closure or called `exit` is moved to a dead list
+//! and reaped by the next thread that runs on any CPU, which returns its stack to
+//! the stack allocator and drops the thread.
+//!
+//! Each thread is scheduled by the class of its `Policy`, new threads start with
+//! `Policy::DEFAULT`. The CPU time a thread used is accounted on every switch and
+//! every tick.
+//!
+//! Each CPU schedules the threads of its own run queue. A thread may run on the
+//! CPUs of its affinity set, all of them by default, and `balance` moves threads
+//! between the allowed CPUs to even out their load.
+
+pub mod balance;
+pub mod policy;
+pub mod scheduler;
+
+pub use self::policy::Policy;
+pub use self::scheduler::{
+    idle_loop, online_cpus, preempt_disable, preempt_enable, schedule, set_affinity,
+    set_policy, wake, yield_now,
+};
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use crate::memory::paging::{self, InactivePageTable, PhysicalAddress};
+use crate::memory::{self, Stack};
+use crate::percpu;
+use crate::smp::{self, CpuSet};
+use crate::sync::Event;
+use crate::time::{self, Instant};
+use crate::x86_64::instructions::interrupts;
+use spin::Mutex;
+
+/// Stack size of spawned threads.
+pub const STACK_PAGES: usize = 16;
+
+/// A unique thread identifier, the idle threads count from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// Returns the identifier as a number.
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The scheduling state of a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum State {
+    /// Waiting in the run queue for a CPU.
+    Runnable,
+    /// Executing on a CPU.
+    Running,
+    /// Waiting for another thread or an interrupt to wake it.
+    Blocked,
+    /// Waiting for a timer to wake it.
+    Sleeping,
+    /// Finished, waiting to be reaped.
+    Dead,
+}
+
+impl State {
+    fn from_u8(value: u8) -> State {
+        match value {
+            0 => State::Runnable,
+            1 => State::Running,
+            2 => State::Blocked,
+            3 => State::Sleeping,
+            _ => State::Dead,
+        }
+    }
+}
+
+type Entry = Box<dyn FnOnce() + Send>;
+
+/// A kernel thread.
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: AtomicU8,
+    idle: bool,
+    /// Whether the thread is in the run queue, only changed with the queue locked.
+    queued: AtomicBool,
+    /// The `Policy`, encoded by `Policy::to_bits`, only changed with the queue locked.
+    policy: AtomicU32,
+    /// The CPU whose run queue the thread belongs to, only changed with that run
+    /// queue locked.
+    cpu: AtomicUsize,
+    /// The `CpuSet` bits of the CPUs the thread may run on.
+    affinity: AtomicU64,
+    /// The physical address of the P4 table the thread runs on, 0 for the kernel's.
+    address_space: AtomicU64,
+    /// Virtual runtime in nanoseconds, used by the fair class.
+    vruntime: AtomicU64,
+    /// Nanoseconds spent running.
+    cpu_time: AtomicU64,
+    /// `time::monotonic_ns` when the CPU time was last accounted.
+    exec_start: AtomicU64,
+    /// `time::monotonic_ns` when the thread was last picked to run.
+    slice_start: AtomicU64,
+    /// Set while a CPU executes on the thread's stack. `switch_context` clears it
+    /// once the registers are saved, before that no other CPU may resume the thread.
+    running: AtomicBool,
+    /// The stack pointer saved by `switch_context`.
+    saved_rsp: UnsafeCell<u64>,
+    /// `None` for idle threads, which run on their CPU's boot stack. Taken and freed
+    /// by `scheduler::reap_dead`, the last reference to a thread may be dropped in
+    /// interrupt context where the memory controller can not be locked.
+    stack: Mutex<Option<Stack>>,
+    entry: Mutex<Option<Entry>>,
+    /// Set when the thread finished, `JoinHandle::join` waits for it.
+    exited: Event,
+}
+
+// `saved_rsp` is only accessed by the CPU switching away from or to the thread,
+// `running` orders the two
+unsafe impl Sync for Thread {}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl Thread {
+    fn new(name: String, stack: Option<Stack>, entry: Option<Entry>, idle: bool) -> Thread {
+        let state = if idle { State::Running } else { State::Runnable };
+        let cpu = percpu::cpu_index();
+        // idle threads never leave their CPU
+        let affinity = if idle { CpuSet::single(cpu) } else { CpuSet::ALL };
+        Thread {
+            id: ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            name,
+            state: AtomicU8::new(state as u8),
+            idle,
+            queued: AtomicBool::new(false),
+            policy: AtomicU32::new(Policy::DEFAULT.to_bits()),
+            cpu: AtomicUsize::new(cpu),
+            affinity: AtomicU64::new(affinity.bits()),
+            address_space: AtomicU64::new(0),
+            vruntime: AtomicU64::new(0),
+            cpu_time: AtomicU64::new(0),
+            exec_start: AtomicU64::new(0),
+            slice_start: AtomicU64::new(0),
+            running: AtomicBool::new(idle),
+            saved_rsp: UnsafeCell::new(0),
+            stack: Mutex::new(stack),
+            entry: Mutex::new(entry),
+            exited: Event::new(),
+        }
+    }
+
+    /// The thread's identifier.
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// The name given to `spawn`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The current scheduling state.
+    pub fn state(&self) -> State {
+        State::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Returns whether this is the idle thread of a CPU.
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    /// The scheduling policy, change it with `set_policy`.
+    pub fn policy(&self) -> Policy {
+        Policy::from_bits(self.policy.load(Ordering::Relaxed))
+    }
+
+    /// The CPU the thread runs or last ran on, or is queued on.
+    pub fn cpu(&self) -> usize {
+        self.cpu.load(Ordering::Relaxed)
+    }
+
+    /// The CPUs the thread may run on, change them with `set_affinity`.
+    pub fn affinity(&self) -> CpuSet {
+        CpuSet::from_bits(self.affinity.load(Ordering::Relaxed))
+    }
+
+    /// The P4 table of the user address space the thread runs on, `None` if it runs
+    /// on the kernel's page table.
+    pub fn address_space(&self) -> Option<PhysicalAddress> {
+        match self.address_space.load(Ordering::Relaxed) {
+            0 => None,
+            p4 => Some(p4),
+        }
+    }
+
+    /// The CPU time the thread used, up to its last switch or tick.
+    pub fn cpu_time(&self) -> Duration {
+        Duration::from_nanos(self.cpu_time.load(Ordering::Relaxed))
+    }
+}
+
+impl fmt::Debug for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Thread")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("state", &self.state())
+            .field("policy", &self.policy())
+            .field("cpu", &self.cpu())
+            .field("affinity", &self.affinity())
+            .field("address_space", &self.address_space())
+            .field("cpu_time", &self.cpu_time())
+            .finish()
+    }
+}
+
+/// An owned permission to wait for a thread and take its result.
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// The thread this handle refers to.
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    /// Returns whether the thread has finished.
+    pub fn is_finished(&self) -> bool {
+        self.thread.state() == State::Dead
+    }
+
+    /// Waits for the thread to finish without taking its result, also for threads
+    /// that called `exit`.
+    pub fn wait(&self) {
+        self.thread.exited.wait();
+    }
+
+    /// Waits for the thread to finish and returns the value its closure returned.
+    ///
+    /// Panics if the thread called `exit` instead of returning.
+    pub fn join(self) -> T {
+        self.thread.exited.wait();
+        self.result
+            .lock()
+            .take()
+            .expect("joined thread exited without a result")
+    }
+}
+
+/// Adopts the executing boot context as the idle thread of this CPU.
+///
+/// Requires the per-CPU area and the kernel heap. The bootstrap processor also
+/// installs the reschedule IPI handler, which needs the interrupt controller.
+pub fn init() {
+    if smp::is_bsp() {
+        scheduler::init();
+    }
+    let cpu = percpu::cpu_index();
+    let idle = Arc::new(Thread::new(format!("idle/{}", cpu), None, None, true));
+    scheduler::init_cpu(idle);
+}
+
+/// Returns the thread running on the executing CPU.
+pub fn current() -> Arc<Thread> {
+    scheduler::try_current().expect("threads not initialized on this CPU")
+}
+
+/// Starts a new thread running `f` and returns a handle to join it.
+///
+/// Requires the memory controller to be installed. Must not be called in interrupt
+/// context.
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let packet = result.clone();
+    let entry: Entry = Box::new(move || {
+        let value = f();
+        *packet.lock() = Some(value);
+    });
+
+    let stack = memory::controller()
+        .alloc_stack(STACK_PAGES)
+        .expect("could not allocate a thread stack");
+    let rsp = initial_frame(&stack);
+
+    let thread = Arc::new(Thread::new(name.to_string(), Some(stack), Some(entry), false));
+    unsafe { *thread.saved_rsp.get() = rsp };
+
+    scheduler::enqueue_new(thread.clone());
+    JoinHandle { thread, result }
+}
+
+/// Ends the calling thread. Threads that return from their closure exit implicitly.
+pub fn exit() -> ! {
+    let thread = current();
+    assert!(!thread.is_idle(), "the idle thread can not exit");
+
+    thread.exited.set();
+    interrupts::without_interrupts(|| {
+        thread.set_state(State::Dead);
+        drop(thread);
+        schedule();
+    });
+    unreachable!("dead thread was resumed");
+}
+
+/// Moves the calling thread into the address space of `table`, it runs on that
+/// table from now on. The table is never freed.
+pub fn set_address_space(table: InactivePageTable) {
+    let p4 = table.p4_address();
+    let thread = current();
+    // the scheduler switches to the stored table, so both change together
+    interrupts::without_interrupts(|| {
+        thread.address_space.store(p4, Ordering::Relaxed);
+        paging::activate(p4);
+    });
+}
+
+/// Puts the calling thread to sleep for at least `duration`.
+///
+/// The idle thread and code that can not block halt the CPU until the deadline
+/// instead.
+pub fn sleep(duration: Duration) {
+    // a deadline past the end of the clock is never reached
+    let deadline = Instant::now().saturating_add(duration);
+
+    if !scheduler::can_block() {
+        assert!(interrupts::are_enabled(), "sleep with interrupts disabled");
+        while Instant::now() < deadline {
+            unsafe { crate::x86_64::instructions::halt() };
+        }
+        return;
+    }
+
+    let thread = current();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+
+        let sleeper = thread.clone();
+        let timer = interrupts::without_interrupts(|| {
+            scheduler::prepare_to_block(State::Sleeping);
+            let timer = time::add_timer(deadline - now, move || {
+                wake(&sleeper);
+            });
+            schedule();
+            timer
+        });
+        // a no-op unless something else woke the thread early
+        time::cancel_timer(timer);
+    }
+}
+
+/// Prepares a new thread's stack so that `switch_context` returns into
+/// `thread_start` with zeroed callee-saved registers.
+fn initial_frame(stack: &Stack) -> u64 {
+    let frame = [
+        0,                   // r15
+        0,                   // r14
+        0,                   // r13
+        0,                   // r12
+        0,                   // rbx
+        0,                   // rbp
+        thread_start as u64, // return address of switch_context
+        0,                   // return address of thread_start, never used
+    ];
+
+    // `thread_start` is entered with rsp + 8 aligned to 16 bytes, like after a call
+    let rsp = stack.top() - (frame.len() as u64) * 8;
+    unsafe {
+        core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+    }
+    rsp
+}
+
+/// The first function a new thread runs, entered with interrupts disabled.
+extern "C" fn thread_start() -> ! {
+    unsafe { interrupts::enable() };
+    scheduler::reap_dead();
+
+    let entry = current()
+        .entry
+        .lock()
+        .take()
+        .expect("thread started twice");
+    entry();
+    exit()
+}
@@ -0,0 +1,180 @@
+//! Fair-share scheduling by virtual runtime.
+//!
+//! Every thread accumulates virtual runtime while it runs: its CPU time scaled by
+//! `NICE_0_WEIGHT / weight`, where the weight follows from the nice value. The
+//! thread with the least virtual runtime runs next, so over time every thread gets
+//! CPU time in proportion to its weight. A nice step changes the share by about 10%.
+//!
+//! A thread that wakes up is placed at no less than the queue's minimum virtual
+//! runtime, minus half a scheduling period of credit, so a long sleep does not buy
+//! it a long run that starves everyone else.
+//!
+//! Every CPU has its own minimum virtual runtime. A thread that moves to another CPU
+//! keeps its distance to the minimum, not its absolute virtual runtime.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::cmp;
+use core::sync::atomic::Ordering;
+use super::{Enqueue, Policy, Scheduler, MIN_NICE};
+use crate::thread::{Thread, ThreadId};
+
+/// The weight of nice 0.
+pub const NICE_0_WEIGHT: u64 = 1024;
+
+/// The period in which every runnable thread should run once, while there are few
+/// enough of them.
+pub const SCHED_LATENCY_NS: u64 = 20_000_000;
+
+/// The shortest slice a thread runs before it is preempted by the tick.
+pub const MIN_GRANULARITY_NS: u64 = 4_000_000;
+
+/// How much less virtual runtime a woken thread needs to preempt the running one.
+pub const WAKEUP_GRANULARITY_NS: u64 = 1_000_000;
+
+/// Weights for nice -20 to 19.
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, // -20
+    29154, 23254, 18705, 14949, 11916, // -15
+    9548, 7620, 6100, 4904, 3906, // -10
+    3121, 2501, 1991, 1586, 1277, // -5
+    1024, 820, 655, 526, 423, // 0
+    335, 272, 215, 172, 137, // 5
+    110, 87, 70, 56, 45, // 10
+    36, 29, 23, 18, 15, // 15
+];
+
+/// Returns the weight of a nice value.
+pub fn weight(nice: i8) -> u64 {
+    WEIGHTS[(nice - MIN_NICE) as usize]
+}
+
+fn thread_weight(thread: &Thread) -> u64 {
+    match thread.policy() {
+        Policy::Fair(nice) => weight(nice),
+        policy => panic!("{:?} thread in the fair class", policy),
+    }
+}
+
+/// The fair-share class.
+pub struct Fair {
+    /// Queued threads ordered by virtual runtime. A thread's virtual runtime only
+    /// changes while it is not queued.
+    queue: BTreeMap<(u64, ThreadId), Arc<Thread>>,
+    /// The sum of the queued threads' weights.
+    load: u64,
+    /// Never decreases, the reference point for threads that join the queue.
+    min_vruntime: u64,
+}
+
+impl Fair {
+    /// Creates an empty class.
+    pub fn new() -> Fair {
+        Fair {
+            queue: BTreeMap::new(),
+            load: 0,
+            min_vruntime: 0,
+        }
+    }
+
+    fn update_min_vruntime(&mut self, running: u64) {
+        let leftmost = self.queue.keys().next().map(|&(vruntime, _)| vruntime);
+        let min = leftmost.map_or(running, |leftmost| cmp::min(leftmost, running));
+        self.min_vruntime = cmp::max(self.min_vruntime, min);
+    }
+
+    /// The wall-clock slice `thread` gets with the current load.
+    fn slice(&self, thread: &Thread) -> u64 {
+        let weight = thread_weight(thread);
+        let slice = SCHED_LATENCY_NS * weight / (self.load + weight);
+        cmp::max(slice, MIN_GRANULARITY_NS)
+    }
+}
+
+impl Scheduler for Fair {
+    fn enqueue(&mut self, thread: Arc<Thread>, reason: Enqueue) {
+        if reason == Enqueue::Wakeup {
+            let floor = self.min_vruntime.saturating_sub(SCHED_LATENCY_NS / 2);
+            let vruntime = thread.vruntime.load(Ordering::Relaxed);
+            thread.vruntime.store(cmp::max(vruntime, floor), Ordering::Relaxed);
+        }
+        self.load += thread_weight(&thread);
+        let key = (thread.vruntime.load(Ordering::Relaxed), thread.id());
+        self.queue.insert(key, thread);
+    }
+
+    fn attach(&mut self, thread: &Thread) {
+        // start in line with the other threads, neither ahead nor behind
+        thread.vruntime.store(self.min_vruntime, Ordering::Relaxed);
+    }
+
+    fn remove(&mut self, thread: &Thread) -> Option<Arc<Thread>> {
+        let key = (thread.vruntime.load(Ordering::Relaxed), thread.id());
+        let thread = self.queue.remove(&key)?;
+        self.load -= thread_weight(&thread);
+        Some(thread)
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Thread>> {
+        let key = *self.queue.keys().next()?;
+        let thread = self.queue.remove(&key)?;
+        self.load -= thread_weight(&thread);
+        self.min_vruntime = cmp::max(self.min_vruntime, key.0);
+        Some(thread)
+    }
+
+    fn steal(&mut self, filter: &mut dyn FnMut(&Thread) -> bool) -> Option<Arc<Thread>> {
+        let key = *self
+            .queue
+            .iter()
+            .rev()
+            .find(|(_, thread)| filter(thread))
+            .map(|(key, _)| key)?;
+        let thread = self.queue.remove(&key)?;
+        self.load -= thread_weight(&thread);
+        Some(thread)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn migrate_out(&mut self, thread: &Thread) {
+        // relative to the minimum, a thread with sleeper credit wraps below zero
+        let vruntime = thread.vruntime.load(Ordering::Relaxed);
+        thread
+            .vruntime
+            .store(vruntime.wrapping_sub(self.min_vruntime), Ordering::Relaxed);
+    }
+
+    fn migrate_in(&mut self, thread: &Thread) {
+        let relative = thread.vruntime.load(Ordering::Relaxed) as i64;
+        let vruntime = cmp::max(self.min_vruntime as i64 + relative, 0) as u64;
+        thread.vruntime.store(vruntime, Ordering::Relaxed);
+    }
+
+    fn charge(&mut self, thread: &Thread, ns: u64) {
+        let delta = ns * NICE_0_WEIGHT / thread_weight(thread);
+        let vruntime = thread.vruntime.fetch_add(delta, Ordering::Relaxed) + delta;
+        self.update_min_vruntime(vruntime);
+    }
+
+    fn tick(&mut self, current: &Thread, ran_ns: u64) -> bool {
+        let leftmost = match self.queue.keys().next() {
+            Some(&(vruntime, _)) => vruntime,
+            None => return false,
+        };
+        if ran_ns >= self.slice(current) {
+            return true;
+        }
+        // far ahead of the most deserving waiter, but never before the granularity
+        ran_ns >= MIN_GRANULARITY_NS
+            && current.vruntime.load(Ordering::Relaxed) > leftmost + self.slice(current)
+    }
+
+    fn should_preempt(&self, current: &Thread, woken: &Thread) -> bool {
+        let current = current.vruntime.load(Ordering::Relaxed);
+        let woken = woken.vruntime.load(Ordering::Relaxed);
+        woken + WAKEUP_GRANULARITY_NS < current
+    }
+}
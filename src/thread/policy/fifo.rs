@@ -0,0 +1,85 @@
+//! Real-time FIFO scheduling.
+//!
+//! The runnable thread with the highest priority runs until it blocks, yields or
+//! a thread with a higher priority becomes runnable. Threads of equal priority run
+//! in the order they became runnable or yielded, a preempted thread goes back to the
+//! front of its priority so it keeps its place.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::{Enqueue, Policy, Scheduler, MAX_RT_PRIORITY};
+use crate::thread::Thread;
+
+/// The real-time FIFO class.
+pub struct Fifo {
+    /// One queue per priority, indexed by priority.
+    queues: Vec<VecDeque<Arc<Thread>>>,
+    count: usize,
+}
+
+impl Fifo {
+    /// Creates an empty class.
+    pub fn new() -> Fifo {
+        Fifo {
+            queues: (0..=MAX_RT_PRIORITY).map(|_| VecDeque::new()).collect(),
+            count: 0,
+        }
+    }
+}
+
+fn priority(thread: &Thread) -> u8 {
+    match thread.policy() {
+        Policy::Fifo(priority) => priority,
+        policy => panic!("{:?} thread in the FIFO class", policy),
+    }
+}
+
+impl Scheduler for Fifo {
+    fn enqueue(&mut self, thread: Arc<Thread>, reason: Enqueue) {
+        let queue = &mut self.queues[usize::from(priority(&thread))];
+        match reason {
+            Enqueue::Preempted => queue.push_front(thread),
+            Enqueue::Wakeup | Enqueue::Yielded => queue.push_back(thread),
+        }
+        self.count += 1;
+    }
+
+    fn remove(&mut self, thread: &Thread) -> Option<Arc<Thread>> {
+        let queue = &mut self.queues[usize::from(priority(thread))];
+        let index = queue.iter().position(|t| t.id() == thread.id())?;
+        self.count -= 1;
+        queue.remove(index)
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Thread>> {
+        let queue = self.queues.iter_mut().rev().find(|q| !q.is_empty())?;
+        self.count -= 1;
+        queue.pop_front()
+    }
+
+    fn steal(&mut self, filter: &mut dyn FnMut(&Thread) -> bool) -> Option<Arc<Thread>> {
+        // the lowest priority waits the longest anyway
+        for queue in self.queues.iter_mut() {
+            if let Some(index) = queue.iter().rposition(|thread| filter(thread)) {
+                self.count -= 1;
+                return queue.remove(index);
+            }
+        }
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.count
+    }
+
+    fn charge(&mut self, _thread: &Thread, _ns: u64) {}
+
+    fn tick(&mut self, _current: &Thread, _ran_ns: u64) -> bool {
+        false
+    }
+
+    fn should_preempt(&self, current: &Thread, woken: &Thread) -> bool {
+        priority(woken) > priority(current)
+    }
+}
@@ -0,0 +1,145 @@
+//! Scheduling policies.
+//!
+//! Every thread belongs to one scheduling class, selected by its `Policy`. A class
+//! implements the `Scheduler` trait and owns the runnable threads of its policy,
+//! each CPU's run queue has its own instance of every class. The classes are
+//! ordered: the run queue picks from the first class that has a runnable thread,
+//! and a runnable thread of a higher class preempts a running thread of a lower
+//! one.
+//!
+//! - `Fifo`: real-time, strictly by priority, first come first served within a
+//!   priority, no time slicing.
+//! - `Fair`: fair share by virtual runtime, weighted by the nice value.
+
+pub mod fair;
+pub mod fifo;
+
+pub use self::fair::Fair;
+pub use self::fifo::Fifo;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::Thread;
+
+/// Real-time priorities, higher runs first.
+pub const MIN_RT_PRIORITY: u8 = 1;
+/// The highest real-time priority.
+pub const MAX_RT_PRIORITY: u8 = 99;
+
+/// The lowest nice value, the largest share of the CPU.
+pub const MIN_NICE: i8 = -20;
+/// The highest nice value, the smallest share of the CPU.
+pub const MAX_NICE: i8 = 19;
+
+/// How a thread is scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Real-time FIFO with a priority from `MIN_RT_PRIORITY` to `MAX_RT_PRIORITY`.
+    Fifo(u8),
+    /// Fair share with a nice value from `MIN_NICE` to `MAX_NICE`.
+    Fair(i8),
+}
+
+impl Policy {
+    /// The policy of new threads.
+    pub const DEFAULT: Policy = Policy::Fair(0);
+
+    /// Index of the policy's class in the run queue, lower indices run first.
+    pub fn class(self) -> usize {
+        match self {
+            Policy::Fifo(_) => 0,
+            Policy::Fair(_) => 1,
+        }
+    }
+
+    /// Returns whether the priority or nice value is in range.
+    pub fn is_valid(self) -> bool {
+        match self {
+            Policy::Fifo(priority) => priority >= MIN_RT_PRIORITY && priority <= MAX_RT_PRIORITY,
+            Policy::Fair(nice) => nice >= MIN_NICE && nice <= MAX_NICE,
+        }
+    }
+
+    pub(super) fn to_bits(self) -> u32 {
+        match self {
+            Policy::Fifo(priority) => u32::from(priority),
+            Policy::Fair(nice) => (1 << 8) | u32::from(nice as u8),
+        }
+    }
+
+    pub(super) fn from_bits(bits: u32) -> Policy {
+        match bits >> 8 {
+            0 => Policy::Fifo(bits as u8),
+            _ => Policy::Fair(bits as u8 as i8),
+        }
+    }
+}
+
+/// Why a thread enters a class's queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enqueue {
+    /// The thread is new, was blocked or sleeping, or moved from another class.
+    Wakeup,
+    /// The thread was running and another thread should run instead.
+    Preempted,
+    /// The thread was running and gave up the CPU itself.
+    Yielded,
+}
+
+/// A scheduling class.
+///
+/// The run queue calls the methods with its lock held and interrupts disabled.
+/// A thread is only queued in the class of its current policy, and only while it is
+/// not running. A thread that moves to another CPU's run queue leaves the old class
+/// instance through `migrate_out` and enters the new one through `migrate_in`.
+pub trait Scheduler: Send {
+    /// Adds a runnable thread.
+    fn enqueue(&mut self, thread: Arc<Thread>, reason: Enqueue);
+
+    /// Prepares a thread that moves into this class from another one, before it is
+    /// enqueued. The thread may be running.
+    fn attach(&mut self, _thread: &Thread) {}
+
+    /// Removes a queued thread, returns `None` if it is not queued here.
+    fn remove(&mut self, thread: &Thread) -> Option<Arc<Thread>>;
+
+    /// Takes the thread that should run next.
+    fn pick_next(&mut self) -> Option<Arc<Thread>>;
+
+    /// Takes a queued thread for `filter` accepts to move to another CPU, preferring
+    /// the one that least needs to run soon.
+    fn steal(&mut self, filter: &mut dyn FnMut(&Thread) -> bool) -> Option<Arc<Thread>>;
+
+    /// Returns whether no thread is queued.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of queued threads.
+    fn len(&self) -> usize;
+
+    /// Converts the class state of a thread that leaves this CPU, and is not queued,
+    /// to a form that does not depend on this instance.
+    fn migrate_out(&mut self, _thread: &Thread) {}
+
+    /// Undoes `migrate_out` for a thread that arrives from another CPU, before it is
+    /// enqueued.
+    fn migrate_in(&mut self, _thread: &Thread) {}
+
+    /// Charges `ns` nanoseconds of CPU time to a running thread of this class.
+    fn charge(&mut self, thread: &Thread, ns: u64);
+
+    /// Called on every tick for the running thread of this class, which has run for
+    /// `ran_ns` since it was picked. Returns whether it should give up the CPU.
+    fn tick(&mut self, current: &Thread, ran_ns: u64) -> bool;
+
+    /// Returns whether the just woken `woken` should preempt `current`, both in this
+    /// class.
+    fn should_preempt(&self, current: &Thread, woken: &Thread) -> bool;
+}
+
+/// Creates the scheduling classes in run queue order.
+pub(super) fn classes() -> Vec<Box<dyn Scheduler>> {
+    vec![Box::new(Fifo::new()), Box::new(Fair::new())]
+}
@@ -0,0 +1,688 @@
+//! Preemptive scheduling over the classes in `policy`.
+//!
+//! Every CPU has its own run queue, split into one queue per scheduling class. A
+//! CPU runs the next thread of the first class that has one. A thread runs until it
+//! blocks, yields or exits, until its class decides on a tick that it had its share,
+//! or until a thread that should preempt it becomes runnable. The tick and `wake`
+//! only mark the CPU with `NEED_RESCHED`, the switch itself happens in
+//! `preempt_on_interrupt_return`, after the interrupt was acknowledged and before
+//! the interrupted thread would resume. A CPU that marks another one sends it
+//! `vectors::RESCHEDULE`, which also wakes it up from `hlt`.
+//!
+//! Which CPU a thread runs on is decided in `balance`. A thread belongs to the run
+//! queue of its `cpu`, which only changes with that run queue locked; code that
+//! locks two run queues locks them in CPU order with `lock_pair`.
+//!
+//! Interrupts are disabled while a CPU switches threads. Kernel code that must not
+//! be moved to another CPU or interleaved with other threads either holds an
+//! `IrqSafeMutex` or brackets the section with `preempt_disable` and
+//! `preempt_enable`.
+//!
+//! To block, a thread sets its own state with `prepare_to_block`, makes itself
+//! known to its waker and calls `schedule`. A `wake` in between is not lost, it
+//! puts the thread back into the run queue and `schedule` returns right away.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use super::balance;
+use super::policy::{self, Enqueue, Policy, Scheduler};
+use super::{State, Thread};
+use crate::apic::{self, DeliveryMode, IpiDestination};
+use crate::interrupts::{self as irq, bottom_half, vectors, IrqReturn};
+use crate::memory::{self, paging};
+use crate::percpu;
+use crate::smp::CpuSet;
+use crate::sync::{IrqSafeMutex, IrqSafeMutexGuard};
+use crate::task;
+use crate::time;
+use crate::watchdog;
+use crate::x86_64::instructions::interrupts;
+
+extern "C" {
+    fn switch_context(save_rsp: *mut u64, next_rsp: u64, prev_running: *const AtomicBool);
+}
+
+pub(super) struct RunQueue {
+    cpu: usize,
+    /// The scheduling classes, indexed by `Policy::class`.
+    classes: Vec<Box<dyn Scheduler>>,
+    /// The thread running on the CPU.
+    current: Option<Arc<Thread>>,
+    /// Dead threads, reaped once no CPU runs on their stack anymore.
+    dead: Vec<Arc<Thread>>,
+}
+
+pub(super) type RunQueueGuard = IrqSafeMutexGuard<'static, RunQueue>;
+
+impl RunQueue {
+    const fn new() -> RunQueue {
+        RunQueue {
+            cpu: 0,
+            classes: Vec::new(),
+            current: None,
+            dead: Vec::new(),
+        }
+    }
+
+    fn class(&mut self, policy: Policy) -> &mut dyn Scheduler {
+        &mut *self.classes[policy.class()]
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.classes.iter().all(|class| class.is_empty())
+    }
+
+    /// Returns whether a class above `policy`'s has a runnable thread.
+    fn has_higher(&self, policy: Policy) -> bool {
+        self.classes[..policy.class()].iter().any(|class| !class.is_empty())
+    }
+
+    pub(super) fn enqueue(&mut self, thread: Arc<Thread>, reason: Enqueue) {
+        debug_assert_eq!(thread.cpu.load(Ordering::Relaxed), self.cpu);
+        thread.queued.store(true, Ordering::Relaxed);
+        self.class(thread.policy()).enqueue(thread, reason);
+        self.update_load();
+    }
+
+    fn remove(&mut self, thread: &Thread) -> Option<Arc<Thread>> {
+        let removed = self.class(thread.policy()).remove(thread)?;
+        removed.queued.store(false, Ordering::Relaxed);
+        self.update_load();
+        Some(removed)
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Thread>> {
+        let next = self.classes.iter_mut().find_map(|class| class.pick_next())?;
+        next.queued.store(false, Ordering::Relaxed);
+        Some(next)
+    }
+
+    /// Takes a queued thread that may move to `cpu`.
+    pub(super) fn steal_for(&mut self, cpu: usize) -> Option<Arc<Thread>> {
+        let mut filter = |thread: &Thread| {
+            // a thread that was just switched out may still be saving its registers
+            thread.affinity().contains(cpu) && !thread.running.load(Ordering::Relaxed)
+        };
+        let thread = self
+            .classes
+            .iter_mut()
+            .find_map(|class| class.steal(&mut filter))?;
+        thread.queued.store(false, Ordering::Relaxed);
+        self.update_load();
+        Some(thread)
+    }
+
+    /// Charges the CPU time since the last accounting to a running thread.
+    fn account(&mut self, thread: &Thread, now: u64) {
+        let start = thread.exec_start.swap(now, Ordering::Relaxed);
+        let delta = now.saturating_sub(start);
+        thread.cpu_time.fetch_add(delta, Ordering::Relaxed);
+        if !thread.is_idle() {
+            self.class(thread.policy()).charge(thread, delta);
+        }
+    }
+
+    fn set_current(&mut self, thread: Arc<Thread>) {
+        self.current = Some(thread);
+        self.update_load();
+    }
+
+    /// Publishes the number of threads queued or running on the CPU.
+    fn update_load(&self) {
+        let queued: usize = self.classes.iter().map(|class| class.len()).sum();
+        let running = match self.current {
+            Some(ref current) if !current.is_idle() => 1,
+            _ => 0,
+        };
+        if let Some(load) = LOAD.get_for(self.cpu) {
+            load.store(queued + running, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns whether `thread`, which just became runnable here, should preempt
+    /// the running thread.
+    fn should_preempt(&self, thread: &Thread) -> bool {
+        let current = match self.current {
+            Some(ref current) => current,
+            None => return false,
+        };
+        if current.is_idle() {
+            return true;
+        }
+        let policy = current.policy();
+        let woken = thread.policy();
+        woken.class() < policy.class()
+            || (woken.class() == policy.class()
+                && self.classes[policy.class()].should_preempt(current, thread))
+    }
+
+    /// Asks the CPU to reschedule if `thread`, which just became runnable here,
+    /// should preempt the running thread.
+    pub(super) fn preempt_if_needed(&self, thread: &Thread) {
+        if self.should_preempt(thread) {
+            resched_cpu(self.cpu);
+        }
+    }
+}
+
+/// Moves a thread that is not queued from the run queue `from` to `to`.
+pub(super) fn migrate(thread: &Thread, from: &mut RunQueue, to: &mut RunQueue) {
+    debug_assert!(!thread.queued.load(Ordering::Relaxed));
+    let policy = thread.policy();
+    from.class(policy).migrate_out(thread);
+    thread.cpu.store(to.cpu, Ordering::Release);
+    to.class(policy).migrate_in(thread);
+}
+
+percpu! {
+    static RUN_QUEUE: IrqSafeMutex<RunQueue> = IrqSafeMutex::new(RunQueue::new());
+    /// Threads queued or running on the CPU, readable without the run queue lock.
+    static LOAD: AtomicUsize = AtomicUsize::new(0);
+    static CURRENT: RefCell<Option<Arc<Thread>>> = RefCell::new(None);
+    static IDLE: RefCell<Option<Arc<Thread>>> = RefCell::new(None);
+    static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+    static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// The CPUs whose run queue is set up.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Installs the reschedule IPI handler, called once on the bootstrap processor.
+pub(super) fn init() {
+    irq::register_irq_with(vectors::RESCHEDULE, |_| {
+        NEED_RESCHED.get().store(true, Ordering::Relaxed);
+        IrqReturn::Handled
+    })
+    .expect("could not register the reschedule IPI handler");
+}
+
+/// Sets up the run queue of the executing CPU and makes `idle` its idle and
+/// current thread.
+pub(super) fn init_cpu(idle: Arc<Thread>) {
+    let cpu = percpu::cpu_index();
+    idle.exec_start.store(time::monotonic_ns(), Ordering::Relaxed);
+    {
+        let mut queue = RUN_QUEUE.get().lock();
+        queue.cpu = cpu;
+        queue.classes = policy::classes();
+        queue.set_current(idle.clone());
+    }
+    IDLE.with(|slot| *slot.borrow_mut() = Some(idle.clone()));
+    CURRENT.with(|slot| *slot.borrow_mut() = Some(idle));
+    ONLINE.fetch_or(1 << cpu, Ordering::Release);
+}
+
+/// The CPUs threads can be scheduled on.
+pub fn online_cpus() -> CpuSet {
+    CpuSet::from_bits(ONLINE.load(Ordering::Acquire))
+}
+
+/// The number of threads queued or running on `cpu`, idle threads not counted.
+pub fn load(cpu: usize) -> usize {
+    LOAD.get_for(cpu)
+        .map(|load| load.load(Ordering::Relaxed))
+        .unwrap_or(0)
+}
+
+/// Returns the thread running on the executing CPU, `None` before `thread::init`.
+pub(super) fn try_current() -> Option<Arc<Thread>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Locks the run queue of an online CPU.
+pub(super) fn lock_cpu(cpu: usize) -> RunQueueGuard {
+    RUN_QUEUE
+        .get_for(cpu)
+        .expect("CPU without a run queue")
+        .lock()
+}
+
+/// Locks the run queues of two different CPUs, in CPU order, and returns the
+/// guards in argument order.
+pub(super) fn lock_pair(first: usize, second: usize) -> (RunQueueGuard, RunQueueGuard) {
+    assert_ne!(first, second, "locking a run queue twice");
+    if first < second {
+        let first = lock_cpu(first);
+        (first, lock_cpu(second))
+    } else {
+        let second = lock_cpu(second);
+        (lock_cpu(first), second)
+    }
+}
+
+/// Locks the run queue `thread` belongs to.
+fn lock_thread(thread: &Thread) -> RunQueueGuard {
+    loop {
+        let cpu = thread.cpu.load(Ordering::Acquire);
+        let queue = lock_cpu(cpu);
+        if thread.cpu.load(Ordering::Acquire) == cpu {
+            return queue;
+        }
+    }
+}
+
+/// Asks `cpu` to reschedule, with an IPI if it is another CPU.
+fn resched_cpu(cpu: usize) {
+    if cpu == percpu::cpu_index() {
+        NEED_RESCHED.get().store(true, Ordering::Relaxed);
+        return;
+    }
+    let flag = match NEED_RESCHED.get_for(cpu) {
+        Some(flag) => flag,
+        None => return,
+    };
+    // an IPI is already on its way if the flag was set
+    if !flag.swap(true, Ordering::AcqRel) {
+        if let Some(target) = percpu::for_cpu(cpu) {
+            unsafe {
+                apic::local().send_ipi(
+                    vectors::RESCHEDULE,
+                    DeliveryMode::Fixed,
+                    IpiDestination::Physical(target.apic_id()),
+                )
+            };
+        }
+    }
+}
+
+/// Adds a new thread to the run queue of the CPU `balance` picks for it.
+pub(super) fn enqueue_new(thread: Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        let cpu = balance::select_cpu(&thread, percpu::cpu_index());
+        // nothing else knows the thread yet
+        thread.cpu.store(cpu, Ordering::Release);
+        let mut queue = lock_cpu(cpu);
+        queue.enqueue(thread.clone(), Enqueue::Wakeup);
+        queue.preempt_if_needed(&thread);
+    });
+}
+
+/// Makes a blocked or sleeping thread runnable again.
+///
+/// Returns false if the thread was not waiting. Can be called in interrupt context.
+pub fn wake(thread: &Arc<Thread>) -> bool {
+    let waiting = || match thread.state() {
+        State::Blocked | State::Sleeping => true,
+        _ => false,
+    };
+
+    loop {
+        let mut queue = lock_thread(thread);
+        if !waiting() {
+            return false;
+        }
+
+        // a thread still on its CPU's stack stays there, it is picked up by its
+        // own `schedule`
+        let source = queue.cpu;
+        let target = if thread.running.load(Ordering::Acquire) {
+            source
+        } else {
+            balance::select_cpu(thread, source)
+        };
+        if target == source {
+            thread.set_state(State::Runnable);
+            queue.enqueue(thread.clone(), Enqueue::Wakeup);
+            queue.preempt_if_needed(thread);
+            return true;
+        }
+        drop(queue);
+
+        let (mut from, mut to) = lock_pair(source, target);
+        if thread.cpu.load(Ordering::Acquire) != source
+            || !waiting()
+            || thread.running.load(Ordering::Acquire)
+        {
+            continue;
+        }
+        thread.set_state(State::Runnable);
+        migrate(thread, &mut from, &mut to);
+        to.enqueue(thread.clone(), Enqueue::Wakeup);
+        to.preempt_if_needed(thread);
+        return true;
+    }
+}
+
+/// Changes the scheduling policy of a thread.
+///
+/// A queued thread moves to the queue of its new class right away, a running thread
+/// is rescheduled by its CPU's next tick at the latest. Panics if the priority or
+/// nice value is out of range.
+pub fn set_policy(thread: &Arc<Thread>, policy: Policy) {
+    assert!(policy.is_valid(), "invalid scheduling policy {:?}", policy);
+    assert!(!thread.is_idle(), "the idle thread has no policy");
+    let mut queue = lock_thread(thread);
+    let old = thread.policy();
+    if old == policy {
+        return;
+    }
+
+    let queued = queue.remove(thread);
+    thread.policy.store(policy.to_bits(), Ordering::Relaxed);
+    if policy.class() != old.class() {
+        queue.class(policy).attach(thread);
+    }
+    if let Some(thread) = queued {
+        queue.enqueue(thread.clone(), Enqueue::Wakeup);
+        queue.preempt_if_needed(&thread);
+    }
+}
+
+/// Restricts the CPUs a thread may run on.
+///
+/// A queued thread moves to an allowed CPU right away, a running thread once its
+/// CPU reschedules, which it is asked to do. CPUs that are not online are ignored,
+/// a set without an online CPU lets the thread run anywhere.
+pub fn set_affinity(thread: &Arc<Thread>, affinity: CpuSet) {
+    assert!(!affinity.is_empty(), "empty CPU affinity");
+    assert!(!thread.is_idle(), "the idle thread is bound to its CPU");
+
+    loop {
+        let queue = lock_thread(thread);
+        thread.affinity.store(affinity.bits(), Ordering::Relaxed);
+        let source = queue.cpu;
+        if balance::allowed_cpus(thread).contains(source) {
+            return;
+        }
+
+        let is_current = queue
+            .current
+            .as_ref()
+            .map_or(false, |current| Arc::ptr_eq(current, thread));
+        if is_current {
+            // its `schedule` moves it away
+            resched_cpu(source);
+            return;
+        }
+        if !thread.queued.load(Ordering::Relaxed) {
+            // placed by `wake` once it runs again
+            return;
+        }
+
+        let target = balance::select_cpu(thread, source);
+        drop(queue);
+        let (mut from, mut to) = lock_pair(source, target);
+        if thread.cpu.load(Ordering::Acquire) != source {
+            continue;
+        }
+        if let Some(thread) = from.remove(thread) {
+            migrate(&thread, &mut from, &mut to);
+            to.enqueue(thread.clone(), Enqueue::Wakeup);
+            to.preempt_if_needed(&thread);
+        }
+        return;
+    }
+}
+
+/// Sets the state of the calling thread to `Blocked` or `Sleeping`, the thread
+/// keeps running until it calls `schedule`.
+///
+/// Must be called with interrupts disabled, before the thread is made visible to
+/// the code that wakes it.
+pub fn prepare_to_block(state: State) {
+    debug_assert!(!interrupts::are_enabled());
+    assert!(
+        state == State::Blocked || state == State::Sleeping,
+        "can not block in state {:?}",
+        state
+    );
+    let current = super::current();
+    assert!(!current.is_idle(), "the idle thread can not block");
+    current.set_state(state);
+}
+
+/// Gives up the CPU to the next runnable thread, if there is one.
+pub fn yield_now() {
+    debug_assert_eq!(
+        PREEMPT_COUNT.get().load(Ordering::Relaxed),
+        0,
+        "yield with preemption disabled"
+    );
+    interrupts::without_interrupts(schedule);
+    reap_dead();
+}
+
+/// Disables preemption on the executing CPU. Calls nest, each one needs a matching
+/// `preempt_enable`.
+pub fn preempt_disable() {
+    PREEMPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+}
+
+/// Re-enables preemption and switches threads if a reschedule became due meanwhile.
+pub fn preempt_enable() {
+    let count = PREEMPT_COUNT.get();
+    debug_assert!(count.load(Ordering::Relaxed) > 0);
+    if count.fetch_sub(1, Ordering::Relaxed) == 1
+        && interrupts::are_enabled()
+        && NEED_RESCHED.get().load(Ordering::Relaxed)
+    {
+        yield_now();
+    }
+}
+
+/// Returns whether the calling thread may block or sleep.
+pub fn can_block() -> bool {
+    match try_current() {
+        Some(current) => {
+            !current.is_idle()
+                && interrupts::are_enabled()
+                && PREEMPT_COUNT.get().load(Ordering::Relaxed) == 0
+        }
+        None => false,
+    }
+}
+
+/// Accounts the running thread's CPU time and lets its class decide whether it
+/// should be preempted, called from the timer interrupt on every CPU. Every
+/// `balance::BALANCE_INTERVAL_TICKS` it also balances the load.
+pub fn tick() {
+    let current = match try_current() {
+        Some(current) => current,
+        None => return,
+    };
+
+    let now = time::monotonic_ns();
+    let mut queue = RUN_QUEUE.get().lock();
+    queue.account(&current, now);
+
+    let preempt = if current.is_idle() {
+        !queue.is_empty()
+    } else {
+        let policy = current.policy();
+        let ran = now.saturating_sub(current.slice_start.load(Ordering::Relaxed));
+        queue.has_higher(policy) || queue.class(policy).tick(&current, ran)
+    };
+    if preempt {
+        NEED_RESCHED.get().store(true, Ordering::Relaxed);
+    }
+    drop(queue);
+
+    let local = percpu::local();
+    if local.ticks.load(Ordering::Relaxed) % balance::BALANCE_INTERVAL_TICKS == 0 {
+        balance::rebalance(local.cpu());
+    }
+}
+
+/// Switches threads if the tick or a wakeup asked for it, called by the interrupt
+/// dispatcher after a device interrupt has been acknowledged.
+pub fn preempt_on_interrupt_return() {
+    let need_resched = NEED_RESCHED.get();
+    if !need_resched.load(Ordering::Relaxed)
+        || PREEMPT_COUNT.get().load(Ordering::Relaxed) != 0
+        || try_current().is_none()
+    {
+        return;
+    }
+    schedule();
+}
+
+/// Runs the idle loop of the executing CPU: runs every runnable thread, every ready
+/// async task and the bottom halves left over by interrupts, and halts until the
+/// next interrupt when there is nothing to do.
+pub fn idle_loop() -> ! {
+    loop {
+        yield_now();
+        task::run_ready();
+        watchdog::touch();
+
+        unsafe {
+            interrupts::disable();
+            bottom_half::run_pending();
+            let idle = RUN_QUEUE.get().lock().is_empty()
+                && !task::has_ready()
+                && !bottom_half::has_pending();
+            if idle {
+                // `sti` only takes effect after `hlt`, no wakeup can slip in between
+                interrupts::enable_and_halt();
+            } else {
+                interrupts::enable();
+            }
+        }
+    }
+}
+
+/// Switches to the next runnable thread. The calling thread stays runnable unless
+/// it blocked or exited, then the CPU runs its idle thread if nothing else is
+/// runnable. Must be called with interrupts disabled.
+pub fn schedule() {
+    debug_assert!(!interrupts::are_enabled());
+    let preempted = NEED_RESCHED.get().swap(false, Ordering::Relaxed);
+    let prev = super::current();
+    let this = percpu::cpu_index();
+    let now = time::monotonic_ns();
+
+    if prev.state() == State::Running && !prev.is_idle() {
+        if !balance::allowed_cpus(&prev).contains(this) {
+            push_current(&prev, now);
+        }
+    } else {
+        balance::pull_if_idle(this);
+    }
+
+    let next = {
+        let mut queue = RUN_QUEUE.get().lock();
+        // `prev` belongs to another run queue once it was pushed away, or stolen
+        // after an early wakeup
+        let ours = prev.cpu.load(Ordering::Acquire) == this;
+
+        // woken up between `prepare_to_block` and now, unless another CPU already
+        // took it out of the run queue it is treated like a running thread; the
+        // class may only see its virtual runtime change while it is not queued
+        if ours && prev.state() == State::Runnable && queue.remove(&prev).is_some() {
+            prev.set_state(State::Running);
+        }
+        if ours {
+            queue.account(&prev, now);
+        }
+
+        // whether `prev` may keep running if nothing else is runnable
+        let mut can_continue = false;
+        match prev.state() {
+            State::Running if prev.is_idle() => can_continue = true,
+            State::Running => {
+                prev.set_state(State::Runnable);
+                let reason = if preempted { Enqueue::Preempted } else { Enqueue::Yielded };
+                queue.enqueue(prev.clone(), reason);
+            }
+            State::Runnable | State::Blocked | State::Sleeping => (),
+            State::Dead => queue.dead.push(prev.clone()),
+        }
+
+        let next = match queue.pick_next() {
+            Some(next) => next,
+            None if can_continue => {
+                prev.set_state(State::Running);
+                return;
+            }
+            None => IDLE
+                .with(|idle| idle.borrow().clone())
+                .expect("no idle thread on this CPU"),
+        };
+        queue.set_current(next.clone());
+        next
+    };
+
+    if Arc::ptr_eq(&prev, &next) {
+        prev.set_state(State::Running);
+        prev.slice_start.store(now, Ordering::Relaxed);
+        return;
+    }
+
+    // the CPU that ran `next` last may still be saving its registers
+    while next.running.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    next.running.store(true, Ordering::Relaxed);
+    next.set_state(State::Running);
+    next.exec_start.store(now, Ordering::Relaxed);
+    next.slice_start.store(now, Ordering::Relaxed);
+    if prev.is_idle() {
+        prev.set_state(State::Runnable);
+    }
+    watchdog::touch();
+    // interrupts and system calls from user mode enter on the thread's kernel stack
+    if let Some(ref stack) = *next.stack.lock() {
+        percpu::local().set_kernel_stack(stack.top());
+    }
+    // threads of a user program run on its page table, all others on the kernel's
+    paging::activate(next.address_space().unwrap_or_else(memory::kernel_address_space));
+
+    let save_rsp = prev.saved_rsp.get();
+    let next_rsp = unsafe { *next.saved_rsp.get() };
+    let prev_running: *const AtomicBool = &prev.running;
+    CURRENT.with(|current| *current.borrow_mut() = Some(next));
+    // the run queue, the dead list or the idle slot keep `prev` alive until
+    // `switch_context` cleared its running flag
+    drop(prev);
+
+    unsafe { switch_context(save_rsp, next_rsp, prev_running) };
+    // back in `prev`, possibly on another CPU
+}
+
+/// Hands the running thread, whose affinity no longer allows the executing CPU,
+/// to an allowed one. It keeps running until the following switch.
+fn push_current(thread: &Arc<Thread>, now: u64) {
+    let this = percpu::cpu_index();
+    let target = balance::select_cpu(thread, this);
+    if target == this {
+        return;
+    }
+
+    let (mut from, mut to) = lock_pair(this, target);
+    from.account(thread, now);
+    thread.set_state(State::Runnable);
+    migrate(thread, &mut from, &mut to);
+    to.enqueue(thread.clone(), Enqueue::Wakeup);
+    to.preempt_if_needed(thread);
+}
+
+/// Frees the stacks of the dead threads no CPU runs on anymore and drops them.
+///
+/// Takes the memory controller lock, so it only runs in thread context with
+/// interrupts enabled.
+pub(super) fn reap_dead() {
+    if !interrupts::are_enabled() {
+        return;
+    }
+
+    let mut reaped = Vec::new();
+    {
+        let mut queue = RUN_QUEUE.get().lock();
+        let mut index = 0;
+        while index < queue.dead.len() {
+            if queue.dead[index].running.load(Ordering::Acquire) {
+                index += 1;
+            } else {
+                reaped.push(queue.dead.swap_remove(index));
+            }
+        }
+    }
+    for thread in reaped {
+        if let Some(stack) = thread.stack.lock().take() {
+            memory::controller().free_stack(stack);
+        }
+    }
+}
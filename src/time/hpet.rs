@@ -0,0 +1,332 @@
+//! High precision event timer driver.
+//!
+//! The HPET is found through the ACPI `HPET` table. Its main counter runs at a fixed
+//! frequency of at least 10 MHz and serves as a clocksource and as the reference
+//! for calibrating the TSC and the local APIC timers. Each comparator can raise
+//! one-shot or periodic interrupts, which are routed through the I/O APIC.
+//!
+//! A 32-bit main counter wraps after a few minutes. Such counters are extended to
+//! 64 bits in software: every read adds the distance from the previous read to the
+//! last extended value, and the kernel tick reads the counter well within a wrap.
+
+use bit_field::BitField;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use crate::acpi::{self, Hpet as HpetTable};
+use crate::apic::{self, ioapic};
+use crate::memory::MemoryController;
+use crate::sync::IrqSafeMutex;
+use spin::Once;
+
+/// Size of the HPET register block.
+const HPET_WINDOW_SIZE: u64 = 0x400;
+
+// register offsets
+const GENERAL_CAPABILITIES: usize = 0x000;
+const GENERAL_CONFIGURATION: usize = 0x010;
+const GENERAL_INTERRUPT_STATUS: usize = 0x020;
+const MAIN_COUNTER: usize = 0x0F0;
+const TIMER_CONFIGURATION: usize = 0x100;
+const TIMER_COMPARATOR: usize = 0x108;
+const TIMER_STRIDE: usize = 0x20;
+
+// general capabilities
+const CAP_COUNT_SIZE_64: u64 = 1 << 13;
+
+// general configuration
+const CONF_ENABLE: u64 = 1 << 0;
+const CONF_LEGACY_ROUTE: u64 = 1 << 1;
+
+// timer N configuration and capabilities
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_SIZE_64: u64 = 1 << 5;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+/// Femtoseconds per second, the unit of the counter period.
+const FEMTOSECONDS: u64 = 1_000_000_000_000_000;
+/// The specification limits the period to 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// How a comparator fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Once, when the main counter reaches the comparator.
+    OneShot,
+    /// Every period, the comparator advances by itself.
+    Periodic,
+}
+
+/// Errors returned when programming a comparator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// There is no HPET or it was not initialized.
+    NotPresent,
+    /// The comparator does not exist.
+    InvalidTimer(u8),
+    /// The comparator can not run in periodic mode.
+    PeriodicUnsupported(u8),
+    /// None of the GSIs the comparator can drive is served by an I/O APIC.
+    NoRoute(u8),
+}
+
+struct Hpet {
+    base: usize,
+    /// Main counter period in femtoseconds.
+    period_fs: u64,
+    timers: u8,
+    /// Whether the main counter has 64 bits, otherwise it is extended in software.
+    counter_64: bool,
+}
+
+impl Hpet {
+    fn read(&self, offset: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + offset) as *const u64) }
+    }
+
+    unsafe fn write(&self, offset: usize, value: u64) {
+        ptr::write_volatile((self.base + offset) as *mut u64, value)
+    }
+
+    fn timer_offset(timer: u8, register: usize) -> usize {
+        register + TIMER_STRIDE * timer as usize
+    }
+
+    /// Reads the main counter, extended to 64 bits if the hardware counts only 32.
+    fn main_counter(&self) -> u64 {
+        let counter = self.read(MAIN_COUNTER);
+        if self.counter_64 {
+            return counter;
+        }
+
+        let mut last = EXTENDED_COUNTER.load(Ordering::Acquire);
+        loop {
+            let elapsed = (counter as u32).wrapping_sub(last as u32);
+            // another CPU may have stored a later value since the register was read
+            if elapsed > u32::max_value() / 2 {
+                return last;
+            }
+            let extended = last + u64::from(elapsed);
+            match EXTENDED_COUNTER.compare_exchange_weak(
+                last,
+                extended,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return extended,
+                Err(current) => last = current,
+            }
+        }
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+/// The last value of a 32-bit main counter extended to 64 bits.
+static EXTENDED_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// Serializes reprogramming of the comparators, reads need no lock.
+static TIMER_LOCK: IrqSafeMutex<()> = IrqSafeMutex::new(());
+
+/// Maps the HPET described by ACPI, resets the main counter and starts it.
+///
+/// Returns false if the firmware provides no usable HPET.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    let table = match acpi::find::<HpetTable>() {
+        Some(table) => table,
+        None => {
+            println!("HPET: no ACPI table");
+            return false;
+        }
+    };
+    let base = match table.base_address() {
+        Some(base) => base,
+        None => {
+            println!("HPET: registers are not memory mapped");
+            return false;
+        }
+    };
+    memory_controller.identity_map_mmio(base, HPET_WINDOW_SIZE);
+
+    let mut hpet = Hpet {
+        base: base as usize,
+        period_fs: 0,
+        timers: 0,
+        counter_64: false,
+    };
+
+    let capabilities = hpet.read(GENERAL_CAPABILITIES);
+    hpet.period_fs = capabilities.get_bits(32..64);
+    hpet.timers = capabilities.get_bits(8..13) as u8 + 1;
+    hpet.counter_64 = capabilities & CAP_COUNT_SIZE_64 != 0;
+    if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+        println!("HPET: invalid counter period {} fs", hpet.period_fs);
+        return false;
+    }
+
+    unsafe {
+        // stop, use I/O APIC routing instead of the legacy replacement
+        let config = hpet.read(GENERAL_CONFIGURATION) & !(CONF_ENABLE | CONF_LEGACY_ROUTE);
+        hpet.write(GENERAL_CONFIGURATION, config);
+
+        for timer in 0..hpet.timers {
+            let offset = Hpet::timer_offset(timer, TIMER_CONFIGURATION);
+            let timer_config = hpet.read(offset) & !(TIMER_INTERRUPT_ENABLE | TIMER_FSB_ENABLE);
+            hpet.write(offset, timer_config);
+        }
+        hpet.write(GENERAL_INTERRUPT_STATUS, !0);
+
+        hpet.write(MAIN_COUNTER, 0);
+        hpet.write(GENERAL_CONFIGURATION, config | CONF_ENABLE);
+    }
+
+    println!(
+        "HPET at {:#x}: {} Hz, {} comparators, {}-bit counter",
+        base,
+        FEMTOSECONDS / hpet.period_fs,
+        hpet.timers,
+        if hpet.counter_64 { 64 } else { 32 }
+    );
+
+    HPET.call_once(|| hpet);
+    true
+}
+
+/// Returns true once `init` found an HPET.
+pub fn is_available() -> bool {
+    HPET.r#try().is_some()
+}
+
+/// Returns the main counter frequency in Hz, or 0 without an HPET.
+pub fn frequency() -> u64 {
+    HPET.r#try().map(|hpet| FEMTOSECONDS / hpet.period_fs).unwrap_or(0)
+}
+
+/// Returns the number of comparators.
+pub fn timer_count() -> u8 {
+    HPET.r#try().map(|hpet| hpet.timers).unwrap_or(0)
+}
+
+/// Reads the main counter, extended to 64 bits.
+///
+/// Panics if `init` did not find an HPET.
+pub fn counter() -> u64 {
+    hpet().main_counter()
+}
+
+/// Returns the nanoseconds since `init`, or `None` without an HPET.
+pub fn nanoseconds() -> Option<u64> {
+    let hpet = HPET.r#try()?;
+    let counter = hpet.main_counter();
+    Some((u128::from(counter) * u128::from(hpet.period_fs) / 1_000_000) as u64)
+}
+
+/// Called from the kernel tick, reads a 32-bit main counter often enough that its
+/// software extension sees every wrap.
+pub fn tick() {
+    if let Some(hpet) = HPET.r#try() {
+        if !hpet.counter_64 {
+            hpet.main_counter();
+        }
+    }
+}
+
+/// Busy-waits for `duration` on the main counter.
+pub fn hpet_sleep(duration: Duration) {
+    let hpet = hpet();
+    let ticks = duration_to_ticks(hpet, duration);
+    let start = hpet.main_counter();
+    while hpet.main_counter() - start < ticks {}
+}
+
+/// Programs comparator `timer` to raise `vector` on the executing CPU after
+/// `duration`, once or periodically.
+///
+/// The caller registers the interrupt handler for `vector`. The comparator is routed
+/// to the highest GSI it supports that an I/O APIC serves.
+pub fn start_timer(timer: u8, mode: TimerMode, duration: Duration, vector: u8) -> Result<(), HpetError> {
+    let hpet = HPET.r#try().ok_or(HpetError::NotPresent)?;
+    if timer >= hpet.timers {
+        return Err(HpetError::InvalidTimer(timer));
+    }
+
+    let _guard = TIMER_LOCK.lock();
+    let config_offset = Hpet::timer_offset(timer, TIMER_CONFIGURATION);
+    let comparator_offset = Hpet::timer_offset(timer, TIMER_COMPARATOR);
+    let capabilities = hpet.read(config_offset);
+
+    if mode == TimerMode::Periodic && capabilities & TIMER_PERIODIC_CAPABLE == 0 {
+        return Err(HpetError::PeriodicUnsupported(timer));
+    }
+
+    let gsi = (0..32u32)
+        .rev()
+        .filter(|&gsi| capabilities.get_bit(32 + gsi as usize))
+        .find(|&gsi| ioapic::has_gsi(gsi))
+        .ok_or(HpetError::NoRoute(timer))?;
+
+    let ticks = duration_to_ticks(hpet, duration).max(1);
+
+    unsafe {
+        let mut config = capabilities
+            & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_LEVEL_TRIGGERED | TIMER_FSB_ENABLE);
+        config.set_bits(9..14, u64::from(gsi));
+        if capabilities & TIMER_SIZE_64 == 0 {
+            config |= TIMER_32BIT_MODE;
+        }
+        hpet.write(config_offset, config);
+
+        ioapic::route_gsi(
+            gsi,
+            vector,
+            ioapic::TriggerMode::Edge,
+            ioapic::Polarity::ActiveHigh,
+            apic::local().id(),
+        );
+
+        let now = hpet.read(MAIN_COUNTER);
+        match mode {
+            TimerMode::OneShot => {
+                hpet.write(comparator_offset, now.wrapping_add(ticks));
+                hpet.write(config_offset, config | TIMER_INTERRUPT_ENABLE);
+            }
+            TimerMode::Periodic => {
+                // with VALUE_SET the first write sets the comparator, the second the period
+                hpet.write(config_offset, config | TIMER_PERIODIC | TIMER_VALUE_SET);
+                hpet.write(comparator_offset, now.wrapping_add(ticks));
+                hpet.write(comparator_offset, ticks);
+                hpet.write(config_offset, config | TIMER_PERIODIC | TIMER_INTERRUPT_ENABLE);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Disables the interrupt of comparator `timer` and masks its GSI.
+pub fn stop_timer(timer: u8) -> Result<(), HpetError> {
+    let hpet = HPET.r#try().ok_or(HpetError::NotPresent)?;
+    if timer >= hpet.timers {
+        return Err(HpetError::InvalidTimer(timer));
+    }
+
+    let _guard = TIMER_LOCK.lock();
+    let offset = Hpet::timer_offset(timer, TIMER_CONFIGURATION);
+    let config = hpet.read(offset);
+    unsafe { hpet.write(offset, config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC)) };
+
+    if config & TIMER_INTERRUPT_ENABLE != 0 {
+        ioapic::mask_gsi(config.get_bits(9..14) as u32);
+    }
+    Ok(())
+}
+
+fn hpet() -> &'static Hpet {
+    HPET.r#try().expect("HPET not initialized")
+}
+
+fn duration_to_ticks(hpet: &Hpet, duration: Duration) -> u64 {
+    (duration.as_nanos() * 1_000_000 / u128::from(hpet.period_fs)) as u64
+}
@@ -0,0 +1,97 @@
+//! A point on the monotonic clock.
+
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::time::Duration;
+
+/// A measurement of the monotonic clock, comparable and usable with `Duration`.
+///
+/// Instants are nanoseconds since the clock started at boot, they never go
+/// backwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns the current time.
+    pub fn now() -> Instant {
+        Instant(super::monotonic_ns())
+    }
+
+    /// Creates an instant from nanoseconds since boot.
+    pub const fn from_nanos(nanos: u64) -> Instant {
+        Instant(nanos)
+    }
+
+    /// Returns the nanoseconds since boot.
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the time elapsed since `earlier`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Returns the time elapsed since this instant.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns `self + duration`, or `None` on overflow.
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = duration.as_nanos();
+        if nanos > u128::from(u64::max_value()) {
+            return None;
+        }
+        self.0.checked_add(nanos as u64).map(Instant)
+    }
+
+    /// Returns `self + duration`, or the last representable instant on overflow.
+    pub fn saturating_add(&self, duration: Duration) -> Instant {
+        self.checked_add(duration).unwrap_or(Instant(u64::max_value()))
+    }
+
+    /// Returns `self - duration`, or `None` if that is before boot.
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = duration.as_nanos();
+        if nanos > u128::from(u64::max_value()) {
+            return None;
+        }
+        self.0.checked_sub(nanos as u64).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
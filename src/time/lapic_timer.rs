@@ -0,0 +1,226 @@
+//! Local APIC timer driver.
+//!
+//! Every CPU has its own LAPIC timer, so unlike the PIT it can drive per-CPU ticks.
+//! The timer counts down at the bus or crystal clock divided by `DIVIDE`. That rate
+//! is read from CPUID leaf 0x15 when reported and calibrated against the HPET or PIT
+//! otherwise. With TSC-deadline support the timer instead fires when the TSC reaches
+//! the value written to `IA32_TSC_DEADLINE`, which makes it exact to the TSC cycle.
+//!
+//! The kernel tick runs the timer periodically, or as a chain of TSC deadlines that
+//! are re-armed from the interrupt.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use crate::apic::{self, Register};
+use crate::apic::lapic::LVT_MASKED;
+use crate::interrupts::{self, vectors, IrqReturn};
+use crate::x86_64::instructions::{mfence, rdtsc};
+use crate::x86_64::registers::msr::{self, wrmsr};
+use raw_cpuid::CpuId;
+
+/// Divider applied to the timer input clock.
+pub const DIVIDE: u32 = 16;
+const DIVIDE_CONFIG_16: u32 = 0b0011;
+
+/// LVT timer mode, bits 17..19.
+const LVT_MODE_ONE_SHOT: u32 = 0b00 << 17;
+const LVT_MODE_PERIODIC: u32 = 0b01 << 17;
+const LVT_MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+
+const CALIBRATION_PERIOD: Duration = Duration::from_millis(10);
+
+/// How the timer is programmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Counts down once and stops.
+    OneShot,
+    /// Counts down and reloads the initial count.
+    Periodic,
+    /// Fires once when the TSC reaches `IA32_TSC_DEADLINE`.
+    TscDeadline,
+}
+
+/// Timer ticks per second after the divider, 0 until `init`.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static HAS_TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+/// TSC cycles between two kernel ticks in deadline mode.
+static TICK_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+percpu! {
+    /// The TSC value of the CPU's next tick, 0 when it does not run a deadline tick.
+    static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Determines the timer frequency on the bootstrap processor and installs the
+/// interrupt handler. The timer itself stays stopped.
+///
+/// Requires the local APIC and, for the calibration, the HPET or the PIT.
+pub fn init() -> bool {
+    if !apic::is_initialized() {
+        return false;
+    }
+    let cpuid = CpuId::new();
+
+    let hz = match cpuid
+        .get_tsc_info()
+        .map(|t| u64::from(t.nominal_frequency()))
+        .filter(|&hz| hz != 0)
+    {
+        // the timer runs on the core crystal clock when the CPU reports it
+        Some(crystal) => crystal / u64::from(DIVIDE),
+        None if super::hpet::is_available() => calibrate(super::hpet::hpet_sleep),
+        None => calibrate(super::pit::pit_sleep),
+    };
+    if hz == 0 {
+        println!("LAPIC timer: calibration failed");
+        return false;
+    }
+    FREQUENCY.store(hz, Ordering::SeqCst);
+
+    let tsc_deadline = super::tsc::frequency() != 0
+        && cpuid
+            .get_feature_info()
+            .map(|f| f.has_tsc_deadline())
+            .unwrap_or(false);
+    HAS_TSC_DEADLINE.store(tsc_deadline, Ordering::SeqCst);
+
+    interrupts::register_irq_with(vectors::LAPIC_TIMER, |context| {
+        rearm_tick();
+        super::tick();
+        crate::watchdog::timer_tick(context);
+        IrqReturn::Handled
+    })
+    .expect("failed to register the LAPIC timer interrupt");
+
+    println!(
+        "LAPIC timer: {} Hz (divide by {}){}",
+        hz,
+        DIVIDE,
+        if tsc_deadline { ", TSC-deadline" } else { "" }
+    );
+    true
+}
+
+/// Returns the timer frequency after the divider, or 0 before `init`.
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Returns whether the TSC-deadline mode can be used.
+pub fn has_tsc_deadline() -> bool {
+    HAS_TSC_DEADLINE.load(Ordering::Relaxed)
+}
+
+/// Measures the timer against `sleep`, a busy-wait on a reference clock.
+pub fn calibrate<F: Fn(Duration)>(sleep: F) -> u64 {
+    let lapic = apic::local();
+    let elapsed = unsafe {
+        lapic.write(Register::TimerDivide, DIVIDE_CONFIG_16);
+        lapic.write(Register::LvtTimer, LVT_MASKED | LVT_MODE_ONE_SHOT);
+        lapic.write(Register::TimerInitialCount, u32::max_value());
+
+        sleep(CALIBRATION_PERIOD);
+
+        let remaining = lapic.read(Register::TimerCurrentCount);
+        lapic.write(Register::TimerInitialCount, 0);
+        u32::max_value() - remaining
+    };
+    (u128::from(elapsed) * 1_000_000_000 / CALIBRATION_PERIOD.as_nanos()) as u64
+}
+
+/// Starts the kernel tick on the executing CPU at `hz`.
+///
+/// Uses TSC deadlines if available and the periodic mode otherwise.
+pub fn start_tick(hz: u32) {
+    if has_tsc_deadline() {
+        let cycles = super::tsc::frequency() / u64::from(hz);
+        TICK_CYCLES.store(cycles, Ordering::SeqCst);
+        let deadline = rdtsc() + cycles;
+        NEXT_DEADLINE.get().store(deadline, Ordering::Relaxed);
+        arm(TimerMode::TscDeadline, deadline);
+    } else {
+        arm(TimerMode::Periodic, frequency() / u64::from(hz));
+    }
+}
+
+/// Fires the timer interrupt once after `duration` on the executing CPU, replacing
+/// whatever the timer was doing.
+pub fn oneshot(duration: Duration) {
+    NEXT_DEADLINE.get().store(0, Ordering::Relaxed);
+    if has_tsc_deadline() {
+        arm(TimerMode::TscDeadline, rdtsc() + super::tsc::duration_to_cycles(duration));
+    } else {
+        let count = duration.as_nanos() * u128::from(frequency()) / 1_000_000_000;
+        arm(TimerMode::OneShot, count as u64);
+    }
+}
+
+/// Programs the timer of the executing CPU to raise `vectors::LAPIC_TIMER`.
+///
+/// `value` is the initial count for `OneShot` and `Periodic`, clamped to 32 bits,
+/// and the absolute TSC deadline for `TscDeadline`.
+pub fn arm(mode: TimerMode, value: u64) {
+    let lapic = apic::local();
+    let vector = u32::from(vectors::LAPIC_TIMER);
+
+    unsafe {
+        match mode {
+            TimerMode::OneShot | TimerMode::Periodic => {
+                let lvt_mode = if mode == TimerMode::Periodic {
+                    LVT_MODE_PERIODIC
+                } else {
+                    LVT_MODE_ONE_SHOT
+                };
+                lapic.write(Register::TimerDivide, DIVIDE_CONFIG_16);
+                lapic.write(Register::LvtTimer, lvt_mode | vector);
+                lapic.write(
+                    Register::TimerInitialCount,
+                    value.max(1).min(u64::from(u32::max_value())) as u32,
+                );
+            }
+            TimerMode::TscDeadline => {
+                assert!(has_tsc_deadline(), "TSC-deadline mode not supported");
+                lapic.write(Register::LvtTimer, LVT_MODE_TSC_DEADLINE | vector);
+                // the LVT write has to be ordered before the first deadline write
+                mfence();
+                wrmsr(msr::IA32_TSC_DEADLINE, value.max(1));
+            }
+        }
+    }
+}
+
+/// Stops the timer of the executing CPU.
+pub fn stop() {
+    NEXT_DEADLINE.get().store(0, Ordering::Relaxed);
+    let lapic = apic::local();
+    unsafe {
+        if has_tsc_deadline() {
+            wrmsr(msr::IA32_TSC_DEADLINE, 0);
+        }
+        lapic.write(Register::LvtTimer, LVT_MASKED);
+        lapic.write(Register::TimerInitialCount, 0);
+    }
+}
+
+/// Schedules the next tick in deadline mode, the periodic mode reloads by itself.
+fn rearm_tick() {
+    let cycles = TICK_CYCLES.load(Ordering::Relaxed);
+    if cycles == 0 {
+        return;
+    }
+    let next = NEXT_DEADLINE.get();
+    let previous = next.load(Ordering::Relaxed);
+    if previous == 0 {
+        return;
+    }
+    // advance from the previous deadline so the tick does not drift, but skip
+    // ticks that were missed entirely
+    let now = rdtsc();
+    let mut deadline = previous + cycles;
+    if deadline <= now {
+        deadline = now + cycles;
+    }
+    next.store(deadline, Ordering::Relaxed);
+    unsafe { wrmsr(msr::IA32_TSC_DEADLINE, deadline) };
+}
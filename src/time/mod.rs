@@ -0,0 +1,105 @@
+//! Time keeping.
+//!
+//! The kernel tick is a periodic interrupt counted in a monotonic, atomic tick
+//! counter. It is driven by the local APIC timer, or by channel 0 of the PIT if the
+//! LAPIC timer can not be used.
+//!
+//! `monotonic_ns()` is the kernel wide clock. It reads the calibrated TSC and falls
+//! back to the HPET main counter, then to the tick counter on machines without them.
+//! `Instant` and `Duration` build on it.
+//!
+//! `realtime()` is the wall-clock time, read from the CMOS RTC at boot and advanced
+//! with the monotonic clock.
+//!
+//! Timers registered with `add_timer` run from the kernel tick, `sleep` parks the
+//! caller until a deadline passed.
+
+pub mod hpet;
+pub mod instant;
+pub mod lapic_timer;
+pub mod pit;
+pub mod rtc;
+pub mod timer;
+pub mod tsc;
+
+pub use self::instant::Instant;
+pub use self::pit::pit_sleep;
+pub use self::rtc::{realtime, DateTime};
+pub use self::timer::{add_periodic_timer, add_timer, cancel_timer, sleep, TimerId};
+pub use core::time::Duration;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::memory::MemoryController;
+
+/// Frequency of the kernel tick.
+pub const TICK_HZ: u32 = 100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the kernel tick and the monotonic clock. Interrupts have to be enabled
+/// for the tick to advance.
+pub fn init(memory_controller: &mut MemoryController) {
+    hpet::init(memory_controller);
+    tsc::init();
+    rtc::init();
+
+    if lapic_timer::init() {
+        lapic_timer::start_tick(TICK_HZ);
+    } else {
+        pit::init(TICK_HZ);
+    }
+}
+
+/// Returns the number of ticks since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the nanoseconds since boot on the monotonic clock.
+pub fn monotonic_ns() -> u64 {
+    tsc::nanoseconds()
+        .or_else(hpet::nanoseconds)
+        .unwrap_or_else(|| ticks() * (1_000_000_000 / u64::from(TICK_HZ)))
+}
+
+/// Busy-waits for `duration` without relying on interrupts.
+///
+/// Spins on the TSC once it is calibrated, and on the HPET or PIT before that.
+pub fn delay(duration: Duration) {
+    if tsc::frequency() != 0 {
+        let end = monotonic_ns() + duration.as_nanos() as u64;
+        while monotonic_ns() < end {
+            core::hint::spin_loop();
+        }
+    } else if hpet::is_available() {
+        hpet::hpet_sleep(duration);
+    } else {
+        pit::pit_sleep(duration);
+    }
+}
+
+/// Starts the kernel tick on an application processor, if the LAPIC timer drives it.
+///
+/// Only the bootstrap processor advances the tick counter and runs timers, the
+/// tick of the other CPUs drives their scheduler.
+pub fn init_ap() {
+    if lapic_timer::frequency() != 0 {
+        lapic_timer::start_tick(TICK_HZ);
+    }
+}
+
+/// Called from the timer interrupt on every CPU that runs the tick. Advances the tick
+/// counter, keeps the HPET counter extension current and runs expired timers on the
+/// bootstrap processor, polls the CPU's machine-check banks and charges the tick to
+/// the running thread.
+fn tick() {
+    let local = crate::percpu::local();
+    let ticks = local.ticks.fetch_add(1, Ordering::Relaxed) + 1;
+    if local.cpu() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        hpet::tick();
+        timer::run_expired();
+    }
+    crate::mce::tick(ticks);
+    crate::thread::scheduler::tick();
+}
@@ -0,0 +1,111 @@
+//! Driver for the 8253/8254 programmable interval timer.
+//!
+//! Channel 0 runs as a periodic rate generator on IRQ 0 and drives the kernel tick.
+//! Channel 2, whose gate is controlled through port 0x61, is used for busy-wait
+//! delays during early calibration of the other clocks.
+
+use core::time::Duration;
+use crate::interrupts::{self, IrqReturn};
+use crate::sync::IrqSafeMutex;
+use crate::x86_64::instructions::port::Port;
+
+/// The input clock of all PIT channels.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// The legacy IRQ line of channel 0.
+pub const PIT_IRQ: u8 = 0;
+
+// command register: channel (bits 6..8), access mode (4..6), operating mode (1..4)
+const CMD_CHANNEL0: u8 = 0b00 << 6;
+const CMD_CHANNEL2: u8 = 0b10 << 6;
+const CMD_ACCESS_LOHI: u8 = 0b11 << 4;
+const CMD_MODE_INTERRUPT_ON_TERMINAL_COUNT: u8 = 0b000 << 1;
+const CMD_MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+// port 0x61
+const GATE2_ENABLE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const OUT2_STATUS: u8 = 1 << 5;
+
+struct Pit {
+    channel0: Port<u8>,
+    channel2: Port<u8>,
+    command: Port<u8>,
+    gate: Port<u8>,
+}
+
+static PIT: IrqSafeMutex<Pit> = IrqSafeMutex::new(Pit {
+    channel0: Port::new(0x40),
+    channel2: Port::new(0x42),
+    command: Port::new(0x43),
+    gate: Port::new(0x61),
+});
+
+/// Returns the reload value for the requested frequency, clamped to the 16-bit counter.
+fn divisor(hz: u32) -> u16 {
+    let divisor = PIT_FREQUENCY / u64::from(hz.max(1));
+    divisor.max(1).min(u64::from(u16::max_value())) as u16
+}
+
+/// Programs channel 0 to interrupt at `hz` and routes IRQ 0 to the kernel tick.
+pub fn init(hz: u32) {
+    let divisor = divisor(hz);
+
+    unsafe {
+        let mut pit = PIT.lock();
+        pit.command
+            .write(CMD_CHANNEL0 | CMD_ACCESS_LOHI | CMD_MODE_RATE_GENERATOR);
+        pit.channel0.write(divisor as u8);
+        pit.channel0.write((divisor >> 8) as u8);
+    }
+
+    interrupts::register_irq_with(interrupts::isa_vector(PIT_IRQ), |_| {
+        super::tick();
+        IrqReturn::Handled
+    })
+    .expect("failed to register the PIT interrupt");
+    interrupts::enable_isa_irq(PIT_IRQ);
+
+    println!(
+        "PIT channel 0 at {} Hz (divisor {})",
+        PIT_FREQUENCY / u64::from(divisor),
+        divisor
+    );
+}
+
+/// Busy-waits for `duration` using channel 2, without relying on interrupts.
+///
+/// Intended for calibrating other clocks during boot; the resolution is one PIT
+/// cycle (~838 ns).
+pub fn pit_sleep(duration: Duration) {
+    let mut remaining = duration.as_nanos() as u64 * PIT_FREQUENCY / 1_000_000_000;
+
+    while remaining > 0 {
+        let count = remaining.min(u64::from(u16::max_value()));
+        wait_cycles(count as u16);
+        remaining -= count;
+    }
+}
+
+/// Counts `cycles` down on channel 2 in one-shot mode and spins until OUT2 goes high.
+fn wait_cycles(cycles: u16) {
+    unsafe {
+        let mut pit = PIT.lock();
+
+        // gate low while programming, speaker off
+        let gate = pit.gate.read() & !(GATE2_ENABLE | SPEAKER_ENABLE);
+        pit.gate.write(gate);
+
+        pit.command
+            .write(CMD_CHANNEL2 | CMD_ACCESS_LOHI | CMD_MODE_INTERRUPT_ON_TERMINAL_COUNT);
+        pit.channel2.write(cycles as u8);
+        pit.channel2.write((cycles >> 8) as u8);
+
+        // a rising gate edge starts the count
+        pit.gate.write(gate | GATE2_ENABLE);
+
+        while pit.gate.read() & OUT2_STATUS == 0 {}
+
+        pit.gate.write(gate);
+    }
+}
@@ -0,0 +1,363 @@
+//! CMOS real-time clock driver.
+//!
+//! The RTC keeps the wall-clock date and time across reboots. It is read once at
+//! boot and `realtime()` then advances the boot time with the monotonic clock, so
+//! reading the time never touches the slow CMOS ports.
+//!
+//! The RTC can also raise IRQ 8 periodically (a power of two rate up to 8 kHz) or
+//! when the alarm time is reached.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use crate::acpi::{self, fadt, Fadt};
+use crate::interrupts::{self, IrqReturn};
+use crate::sync::IrqSafeMutex;
+use crate::x86_64::instructions::port::Port;
+
+/// The legacy IRQ line of the RTC.
+pub const RTC_IRQ: u8 = 8;
+
+// CMOS registers
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+/// Writing an index with bit 7 set masks the NMI.
+const NMI_DISABLE: u8 = 1 << 7;
+
+// status register A
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE_MASK: u8 = 0x0F;
+
+// status register B
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_ALARM_INTERRUPT: u8 = 1 << 5;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+
+// status register C, reading it acknowledges the interrupt
+const STATUS_C_ALARM: u8 = 1 << 5;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+
+/// The hour bit marking PM in 12 hour mode.
+const HOUR_PM: u8 = 1 << 7;
+
+/// The periodic interrupt runs at `32768 >> (rate - 1)` Hz, rates below 3 do not work.
+const MIN_RATE: u8 = 3;
+const MAX_RATE: u8 = 15;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A calendar date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC.
+    pub fn to_unix_timestamp(&self) -> u64 {
+        // days from civil, with years starting in March so leap days come last
+        let year = i64::from(self.year) - if self.month <= 2 { 1 } else { 0 };
+        let era = if year >= 0 { year } else { year - 399 } / 400;
+        let year_of_era = year - era * 400;
+        let month = i64::from(self.month);
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days as u64 * SECONDS_PER_DAY
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+
+    /// The date and time `timestamp` seconds after 1970-01-01 00:00:00 UTC.
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = (timestamp / SECONDS_PER_DAY) as i64 + 719_468;
+        let seconds = timestamp % SECONDS_PER_DAY;
+
+        let era = days / 146_097;
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    unsafe fn read(&mut self, reg: u8) -> u8 {
+        self.index.write(NMI_DISABLE | reg);
+        let value = self.data.read();
+        self.index.write(0);
+        value
+    }
+
+    unsafe fn write(&mut self, reg: u8, value: u8) {
+        self.index.write(NMI_DISABLE | reg);
+        self.data.write(value);
+        self.index.write(0);
+    }
+
+    unsafe fn update_in_progress(&mut self) -> bool {
+        self.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+
+    /// Reads the raw time registers, waiting for an update to finish first.
+    unsafe fn read_raw(&mut self, century_register: Option<u8>) -> [u8; 7] {
+        while self.update_in_progress() {}
+        [
+            self.read(REG_SECONDS),
+            self.read(REG_MINUTES),
+            self.read(REG_HOURS),
+            self.read(REG_DAY),
+            self.read(REG_MONTH),
+            self.read(REG_YEAR),
+            century_register.map(|reg| self.read(reg)).unwrap_or(0),
+        ]
+    }
+}
+
+static CMOS: IrqSafeMutex<Cmos> = IrqSafeMutex::new(Cmos {
+    index: Port::new(0x70),
+    data: Port::new(0x71),
+});
+
+/// CMOS index of the century register from the FADT, 0 if there is none.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+/// Wall-clock time at `BOOT_MONOTONIC_NS`, in nanoseconds since the Unix epoch.
+static BOOT_REALTIME_NS: AtomicU64 = AtomicU64::new(0);
+static BOOT_MONOTONIC_NS: AtomicU64 = AtomicU64::new(0);
+
+static PERIODIC_COUNT: AtomicU64 = AtomicU64::new(0);
+static ALARM_CALLBACK: IrqSafeMutex<Option<fn()>> = IrqSafeMutex::new(None);
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+/// Reads the RTC, pins `realtime()` to it and installs the IRQ 8 handler.
+///
+/// Requires the monotonic clock to be running.
+pub fn init() {
+    let fadt = acpi::find::<Fadt>();
+    if let Some(fadt) = fadt {
+        if fadt.iapc_boot_arch() & fadt::BOOT_ARCH_CMOS_RTC_NOT_PRESENT != 0 {
+            println!("RTC: not present according to ACPI");
+            return;
+        }
+        CENTURY_REGISTER.store(fadt.century_register().unwrap_or(0), Ordering::SeqCst);
+    }
+
+    let now = read();
+    BOOT_MONOTONIC_NS.store(super::monotonic_ns(), Ordering::SeqCst);
+    BOOT_REALTIME_NS.store(now.to_unix_timestamp() * 1_000_000_000, Ordering::SeqCst);
+
+    interrupts::register_irq_with(interrupts::isa_vector(RTC_IRQ), |_| {
+        handle_interrupt();
+        IrqReturn::Handled
+    })
+    .expect("failed to register the RTC interrupt");
+
+    println!("RTC: {}", now);
+}
+
+/// Reads the current date and time from the CMOS.
+///
+/// The registers are read until two consecutive reads agree, so an update in the
+/// middle of the read can not produce a torn value.
+pub fn read() -> DateTime {
+    let century_register = match CENTURY_REGISTER.load(Ordering::Relaxed) {
+        0 => None,
+        reg => Some(reg),
+    };
+
+    let (raw, status_b) = unsafe {
+        let mut cmos = CMOS.lock();
+        let mut raw = cmos.read_raw(century_register);
+        loop {
+            let again = cmos.read_raw(century_register);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(REG_STATUS_B))
+    };
+
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year, mut century] = raw;
+    let pm = hour & HOUR_PM != 0;
+    hour &= !HOUR_PM;
+
+    if status_b & STATUS_B_BINARY == 0 {
+        second = from_bcd(second);
+        minute = from_bcd(minute);
+        hour = from_bcd(hour);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+        century = from_bcd(century);
+    }
+
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let full_year = if century_register.is_some() && century != 0 {
+        u16::from(century) * 100 + u16::from(year)
+    } else {
+        2000 + u16::from(year)
+    };
+
+    DateTime {
+        year: full_year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+/// Returns the wall-clock time as the duration since the Unix epoch.
+pub fn realtime() -> Duration {
+    let elapsed = super::monotonic_ns().saturating_sub(BOOT_MONOTONIC_NS.load(Ordering::Relaxed));
+    Duration::from_nanos(BOOT_REALTIME_NS.load(Ordering::Relaxed) + elapsed)
+}
+
+/// Starts the periodic interrupt at `32768 >> (rate - 1)` Hz, rate 3 (8192 Hz) to
+/// 15 (2 Hz).
+pub fn enable_periodic(rate: u8) {
+    let rate = rate.max(MIN_RATE).min(MAX_RATE);
+    unsafe {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(REG_STATUS_A) & !STATUS_A_RATE_MASK;
+        cmos.write(REG_STATUS_A, status_a | rate);
+        let status_b = cmos.read(REG_STATUS_B);
+        cmos.write(REG_STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+        cmos.read(REG_STATUS_C);
+    }
+    interrupts::enable_isa_irq(RTC_IRQ);
+}
+
+/// Stops the periodic interrupt.
+pub fn disable_periodic() {
+    update_status_b(|status_b| status_b & !STATUS_B_PERIODIC_INTERRUPT);
+}
+
+/// Returns the number of periodic interrupts seen so far.
+pub fn periodic_count() -> u64 {
+    PERIODIC_COUNT.load(Ordering::Relaxed)
+}
+
+/// Calls `callback` from interrupt context once the RTC reaches `hour:minute:second`
+/// (UTC) of any day. Replaces a previously set alarm.
+pub fn set_alarm(hour: u8, minute: u8, second: u8, callback: fn()) {
+    *ALARM_CALLBACK.lock() = Some(callback);
+
+    unsafe {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(REG_STATUS_B);
+        let encode = |value: u8| {
+            if status_b & STATUS_B_BINARY == 0 {
+                to_bcd(value)
+            } else {
+                value
+            }
+        };
+        let hour = if status_b & STATUS_B_24_HOUR == 0 {
+            // 12 hour mode: 1-12 with the PM flag
+            let pm = hour >= 12;
+            let hour12 = if hour % 12 == 0 { 12 } else { hour % 12 };
+            encode(hour12) | if pm { HOUR_PM } else { 0 }
+        } else {
+            encode(hour)
+        };
+
+        cmos.write(REG_SECONDS_ALARM, encode(second));
+        cmos.write(REG_MINUTES_ALARM, encode(minute));
+        cmos.write(REG_HOURS_ALARM, hour);
+        cmos.write(REG_STATUS_B, status_b | STATUS_B_ALARM_INTERRUPT);
+        cmos.read(REG_STATUS_C);
+    }
+    interrupts::enable_isa_irq(RTC_IRQ);
+}
+
+/// Cancels the alarm.
+pub fn cancel_alarm() {
+    update_status_b(|status_b| status_b & !STATUS_B_ALARM_INTERRUPT);
+    *ALARM_CALLBACK.lock() = None;
+}
+
+fn update_status_b<F: FnOnce(u8) -> u8>(f: F) {
+    unsafe {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(REG_STATUS_B);
+        cmos.write(REG_STATUS_B, f(status_b));
+    }
+}
+
+fn handle_interrupt() {
+    // the RTC raises no further interrupts until status C has been read
+    let status_c = unsafe { CMOS.lock().read(REG_STATUS_C) };
+
+    if status_c & STATUS_C_PERIODIC != 0 {
+        PERIODIC_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    if status_c & STATUS_C_ALARM != 0 {
+        let callback = *ALARM_CALLBACK.lock();
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+}
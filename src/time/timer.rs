@@ -0,0 +1,186 @@
+//! Kernel timers: deferred one-shot and periodic callbacks.
+//!
+//! Pending timers are kept in a map by id, and their deadlines on the monotonic
+//! clock in a min-heap keyed by `(deadline, id)`, so timers with equal deadlines
+//! fire in the order they were added. Cancelling only removes the timer from the
+//! map; its heap entry is dropped once it reaches the top. The kernel tick calls
+//! `run_expired`, which runs the callbacks of all expired timers in interrupt
+//! context. The resolution is therefore one tick (`1 / TICK_HZ`).
+//!
+//! Callbacks run with interrupts disabled and must not block. They may add and
+//! cancel timers, including their own.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BinaryHeap};
+use core::cmp::Reverse;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use super::Instant;
+use crate::sync::IrqSafeMutex;
+
+/// Identifies a pending timer, needed to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+type Callback = Box<dyn FnMut() + Send>;
+
+struct Timer {
+    id: TimerId,
+    deadline: Instant,
+    period: Option<Duration>,
+    callback: Callback,
+}
+
+struct TimerQueue {
+    /// The pending timers.
+    timers: BTreeMap<TimerId, Timer>,
+    /// Deadline of every pending timer, earliest first, and stale entries of
+    /// cancelled ones.
+    deadlines: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    /// The timer whose callback is running right now.
+    running: Option<TimerId>,
+    /// Set when the running timer was cancelled by its own (or a nested) callback.
+    running_cancelled: bool,
+}
+
+impl TimerQueue {
+    fn insert(&mut self, timer: Timer) {
+        self.deadlines.push(Reverse((timer.deadline, timer.id)));
+        self.timers.insert(timer.id, timer);
+    }
+
+    fn remove(&mut self, id: TimerId) -> bool {
+        if self.timers.remove(&id).is_none() {
+            return false;
+        }
+        // rebuild the heap once cancelled entries make up most of it
+        if self.deadlines.len() > 2 * self.timers.len() + 16 {
+            let timers = &self.timers;
+            self.deadlines = mem::replace(&mut self.deadlines, BinaryHeap::new())
+                .into_vec()
+                .into_iter()
+                .filter(|Reverse((_, id))| timers.contains_key(id))
+                .collect();
+        }
+        true
+    }
+
+    /// Drops cancelled entries from the top of the heap and returns the earliest
+    /// pending deadline.
+    fn next(&mut self) -> Option<(Instant, TimerId)> {
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if self.timers.contains_key(&id) {
+                return Some((deadline, id));
+            }
+            self.deadlines.pop();
+        }
+        None
+    }
+
+    fn pop_expired(&mut self, now: Instant) -> Option<Timer> {
+        match self.next() {
+            Some((deadline, id)) if deadline <= now => {
+                self.deadlines.pop();
+                self.timers.remove(&id)
+            }
+            _ => None,
+        }
+    }
+}
+
+lazy_static! {
+    static ref TIMERS: IrqSafeMutex<TimerQueue> = IrqSafeMutex::new(TimerQueue {
+        timers: BTreeMap::new(),
+        deadlines: BinaryHeap::new(),
+        running: None,
+        running_cancelled: false,
+    });
+}
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Calls `callback` once, `delay` from now.
+pub fn add_timer<F: FnMut() + Send + 'static>(delay: Duration, callback: F) -> TimerId {
+    add(Instant::now().saturating_add(delay), None, Box::new(callback))
+}
+
+/// Calls `callback` every `period`, starting one period from now.
+pub fn add_periodic_timer<F: FnMut() + Send + 'static>(period: Duration, callback: F) -> TimerId {
+    assert!(period > Duration::from_nanos(0), "periodic timer with a zero period");
+    add(Instant::now().saturating_add(period), Some(period), Box::new(callback))
+}
+
+/// Removes a pending timer. Returns false if it already expired (one-shot) or was
+/// cancelled before.
+///
+/// A timer cancelled from within its own callback does not run again.
+pub fn cancel_timer(id: TimerId) -> bool {
+    let mut queue = TIMERS.lock();
+    if queue.remove(id) {
+        return true;
+    }
+    if queue.running == Some(id) && !queue.running_cancelled {
+        queue.running_cancelled = true;
+        return true;
+    }
+    false
+}
+
+/// Returns the deadline of the next pending timer.
+pub fn next_deadline() -> Option<Instant> {
+    TIMERS.lock().next().map(|(deadline, _)| deadline)
+}
+
+/// Runs the callbacks of every expired timer, called from the kernel tick.
+pub fn run_expired() {
+    let now = Instant::now();
+
+    loop {
+        let mut timer = {
+            let mut queue = TIMERS.lock();
+            match queue.pop_expired(now) {
+                Some(timer) => {
+                    queue.running = Some(timer.id);
+                    queue.running_cancelled = false;
+                    timer
+                }
+                None => return,
+            }
+        };
+
+        // the lock is not held so the callback can manage timers itself
+        (timer.callback)();
+
+        let mut queue = TIMERS.lock();
+        let cancelled = queue.running_cancelled;
+        queue.running = None;
+
+        if let (Some(period), false) = (timer.period, cancelled) {
+            // keep the cadence, but skip periods that were missed entirely
+            timer.deadline += period;
+            if timer.deadline <= now {
+                timer.deadline = now + period;
+            }
+            queue.insert(timer);
+        }
+    }
+}
+
+/// Parks the caller for at least `duration`.
+///
+/// Threads sleep until a timer wakes them, other code halts the CPU between ticks.
+/// Interrupts have to be enabled either way.
+pub fn sleep(duration: Duration) {
+    crate::thread::sleep(duration);
+}
+
+fn add(deadline: Instant, period: Option<Duration>, callback: Callback) -> TimerId {
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    TIMERS.lock().insert(Timer {
+        id,
+        deadline,
+        period,
+        callback,
+    });
+    id
+}
@@ -0,0 +1,232 @@
+//! Time stamp counter clocksource.
+//!
+//! The TSC frequency is taken from CPUID when the CPU reports it, and measured
+//! against the HPET or the PIT otherwise. Counter values are converted to nanoseconds with a
+//! fixed point multiplier so reading the clock never divides.
+//!
+//! The conversion is relative to an origin, a counter value and the nanoseconds it
+//! stands for. `set_frequency` moves the origin to the present, so a new frequency
+//! only changes how fast the clock advances from then on and it never goes
+//! backwards. Two copies of the conversion are kept: while one is updated readers
+//! use the other, so the clock can be read in any context, NMIs included, without
+//! waiting for a writer.
+
+use core::sync::atomic::{self, AtomicU64, Ordering};
+use core::time::Duration;
+use crate::x86_64::instructions::rdtsc;
+use crate::x86_64::registers::msr::{self, rdmsr};
+use crate::x86_64::instructions::interrupts;
+use raw_cpuid::CpuId;
+use spin::Mutex;
+
+/// Length of one calibration run against the reference clock.
+const CALIBRATION_PERIOD: Duration = Duration::from_millis(50);
+const CALIBRATION_RUNS: usize = 3;
+
+/// Fractional bits of `Conversion::multiplier`.
+const SHIFT: u32 = 32;
+
+/// Where the TSC frequency came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrequencySource {
+    /// CPUID leaf 0x15, crystal clock times the TSC/crystal ratio.
+    CpuidTscLeaf,
+    /// CPUID leaf 0x16, the processor base frequency.
+    CpuidFrequencyLeaf,
+    /// The maximum non-turbo ratio in `MSR_PLATFORM_INFO` times the 100 MHz bus clock.
+    PlatformInfo,
+    /// Measured against PIT channel 2.
+    PitCalibration,
+    /// Measured against the HPET main counter.
+    HpetCalibration,
+}
+
+/// `ns = origin_ns + ((tsc - origin_tsc) * multiplier >> SHIFT)`.
+struct Conversion {
+    origin_tsc: AtomicU64,
+    origin_ns: AtomicU64,
+    /// 0 while the TSC is not in use.
+    multiplier: AtomicU64,
+}
+
+const CONVERSION_INIT: Conversion = Conversion {
+    origin_tsc: AtomicU64::new(0),
+    origin_ns: AtomicU64::new(0),
+    multiplier: AtomicU64::new(0),
+};
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// Readers use `CONVERSIONS[SEQUENCE & 1]` and retry if `SEQUENCE` changed meanwhile.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static CONVERSIONS: [Conversion; 2] = [CONVERSION_INIT; 2];
+/// Serializes `set_frequency`.
+static WRITER: Mutex<()> = Mutex::new(());
+
+/// Returns whether the CPU has a TSC.
+pub fn is_available() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .map(|f| f.has_tsc())
+        .unwrap_or(false)
+}
+
+/// Returns whether the TSC runs at a constant rate in all ACPI P-, C- and T-states.
+pub fn is_invariant() -> bool {
+    CpuId::new()
+        .get_extended_function_info()
+        .map(|f| f.has_invariant_tsc())
+        .unwrap_or(false)
+}
+
+/// Determines the TSC frequency and starts the clock at zero.
+///
+/// Returns false if the CPU has no TSC.
+pub fn init() -> bool {
+    if !is_available() {
+        println!("TSC not available");
+        return false;
+    }
+
+    let (hz, source) = frequency_from_cpuid()
+        .or_else(frequency_from_platform_info)
+        .unwrap_or_else(|| {
+            if super::hpet::is_available() {
+                (calibrate(super::hpet::hpet_sleep), FrequencySource::HpetCalibration)
+            } else {
+                (calibrate(super::pit::pit_sleep), FrequencySource::PitCalibration)
+            }
+        });
+
+    set_frequency(hz);
+
+    println!(
+        "TSC: {}.{:03} MHz ({:?}){}",
+        hz / 1_000_000,
+        (hz / 1_000) % 1_000,
+        source,
+        if is_invariant() { ", invariant" } else { ", NOT invariant" }
+    );
+    true
+}
+
+/// Replaces the frequency, e.g. after a more precise calibration. The first call
+/// starts the clock at zero.
+///
+/// The clock continues from its current value at the new rate.
+pub fn set_frequency(hz: u64) {
+    interrupts::without_interrupts(|| {
+        let _writer = WRITER.lock();
+        let tsc = rdtsc();
+        let conversion = (tsc, convert(tsc).unwrap_or(0), (1_000_000_000u64 << SHIFT) / hz);
+
+        // readers move to the other copy while each one is written
+        for _ in 0..2 {
+            let sequence = SEQUENCE.fetch_add(1, Ordering::SeqCst) + 1;
+            store(&CONVERSIONS[(sequence & 1) as usize ^ 1], conversion);
+        }
+        FREQUENCY.store(hz, Ordering::SeqCst);
+    });
+}
+
+fn store(conversion: &Conversion, (origin_tsc, origin_ns, multiplier): (u64, u64, u64)) {
+    conversion.origin_tsc.store(origin_tsc, Ordering::Relaxed);
+    conversion.origin_ns.store(origin_ns, Ordering::Relaxed);
+    conversion.multiplier.store(multiplier, Ordering::Relaxed);
+    atomic::fence(Ordering::Release);
+}
+
+/// Converts the counter value `tsc` into nanoseconds since `init`.
+fn convert(tsc: u64) -> Option<u64> {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+        let conversion = &CONVERSIONS[(sequence & 1) as usize];
+        let origin_tsc = conversion.origin_tsc.load(Ordering::Relaxed);
+        let origin_ns = conversion.origin_ns.load(Ordering::Relaxed);
+        let multiplier = conversion.multiplier.load(Ordering::Relaxed);
+        atomic::fence(Ordering::Acquire);
+        if SEQUENCE.load(Ordering::Relaxed) != sequence {
+            continue;
+        }
+
+        if multiplier == 0 {
+            return None;
+        }
+        // a TSC slightly behind the origin, e.g. on another CPU, reads as the origin
+        let delta = tsc.saturating_sub(origin_tsc);
+        return Some(origin_ns + ((u128::from(delta) * u128::from(multiplier)) >> SHIFT) as u64);
+    }
+}
+
+/// Returns the TSC frequency in Hz, or 0 before `init`.
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Returns the nanoseconds since `init`, or `None` if the TSC is not in use.
+pub fn nanoseconds() -> Option<u64> {
+    convert(rdtsc())
+}
+
+/// Converts a duration into TSC cycles.
+pub fn duration_to_cycles(duration: Duration) -> u64 {
+    (duration.as_nanos() * u128::from(frequency()) / 1_000_000_000) as u64
+}
+
+/// Measures the TSC against `sleep`, a busy-wait on a reference clock.
+///
+/// The shortest of several runs is used, interruptions can only make a run longer.
+pub fn calibrate<F: Fn(Duration)>(sleep: F) -> u64 {
+    let mut best = u64::max_value();
+    for _ in 0..CALIBRATION_RUNS {
+        let start = rdtsc();
+        sleep(CALIBRATION_PERIOD);
+        let cycles = rdtsc() - start;
+        best = best.min(cycles);
+    }
+    (u128::from(best) * 1_000_000_000 / CALIBRATION_PERIOD.as_nanos()) as u64
+}
+
+fn frequency_from_cpuid() -> Option<(u64, FrequencySource)> {
+    let cpuid = CpuId::new();
+
+    if let Some(tsc) = cpuid.get_tsc_info() {
+        let (numerator, denominator) = (u64::from(tsc.numerator()), u64::from(tsc.denominator()));
+        let crystal = u64::from(tsc.nominal_frequency());
+        if numerator != 0 && denominator != 0 && crystal != 0 {
+            return Some((crystal * numerator / denominator, FrequencySource::CpuidTscLeaf));
+        }
+    }
+
+    cpuid
+        .get_processor_frequency_info()
+        .map(|f| u64::from(f.processor_base_frequency()) * 1_000_000)
+        .filter(|&hz| hz != 0)
+        .map(|hz| (hz, FrequencySource::CpuidFrequencyLeaf))
+}
+
+/// Reads `MSR_PLATFORM_INFO`, only attempted on bare metal Intel family 6 CPUs since
+/// the MSR raises `#GP` everywhere else (including most hypervisors).
+fn frequency_from_platform_info() -> Option<(u64, FrequencySource)> {
+    let cpuid = CpuId::new();
+
+    let is_intel = cpuid
+        .get_vendor_info()
+        .map(|v| v.as_string() == "GenuineIntel")
+        .unwrap_or(false);
+    let features = cpuid.get_feature_info()?;
+    // CPUID.1:ECX bit 31 is set by hypervisors
+    let hypervisor = raw_cpuid::cpuid!(1).ecx & (1 << 31) != 0;
+    // Nehalem and later
+    let model = (features.extended_model_id() << 4) | features.model_id();
+    let has_platform_info = features.family_id() == 6 && model >= 0x1A;
+
+    if !is_intel || hypervisor || !has_platform_info {
+        return None;
+    }
+
+    let ratio = (rdmsr(msr::MSR_PLATFORM_INFO) >> 8) & 0xFF;
+    if ratio == 0 {
+        return None;
+    }
+    Some((ratio * 100_000_000, FrequencySource::PlatformInfo))
+}
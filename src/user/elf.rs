@@ -0,0 +1,330 @@
+//! The ELF64 file format, as far as the loader needs it.
+//!
+//! `ElfFile::parse` checks the file header and the program header table of an
+//! x86_64 executable. The image comes from an untrusted source and may be
+//! unaligned, so every field is read byte by byte with bounds checks; once parsed,
+//! the file data of every program header lies within the image.
+
+use alloc::vec::Vec;
+
+/// `e_ident[EI_MAG0..=EI_MAG3]`.
+const MAGIC: [u8; 4] = *b"\x7fELF";
+/// `e_ident[EI_CLASS]` of 64 bit files.
+const CLASS_64: u8 = 2;
+/// `e_ident[EI_DATA]` of little endian files.
+const DATA_LSB: u8 = 1;
+/// The only ELF version, `e_ident[EI_VERSION]` and `e_version`.
+const VERSION_CURRENT: u32 = 1;
+
+const HEADER_SIZE: u64 = 64;
+/// Size of a program header, `AT_PHENT`.
+pub const PROGRAM_HEADER_SIZE: u64 = 56;
+const DYNAMIC_SIZE: u64 = 16;
+const RELA_SIZE: u64 = 24;
+
+/// `e_type` of executables linked to a fixed address.
+pub const ET_EXEC: u16 = 2;
+/// `e_type` of position independent executables and shared objects.
+pub const ET_DYN: u16 = 3;
+/// `e_machine` of x86_64.
+pub const EM_X86_64: u16 = 62;
+
+/// A segment to be mapped.
+pub const PT_LOAD: u32 = 1;
+/// The dynamic section.
+pub const PT_DYNAMIC: u32 = 2;
+/// The path of the dynamic linker.
+pub const PT_INTERP: u32 = 3;
+/// The program header table itself.
+pub const PT_PHDR: u32 = 6;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+/// A relocation that does nothing.
+pub const R_X86_64_NONE: u32 = 0;
+/// `*offset = base + addend`.
+pub const R_X86_64_RELATIVE: u32 = 8;
+/// Calls a resolver in user mode, left to the program's startup code.
+pub const R_X86_64_IRELATIVE: u32 = 37;
+
+bitflags! {
+    /// `p_flags` of a program header.
+    pub struct SegmentFlags: u32 {
+        const EXECUTE = 1;
+        const WRITE = 1 << 1;
+        const READ = 1 << 2;
+    }
+}
+
+/// Reasons an image is not an executable the loader can handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// A header or table reaches past the end of the image.
+    Truncated,
+    /// The image does not start with the ELF magic.
+    BadMagic,
+    /// `EI_CLASS` is not 64 bit.
+    UnsupportedClass(u8),
+    /// `EI_DATA` is not little endian.
+    UnsupportedEncoding(u8),
+    /// The ELF version is not 1.
+    UnsupportedVersion(u32),
+    /// The file is neither `ET_EXEC` nor `ET_DYN`.
+    UnsupportedType(u16),
+    /// The file is not for x86_64.
+    UnsupportedMachine(u16),
+    /// The program header table is empty or has entries of the wrong size.
+    BadProgramHeaders,
+    /// The program header with this index has inconsistent sizes or alignment.
+    BadSegment(u16),
+    /// There is no `PT_LOAD` segment.
+    NoLoadableSegments,
+    /// The program needs a dynamic linker.
+    Interpreter,
+    /// The dynamic section or its relocation table is malformed.
+    BadDynamic,
+    /// A relocation of this type can not be applied by the kernel.
+    UnsupportedRelocation(u32),
+}
+
+/// The fields of the file header the loader uses.
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub file_type: u16,
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub program_header_count: u16,
+}
+
+/// A program header.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: SegmentFlags,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+/// A relocation with an explicit addend, from the `DT_RELA` table.
+#[derive(Debug, Clone, Copy)]
+pub struct Rela {
+    pub offset: u64,
+    pub kind: u32,
+    pub symbol: u32,
+    pub addend: i64,
+}
+
+/// A parsed executable.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    header: Header,
+    program_headers: Vec<ProgramHeader>,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Checks the headers of the executable in `data`.
+    pub fn parse(data: &'a [u8]) -> Result<ElfFile<'a>, ElfError> {
+        let ident = bytes(data, 0, HEADER_SIZE)?;
+        if ident[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if ident[4] != CLASS_64 {
+            return Err(ElfError::UnsupportedClass(ident[4]));
+        }
+        if ident[5] != DATA_LSB {
+            return Err(ElfError::UnsupportedEncoding(ident[5]));
+        }
+        if u32::from(ident[6]) != VERSION_CURRENT {
+            return Err(ElfError::UnsupportedVersion(u32::from(ident[6])));
+        }
+        let version = read_u32(data, 20)?;
+        if version != VERSION_CURRENT {
+            return Err(ElfError::UnsupportedVersion(version));
+        }
+
+        let file_type = read_u16(data, 16)?;
+        if file_type != ET_EXEC && file_type != ET_DYN {
+            return Err(ElfError::UnsupportedType(file_type));
+        }
+        let machine = read_u16(data, 18)?;
+        if machine != EM_X86_64 {
+            return Err(ElfError::UnsupportedMachine(machine));
+        }
+
+        let header = Header {
+            file_type,
+            entry: read_u64(data, 24)?,
+            program_header_offset: read_u64(data, 32)?,
+            program_header_count: read_u16(data, 56)?,
+        };
+        let entry_size = read_u16(data, 54)?;
+        if u64::from(entry_size) != PROGRAM_HEADER_SIZE || header.program_header_count == 0 {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        let mut program_headers = Vec::with_capacity(header.program_header_count as usize);
+        for index in 0..header.program_header_count {
+            let offset = header.program_header_offset + u64::from(index) * PROGRAM_HEADER_SIZE;
+            let program_header = read_program_header(data, offset)?;
+            check_program_header(data, &program_header).ok_or(ElfError::BadSegment(index))?;
+            match program_header.kind {
+                PT_INTERP => return Err(ElfError::Interpreter),
+                _ => program_headers.push(program_header),
+            }
+        }
+        if !program_headers.iter().any(|ph| ph.kind == PT_LOAD) {
+            return Err(ElfError::NoLoadableSegments);
+        }
+
+        Ok(ElfFile {
+            data,
+            header,
+            program_headers,
+        })
+    }
+
+    /// The file header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Whether the executable can be loaded at any address, `ET_DYN` files are.
+    pub fn is_position_independent(&self) -> bool {
+        self.header.file_type == ET_DYN
+    }
+
+    /// The program headers, in file order.
+    pub fn program_headers(&self) -> &[ProgramHeader] {
+        &self.program_headers
+    }
+
+    /// The bytes of a segment that come from the file.
+    pub fn segment_data(&self, program_header: &ProgramHeader) -> &'a [u8] {
+        let start = program_header.offset as usize;
+        &self.data[start..start + program_header.file_size as usize]
+    }
+
+    /// Returns the file offset of `len` bytes at the link time address `address`, if
+    /// the file part of a `PT_LOAD` segment holds all of them.
+    pub fn file_offset(&self, address: u64, len: u64) -> Option<u64> {
+        self.program_headers
+            .iter()
+            .filter(|ph| ph.kind == PT_LOAD)
+            .find_map(|ph| {
+                let start = address.checked_sub(ph.vaddr)?;
+                if start.checked_add(len)? <= ph.file_size {
+                    Some(ph.offset + start)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Returns the `DT_RELA` relocation table of the dynamic section, empty if there
+    /// is none.
+    pub fn relocations(&self) -> Result<Vec<Rela>, ElfError> {
+        let dynamic = match self.program_headers.iter().find(|ph| ph.kind == PT_DYNAMIC) {
+            Some(dynamic) => dynamic,
+            None => return Ok(Vec::new()),
+        };
+
+        let (mut table, mut table_size, mut entry_size) = (None, 0, RELA_SIZE);
+        let end = dynamic.offset + dynamic.file_size;
+        let mut offset = dynamic.offset;
+        while offset + DYNAMIC_SIZE <= end {
+            let value = read_u64(self.data, offset + 8)?;
+            match read_u64(self.data, offset)? {
+                DT_NULL => break,
+                DT_RELA => table = Some(value),
+                DT_RELASZ => table_size = value,
+                DT_RELAENT => entry_size = value,
+                _ => {}
+            }
+            offset += DYNAMIC_SIZE;
+        }
+
+        let address = match table {
+            Some(address) => address,
+            None => return Ok(Vec::new()),
+        };
+        if entry_size != RELA_SIZE || table_size % RELA_SIZE != 0 {
+            return Err(ElfError::BadDynamic);
+        }
+        let start = self
+            .file_offset(address, table_size)
+            .ok_or(ElfError::BadDynamic)?;
+
+        (0..table_size / RELA_SIZE)
+            .map(|index| {
+                let offset = start + index * RELA_SIZE;
+                let info = read_u64(self.data, offset + 8)?;
+                Ok(Rela {
+                    offset: read_u64(self.data, offset)?,
+                    kind: info as u32,
+                    symbol: (info >> 32) as u32,
+                    addend: read_u64(self.data, offset + 16)? as i64,
+                })
+            })
+            .collect()
+    }
+}
+
+fn read_program_header(data: &[u8], offset: u64) -> Result<ProgramHeader, ElfError> {
+    Ok(ProgramHeader {
+        kind: read_u32(data, offset)?,
+        flags: SegmentFlags::from_bits_truncate(read_u32(data, offset + 4)?),
+        offset: read_u64(data, offset + 8)?,
+        vaddr: read_u64(data, offset + 16)?,
+        file_size: read_u64(data, offset + 32)?,
+        mem_size: read_u64(data, offset + 40)?,
+        align: read_u64(data, offset + 48)?,
+    })
+}
+
+/// Returns `None` if the file part of the segment leaves the image or the header is
+/// inconsistent.
+fn check_program_header(data: &[u8], ph: &ProgramHeader) -> Option<()> {
+    bytes(data, ph.offset, ph.file_size).ok()?;
+    if ph.kind != PT_LOAD {
+        return Some(());
+    }
+
+    ph.vaddr.checked_add(ph.mem_size)?;
+    if ph.file_size > ph.mem_size {
+        return None;
+    }
+    // the file offset and the address agree modulo the alignment, so the file can
+    // be mapped page by page
+    if ph.align > 1 && (!ph.align.is_power_of_two() || ph.vaddr % ph.align != ph.offset % ph.align)
+    {
+        return None;
+    }
+    Some(())
+}
+
+fn bytes(data: &[u8], offset: u64, len: u64) -> Result<&[u8], ElfError> {
+    let end = offset.checked_add(len).ok_or(ElfError::Truncated)?;
+    data.get(offset as usize..end as usize).ok_or(ElfError::Truncated)
+}
+
+fn read_u16(data: &[u8], offset: u64) -> Result<u16, ElfError> {
+    let b = bytes(data, offset, 2)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], offset: u64) -> Result<u32, ElfError> {
+    let b = bytes(data, offset, 4)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_u64(data: &[u8], offset: u64) -> Result<u64, ElfError> {
+    let mut value = [0; 8];
+    value.copy_from_slice(bytes(data, offset, 8)?);
+    Ok(u64::from_le_bytes(value))
+}
@@ -0,0 +1,402 @@
+//! Loads static ELF64 executables into a new address space.
+//!
+//! `load` maps the `PT_LOAD` segments into a fresh page table from
+//! `MemoryController::new_address_space`, with `EntryFlags` from the segment flags
+//! and the part beyond the file data (`.bss`) zeroed. The file data is copied from
+//! the image straight into the new frames. `load` also builds the initial stack
+//! the System V ABI describes: `argc` at the stack pointer, then the `argv` and
+//! `envp` pointer arrays and the auxiliary vector, with the strings above them.
+//! `Program::start` switches the calling thread to the new page table and jumps
+//! to the entry point.
+//!
+//! `ET_EXEC` files have to be linked into the user part of the address space.
+//! Static PIE (`ET_DYN` without an interpreter) is loaded at `PIE_BASE` and its
+//! `R_X86_64_RELATIVE` relocations are applied while the segments are copied.
+//! Applying them is idempotent, so startup code that relocates the program again
+//! does no harm.
+
+use alloc::vec::Vec;
+use core::cmp::{self, Ordering};
+use super::elf::{self, ElfError, ElfFile, SegmentFlags};
+use crate::memory::paging::{
+    self, EntryFlags, InactivePageTable, VirtualAddress, USER_SPACE_END, USER_SPACE_START,
+};
+use crate::memory::{self, PAGE_SIZE};
+use crate::thread;
+use crate::x86_64::instructions::{self, random::RdRand};
+
+/// Where position independent executables are loaded.
+pub const PIE_BASE: VirtualAddress = USER_SPACE_START;
+
+/// Top of the initial stack, an unmapped page separates it from the end of user
+/// space.
+const STACK_TOP: VirtualAddress = USER_SPACE_END - PAGE_SIZE;
+/// Size of the initial stack, it does not grow.
+const STACK_SIZE: u64 = 16 * PAGE_SIZE;
+/// Segments end below the stack's guard page.
+const SEGMENTS_END: VirtualAddress = STACK_TOP - STACK_SIZE - PAGE_SIZE;
+
+/// Size of the random bytes `AT_RANDOM` points to.
+const RANDOM_SIZE: u64 = 16;
+
+// auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+/// Reasons `load` fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The image is not a supported executable.
+    Elf(ElfError),
+    /// A segment, relocation or the entry point is outside of the user part of the
+    /// address space or the program's segments.
+    BadAddress(VirtualAddress),
+    /// Two segments share the page with this address.
+    Overlap(VirtualAddress),
+    /// The arguments and environment do not fit on the initial stack.
+    ArgumentsTooLarge,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> LoadError {
+        LoadError::Elf(error)
+    }
+}
+
+/// A program loaded into its own address space, ready to run.
+pub struct Program {
+    table: InactivePageTable,
+    entry: VirtualAddress,
+    stack_pointer: VirtualAddress,
+}
+
+impl Program {
+    /// The address execution starts at.
+    pub fn entry(&self) -> VirtualAddress {
+        self.entry
+    }
+
+    /// The initial stack pointer, pointing at `argc`.
+    pub fn stack_pointer(&self) -> VirtualAddress {
+        self.stack_pointer
+    }
+
+    /// Moves the calling thread into the program's address space and continues it
+    /// at the entry point, see `enter_user`.
+    pub fn start(self) -> ! {
+        thread::set_address_space(self.table);
+        super::enter_user(self.entry, self.stack_pointer)
+    }
+}
+
+/// A `PT_LOAD` segment at its load address.
+struct Segment<'a> {
+    start: VirtualAddress,
+    size: u64,
+    /// The file data in the image.
+    data: &'a [u8],
+    flags: SegmentFlags,
+}
+
+impl<'a> Segment<'a> {
+    fn end(&self) -> VirtualAddress {
+        self.start + self.size
+    }
+}
+
+/// Loads the executable in `image` into a new address space, with `argv` and `envp`
+/// on its stack.
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, LoadError> {
+    let elf = ElfFile::parse(image)?;
+
+    let bias = if elf.is_position_independent() {
+        let lowest = elf
+            .program_headers()
+            .iter()
+            .filter(|ph| ph.kind == elf::PT_LOAD)
+            .map(|ph| ph.vaddr & !(PAGE_SIZE - 1))
+            .min()
+            .unwrap();
+        PIE_BASE.wrapping_sub(lowest)
+    } else {
+        0
+    };
+
+    let mut segments = Vec::new();
+    for ph in elf.program_headers() {
+        if ph.kind != elf::PT_LOAD || ph.mem_size == 0 {
+            continue;
+        }
+        let start = ph.vaddr.wrapping_add(bias);
+        let end = start.checked_add(ph.mem_size);
+        if !paging::is_user_address(start) || end.map_or(true, |end| end > SEGMENTS_END) {
+            return Err(LoadError::BadAddress(start));
+        }
+        segments.push(Segment {
+            start,
+            size: ph.mem_size,
+            data: elf.segment_data(ph),
+            flags: ph.flags,
+        });
+    }
+    segments.sort_by_key(|segment| segment.start);
+    for pair in segments.windows(2) {
+        if (pair[0].end() - 1) / PAGE_SIZE >= pair[1].start / PAGE_SIZE {
+            return Err(LoadError::Overlap(pair[1].start));
+        }
+    }
+
+    // the values to write at load addresses, sorted by address
+    let mut relocations = Vec::new();
+    if elf.is_position_independent() {
+        for rela in elf.relocations()? {
+            match rela.kind {
+                elf::R_X86_64_NONE => {}
+                elf::R_X86_64_RELATIVE => {
+                    let address = rela.offset.wrapping_add(bias);
+                    check_relocation(&segments, address)?;
+                    relocations.push((address, bias.wrapping_add(rela.addend as u64)));
+                }
+                // needs user code to run, the program's startup code handles it
+                elf::R_X86_64_IRELATIVE => {}
+                kind => return Err(ElfError::UnsupportedRelocation(kind).into()),
+            }
+        }
+    }
+    relocations.sort_by_key(|&(address, _)| address);
+
+    let entry = elf.header().entry.wrapping_add(bias);
+    let executable = segments
+        .iter()
+        .any(|s| s.flags.contains(SegmentFlags::EXECUTE) && entry >= s.start && entry < s.end());
+    if !executable {
+        return Err(LoadError::BadAddress(entry));
+    }
+
+    let header = elf.header();
+    let mut auxv = Vec::new();
+    if let Some(phdr) = program_headers_address(&elf) {
+        auxv.push((AT_PHDR, phdr.wrapping_add(bias)));
+    }
+    auxv.push((AT_PHENT, elf::PROGRAM_HEADER_SIZE));
+    auxv.push((AT_PHNUM, u64::from(header.program_header_count)));
+    auxv.push((AT_PAGESZ, PAGE_SIZE));
+    auxv.push((AT_ENTRY, entry));
+    let stack = initial_stack(argv, envp, &auxv)?;
+
+    let mut controller = memory::controller();
+    let mut table = controller.new_address_space();
+    for segment in &segments {
+        let flags = EntryFlags::from_elf_segment_flags(segment.flags);
+        controller.map_user_in(
+            &mut table,
+            segment.start,
+            segment.size,
+            flags,
+            |page_start, page| {
+                copy_into_page(page_start, page, segment.start, segment.data);
+                relocate_page(&relocations, page_start, page);
+            },
+        );
+    }
+    let stack_flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    controller.map_user_in(
+        &mut table,
+        STACK_TOP - STACK_SIZE,
+        STACK_SIZE,
+        stack_flags,
+        |page_start, page| stack.fill(page_start, page),
+    );
+
+    Ok(Program {
+        table,
+        entry,
+        stack_pointer: stack.stack_pointer,
+    })
+}
+
+/// Checks that the 8 bytes a relocation writes at `address` lie in one segment.
+fn check_relocation(segments: &[Segment], address: VirtualAddress) -> Result<(), LoadError> {
+    let end = address.checked_add(8).ok_or(LoadError::BadAddress(address))?;
+    if segments.iter().any(|s| address >= s.start && end <= s.end()) {
+        Ok(())
+    } else {
+        Err(LoadError::BadAddress(address))
+    }
+}
+
+/// Copies the part of `bytes`, placed at `address`, that falls into the page at
+/// `page_start` with the contents `page`.
+fn copy_into_page(
+    page_start: VirtualAddress,
+    page: &mut [u8],
+    address: VirtualAddress,
+    bytes: &[u8],
+) {
+    let from = cmp::max(page_start, address);
+    let to = cmp::min(page_start + PAGE_SIZE, address + bytes.len() as u64);
+    if from < to {
+        page[(from - page_start) as usize..(to - page_start) as usize]
+            .copy_from_slice(&bytes[(from - address) as usize..(to - address) as usize]);
+    }
+}
+
+/// Applies the relocations, sorted by address, that write into the page at
+/// `page_start`. A relocation may span two pages.
+fn relocate_page(
+    relocations: &[(VirtualAddress, u64)],
+    page_start: VirtualAddress,
+    page: &mut [u8],
+) {
+    // the first relocation that ends after the start of the page
+    let first = relocations
+        .binary_search_by(|&(address, _)| {
+            if address + 8 <= page_start {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        })
+        .unwrap_err();
+    let page_end = page_start + PAGE_SIZE;
+    for &(address, value) in relocations[first..]
+        .iter()
+        .take_while(|&&(address, _)| address < page_end)
+    {
+        copy_into_page(page_start, page, address, &value.to_le_bytes());
+    }
+}
+
+/// Returns the link time address of the program header table, if it is loaded.
+fn program_headers_address(elf: &ElfFile) -> Option<VirtualAddress> {
+    if let Some(phdr) = elf.program_headers().iter().find(|ph| ph.kind == elf::PT_PHDR) {
+        return Some(phdr.vaddr);
+    }
+
+    // without PT_PHDR, the table is loaded if a segment holds its file offset
+    let header = elf.header();
+    let size = u64::from(header.program_header_count) * elf::PROGRAM_HEADER_SIZE;
+    elf.program_headers()
+        .iter()
+        .filter(|ph| ph.kind == elf::PT_LOAD)
+        .find(|ph| {
+            header.program_header_offset >= ph.offset
+                && header.program_header_offset + size <= ph.offset + ph.file_size
+        })
+        .map(|ph| ph.vaddr + (header.program_header_offset - ph.offset))
+}
+
+/// The contents of the top of the initial stack.
+struct InitialStack<'a> {
+    stack_pointer: VirtualAddress,
+    /// Where the `AT_RANDOM` bytes go, and the bytes.
+    random: (VirtualAddress, [u8; RANDOM_SIZE as usize]),
+    /// The argument and environment strings and their addresses, the zeroed pages
+    /// terminate them.
+    strings: Vec<(VirtualAddress, &'a str)>,
+    /// `argc`, the `argv` and `envp` arrays and the auxiliary vector, from the stack
+    /// pointer up.
+    words: Vec<u64>,
+}
+
+impl<'a> InitialStack<'a> {
+    /// Writes the part of the stack that falls into the page at `page_start`.
+    fn fill(&self, page_start: VirtualAddress, page: &mut [u8]) {
+        let (random, bytes) = self.random;
+        copy_into_page(page_start, page, random, &bytes);
+        for &(address, string) in &self.strings {
+            copy_into_page(page_start, page, address, string.as_bytes());
+        }
+        for (index, word) in self.words.iter().enumerate() {
+            let address = self.stack_pointer + index as u64 * 8;
+            copy_into_page(page_start, page, address, &word.to_le_bytes());
+        }
+    }
+}
+
+/// Lays out the top of the initial stack.
+fn initial_stack<'a>(
+    argv: &[&'a str],
+    envp: &[&'a str],
+    auxv: &[(u64, u64)],
+) -> Result<InitialStack<'a>, LoadError> {
+    let strings_size = argv
+        .iter()
+        .chain(envp)
+        .map(|s| s.len() as u64 + 1)
+        .sum::<u64>()
+        + RANDOM_SIZE;
+    if strings_size > STACK_SIZE {
+        return Err(LoadError::ArgumentsTooLarge);
+    }
+    let strings_start = (STACK_TOP - strings_size) & !0xf;
+    // argc, both arrays with their null terminator and the auxiliary vector with
+    // `AT_RANDOM` and `AT_NULL`
+    let word_count = (1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 2)) as u64;
+    let stack_pointer = strings_start
+        .checked_sub(word_count * 8)
+        .map(|sp| sp & !0xf)
+        .filter(|&sp| STACK_TOP - sp <= STACK_SIZE)
+        .ok_or(LoadError::ArgumentsTooLarge)?;
+
+    let random = strings_start;
+    let mut strings = Vec::with_capacity(argv.len() + envp.len());
+    let mut next = random + RANDOM_SIZE;
+    let mut pointers = |list: &[&'a str], next: &mut VirtualAddress| {
+        let mut addresses = Vec::with_capacity(list.len() + 1);
+        for &string in list {
+            addresses.push(*next);
+            strings.push((*next, string));
+            *next += string.len() as u64 + 1;
+        }
+        addresses.push(0);
+        addresses
+    };
+    let argv_pointers = pointers(argv, &mut next);
+    let envp_pointers = pointers(envp, &mut next);
+
+    let mut words = Vec::with_capacity(word_count as usize);
+    words.push(argv.len() as u64);
+    words.extend(argv_pointers);
+    words.extend(envp_pointers);
+    for &(kind, value) in auxv {
+        words.push(kind);
+        words.push(value);
+    }
+    words.extend(&[AT_RANDOM, random, AT_NULL, 0]);
+
+    Ok(InitialStack {
+        stack_pointer,
+        random: (random, random_bytes()),
+        strings,
+        words,
+    })
+}
+
+/// Returns the bytes `AT_RANDOM` points to, C libraries seed stack canaries with
+/// them. Falls back to the time stamp counter without `rdrand`.
+fn random_bytes() -> [u8; RANDOM_SIZE as usize] {
+    let rdrand = RdRand::new();
+    let mut state = instructions::rdtsc();
+    let mut next = || {
+        if let Some(value) = rdrand.as_ref().and_then(RdRand::get_u64) {
+            return value;
+        }
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+
+    let mut bytes = [0; RANDOM_SIZE as usize];
+    bytes[..8].copy_from_slice(&next().to_le_bytes());
+    bytes[8..].copy_from_slice(&next().to_le_bytes());
+    bytes
+}
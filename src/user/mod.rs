@@ -0,0 +1,111 @@
+//! User mode.
+//!
+//! User code runs in ring 3 with the user segments of the GDT, on pages mapped with
+//! `USER_ACCESSIBLE` in the user part of the address space, see
+//! `MemoryController::map_user`. A kernel thread continues in user mode with
+//! `enter_user`, which does not return: interrupts and exceptions from user mode
+//! enter the kernel at the top of the thread's kernel stack, which the scheduler
+//! puts into `TSS.privilege_stack_table[0]` whenever it switches to the thread.
+//!
+//! User code calls into the kernel with the `syscall` instruction. An exception
+//! raised by user code ends the thread that raised it, the kernel keeps running.
+//!
+//! `loader` loads ELF executables into an address space of their own. A thread that
+//! starts such a program runs on its page table from then on, the scheduler loads
+//! it into `cr3` whenever it switches to the thread.
+
+pub mod elf;
+pub mod loader;
+pub mod syscall;
+
+use crate::interrupts::exceptions::{self, PAGE_FAULT};
+use crate::interrupts::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::interrupts::InterruptContext;
+use crate::memory::paging::{self, VirtualAddress};
+use crate::thread::{self, scheduler};
+use crate::x86_64::instructions::interrupts;
+use crate::x86_64::registers::control_regs;
+use crate::x86_64::structures::idt::PageFaultErrorCode;
+
+/// RFLAGS user code starts with, interrupts enabled and the reserved bit 1 set.
+const USER_RFLAGS: u64 = 0x202;
+
+/// Continues the calling thread in user mode at `entry`, with `stack` as the stack
+/// pointer and all other general purpose registers zeroed.
+///
+/// Everything on the thread's kernel stack is abandoned, the caller must not hold
+/// locks or other resources. Must be called by a spawned thread that can block.
+pub fn enter_user(entry: VirtualAddress, stack: VirtualAddress) -> ! {
+    assert!(paging::is_user_address(entry), "user entry {:#x} outside of user space", entry);
+    assert!(
+        paging::is_user_address(stack - 1),
+        "user stack {:#x} outside of user space",
+        stack
+    );
+    assert!(scheduler::can_block(), "enter_user from a context that can not block");
+
+    unsafe {
+        // the next interrupt arrives from user mode, with the user GS base
+        interrupts::disable();
+        llvm_asm!("
+            swapgs
+            pushq $1
+            pushq $2
+            pushq $3
+            pushq $4
+            pushq $0
+            xorq %rax, %rax
+            xorq %rbx, %rbx
+            xorq %rcx, %rcx
+            xorq %rdx, %rdx
+            xorq %rsi, %rsi
+            xorq %rdi, %rdi
+            xorq %rbp, %rbp
+            xorq %r8, %r8
+            xorq %r9, %r9
+            xorq %r10, %r10
+            xorq %r11, %r11
+            xorq %r12, %r12
+            xorq %r13, %r13
+            xorq %r14, %r14
+            xorq %r15, %r15
+            iretq"
+            :
+            : "r" (entry),
+              "r" (u64::from(USER_DATA_SELECTOR.0)),
+              "r" (stack),
+              "r" (USER_RFLAGS),
+              "r" (u64::from(USER_CODE_SELECTOR.0))
+            :
+            : "volatile");
+    }
+    unreachable!("returned from user mode");
+}
+
+/// Ends the current thread after user code raised an exception, called by the
+/// exception handler for every exception from user mode.
+pub fn handle_fault(context: &InterruptContext) -> ! {
+    {
+        let thread = thread::current();
+        let vector = context.vector();
+        if vector == PAGE_FAULT {
+            println!(
+                "[user] thread {:?} '{}' killed by PAGE FAULT accessing {:#x}, {:?}\n{:#?}",
+                thread.id(),
+                thread.name(),
+                control_regs::cr2(),
+                PageFaultErrorCode::from_bits_truncate(context.error_code),
+                context
+            );
+        } else {
+            println!(
+                "[user] thread {:?} '{}' killed by {}\n{:#?}",
+                thread.id(),
+                thread.name(),
+                exceptions::name(vector),
+                context
+            );
+        }
+    }
+    thread::exit()
+}
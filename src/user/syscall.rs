@@ -0,0 +1,192 @@
+//! System calls through `syscall` and `sysret`.
+//!
+//! `init_cpu` points `IA32_LSTAR` at `syscall_entry` in `syscall_entry.asm` and
+//! enables the instruction in `IA32_EFER`. User code passes the system call number
+//! in `rax` and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, like
+//! on Linux; the instruction itself clobbers `rcx` and `r11`. The result comes back
+//! in `rax`, values from -4095 to -1 are a negated `Errno`.
+//!
+//! The numbers in `nr` are the ABI, a number never changes its meaning and new
+//! system calls get new numbers. Handlers run in the calling thread with interrupts
+//! enabled and may block.
+
+use alloc::string::String;
+use core::slice;
+use core::time::Duration;
+use crate::interrupts::gdt::{KERNEL_CODE_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::memory::{self, paging, PAGE_SIZE};
+use crate::thread;
+use crate::time;
+use crate::x86_64::registers::msr::{self, rdmsr, wrmsr};
+
+extern "C" {
+    /// The entry stub in `syscall_entry.asm`.
+    fn syscall_entry();
+}
+
+/// `IA32_EFER.SCE`, enables `syscall` and `sysret`.
+const EFER_SCE: u64 = 1 << 0;
+
+/// RFLAGS bits cleared on entry: TF, IF, DF, IOPL, NT and AC.
+const SYSCALL_FMASK: u64 = 0x4_7700;
+
+/// The system call numbers.
+pub mod nr {
+    /// `exit(code)`, ends the calling thread.
+    pub const EXIT: u64 = 0;
+    /// `write(fd, buf, len)`, writes to the console, fd 1 and 2 only.
+    pub const WRITE: u64 = 1;
+    /// `yield()`, gives up the CPU.
+    pub const YIELD: u64 = 2;
+    /// `sleep(ms)`, sleeps for at least `ms` milliseconds, `Invalid` if the wakeup
+    /// lies past the end of the monotonic clock.
+    pub const SLEEP: u64 = 3;
+    /// `gettid()`, returns the identifier of the calling thread.
+    pub const GETTID: u64 = 4;
+    /// `clock()`, returns the nanoseconds since boot.
+    pub const CLOCK: u64 = 5;
+}
+
+/// Error numbers returned to user code, the same values as on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Errno {
+    /// The file descriptor is not open.
+    BadFile = 9,
+    /// A pointer argument does not point at mapped user memory.
+    Fault = 14,
+    /// An argument is out of range.
+    Invalid = 22,
+    /// No system call has the number.
+    NoSys = 38,
+}
+
+/// The value a system call returns in `rax`, or the error it fails with.
+pub type SyscallResult = Result<u64, Errno>;
+
+type Handler = fn(&[u64; 6]) -> SyscallResult;
+
+/// The handlers, indexed by system call number.
+static TABLE: [Handler; 6] = [sys_exit, sys_write, sys_yield, sys_sleep, sys_gettid, sys_clock];
+
+/// The user registers as pushed by `syscall_entry` (lowest address first).
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// The system call number on entry, the return value on exit.
+    pub rax: u64,
+    /// The user instruction pointer, `sysret` restores it from `rcx`.
+    pub rip: u64,
+    /// The user flags, `sysret` restores them from `r11`.
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+/// Enables `syscall` on the executing CPU.
+pub fn init_cpu() {
+    // `sysret` loads SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16
+    let sysret_base = USER_CODE_SELECTOR.0 - 16;
+    debug_assert_eq!(USER_DATA_SELECTOR.0, sysret_base + 8);
+    // `syscall` loads CS from STAR[47:32] and SS from STAR[47:32] + 8
+    let star = u64::from(sysret_base) << 48 | u64::from(KERNEL_CODE_SELECTOR.0) << 32;
+
+    unsafe {
+        wrmsr(msr::IA32_STAR, star);
+        wrmsr(msr::IA32_LSTAR, syscall_entry as u64);
+        wrmsr(msr::IA32_FMASK, SYSCALL_FMASK);
+        wrmsr(msr::IA32_EFER, rdmsr(msr::IA32_EFER) | EFER_SCE);
+    }
+}
+
+/// Called by `syscall_entry` with interrupts enabled.
+#[no_mangle]
+pub extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    let result = match TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(&args),
+        None => Err(Errno::NoSys),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (errno as u64).wrapping_neg(),
+    };
+
+    // `sysret` to a non-canonical address faults in ring 0, on the user stack; a
+    // `syscall` at the very end of the lower half returns there
+    if frame.rip >= paging::USER_SPACE_END {
+        let thread = thread::current();
+        println!(
+            "[user] thread {:?} '{}' killed, non-canonical return address {:#x}",
+            thread.id(),
+            thread.name(),
+            frame.rip
+        );
+        drop(thread);
+        thread::exit();
+    }
+}
+
+/// Returns the user memory at `address` if all of it is mapped.
+fn user_bytes<'a>(address: u64, len: u64) -> Result<&'a [u8], Errno> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    let end = address.checked_add(len - 1).ok_or(Errno::Fault)?;
+    if !paging::is_user_address(address) || !paging::is_user_address(end) {
+        return Err(Errno::Fault);
+    }
+
+    let controller = memory::controller();
+    let mut page = address & !(PAGE_SIZE - 1);
+    while page <= end {
+        if controller.translate(page).is_none() {
+            return Err(Errno::Fault);
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(unsafe { slice::from_raw_parts(address as *const u8, len as usize) })
+}
+
+fn sys_exit(args: &[u64; 6]) -> SyscallResult {
+    let code = args[0] as i64;
+    if code != 0 {
+        println!("[user] thread {:?} exited with {}", thread::current().id(), code);
+    }
+    thread::exit()
+}
+
+fn sys_write(args: &[u64; 6]) -> SyscallResult {
+    let (fd, buf, len) = (args[0], args[1], args[2]);
+    if fd != 1 && fd != 2 {
+        return Err(Errno::BadFile);
+    }
+    let bytes = user_bytes(buf, len)?;
+    print!("{}", String::from_utf8_lossy(bytes));
+    Ok(len)
+}
+
+fn sys_yield(_args: &[u64; 6]) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(args: &[u64; 6]) -> SyscallResult {
+    let duration = Duration::from_millis(args[0]);
+    time::Instant::now().checked_add(duration).ok_or(Errno::Invalid)?;
+    thread::sleep(duration);
+    Ok(0)
+}
+
+fn sys_gettid(_args: &[u64; 6]) -> SyscallResult {
+    Ok(thread::current().id().as_u64())
+}
+
+fn sys_clock(_args: &[u64; 6]) -> SyscallResult {
+    Ok(time::monotonic_ns())
+}
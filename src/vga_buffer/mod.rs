@@ -1,7 +1,7 @@
 use core::fmt;
 use core::fmt::Write;
 use core::ptr::Unique;
-use spin::Mutex;
+use crate::sync::IrqSafeMutex;
 use volatile::Volatile;
 
 #[allow(dead_code)]
@@ -113,7 +113,7 @@ impl fmt::Write for Writer {
     }
 }
 
-pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
+pub static WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(Writer {
     column_position: 0,
     color_code: ColorCode::new(Color::Green, Color::Black),
     buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) },
@@ -0,0 +1,257 @@
+//! Watchdog that reports CPUs which stopped making progress.
+//!
+//! Kernel code calls `touch()` whenever a CPU makes progress. A periodic check on
+//! every CPU counts how long it has gone without a `touch()` and, once the timeout
+//! has passed, prints the interrupted context and a backtrace over serial.
+//!
+//! The check is driven by a performance counter overflow delivered as an NMI when
+//! the CPU has an architectural PMU, so it also catches CPUs spinning with interrupts
+//! disabled. Without a PMU (e.g. QEMU TCG) the kernel tick drives the check instead,
+//! which only catches hangs with interrupts enabled.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::apic::{self, Register};
+use crate::apic::lapic::LVT_MASKED;
+use crate::backtrace;
+use crate::interrupts::InterruptContext;
+use crate::percpu;
+use crate::time;
+use crate::x86_64::registers::msr::{self, rdmsr, wrmsr};
+use raw_cpuid::CpuId;
+use spin::Once;
+
+// Debuging toggles
+pub const WATCHDOG_ENABLE: bool = false;
+pub const WATCHDOG_TIMEOUT_SECS: u64 = 10;
+
+// Assumed clock rate, only used when CPUID does not report it.
+const FALLBACK_CPU_HZ: u64 = 2_000_000_000;
+
+const APIC_LVT_DELIVER_NMI: u32 = 0b100 << 8;
+
+/// `IA32_PMC0` writes only take the low 32 bits (sign extended), which limits a period.
+const MAX_PMC_PERIOD: u64 = (1 << 31) - 1;
+
+const EVTSEL_UNHALTED_CORE_CYCLES: u64 = 0x3C;
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    /// Unhalted core cycles on PMC0, overflow raises an NMI through the LVT PMI entry.
+    PerfCounter { version: u8, width: u8 },
+    /// Every `TICK_HZ`-th kernel tick, i.e. once per second.
+    TimerTick,
+}
+
+#[derive(Debug)]
+struct Config {
+    source: Source,
+    /// PMC cycles or kernel ticks between two checks.
+    period: u64,
+    /// Checks without a `touch()` until a CPU is reported.
+    checks_until_timeout: u64,
+    timeout_secs: u64,
+}
+
+struct CpuState {
+    active: AtomicBool,
+    ticks: AtomicU64,
+    touched: AtomicBool,
+    missed: AtomicU64,
+    reported: AtomicBool,
+}
+
+const CPU_STATE_INIT: CpuState = CpuState {
+    active: AtomicBool::new(false),
+    ticks: AtomicU64::new(0),
+    touched: AtomicBool::new(true),
+    missed: AtomicU64::new(0),
+    reported: AtomicBool::new(false),
+};
+
+percpu! {
+    static STATE: CpuState = CPU_STATE_INIT;
+}
+
+static CONFIG: Once<Config> = Once::new();
+
+/// Starts the watchdog on the executing (bootstrap) CPU.
+///
+/// Requires the local APIC and the interrupt dispatch table to be set up.
+pub fn init(timeout_secs: u64) {
+    let cpuid = CpuId::new();
+
+    let pmu = cpuid
+        .get_performance_monitoring_info()
+        .filter(|p| p.version_id() > 0 && p.number_of_counters() > 0);
+
+    let config = match pmu {
+        Some(pmu) => {
+            let cpu_hz = cpuid
+                .get_processor_frequency_info()
+                .map(|f| u64::from(f.processor_base_frequency()) * 1_000_000)
+                .filter(|&hz| hz != 0)
+                .unwrap_or(FALLBACK_CPU_HZ);
+            let period = core::cmp::min(cpu_hz, MAX_PMC_PERIOD);
+
+            Config {
+                source: Source::PerfCounter {
+                    version: pmu.version_id(),
+                    width: pmu.counter_bit_width(),
+                },
+                period,
+                checks_until_timeout: timeout_secs * cpu_hz / period,
+                timeout_secs,
+            }
+        }
+        // one check per second
+        None => Config {
+            source: Source::TimerTick,
+            period: u64::from(time::TICK_HZ),
+            checks_until_timeout: timeout_secs,
+            timeout_secs,
+        },
+    };
+
+    let config = CONFIG.call_once(|| config);
+    println!(
+        "Watchdog: {:?}, timeout {} seconds",
+        config.source, config.timeout_secs
+    );
+
+    init_ap();
+}
+
+/// Starts the watchdog on the executing CPU, after `init` ran on the bootstrap CPU.
+pub fn init_ap() {
+    let config = match CONFIG.r#try() {
+        Some(config) => config,
+        None => return,
+    };
+    let lapic = apic::local();
+    STATE.get().active.store(true, Ordering::Relaxed);
+
+    match config.source {
+        Source::PerfCounter { version, .. } => unsafe {
+            wrmsr(msr::IA32_PERFEVTSEL0, 0);
+            reload_counter(config);
+            lapic.write(Register::LvtPerfCounter, APIC_LVT_DELIVER_NMI);
+            if version >= 2 {
+                let global = rdmsr(msr::IA32_PERF_GLOBAL_CTRL);
+                wrmsr(msr::IA32_PERF_GLOBAL_CTRL, global | 1);
+            }
+            wrmsr(
+                msr::IA32_PERFEVTSEL0,
+                EVTSEL_UNHALTED_CORE_CYCLES | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN,
+            );
+        },
+        Source::TimerTick => (),
+    }
+}
+
+/// Stops the watchdog on the executing CPU.
+pub fn disable() {
+    let config = match CONFIG.r#try() {
+        Some(config) => config,
+        None => return,
+    };
+    STATE.get().active.store(false, Ordering::Relaxed);
+
+    if let Source::PerfCounter { .. } = config.source {
+        unsafe {
+            wrmsr(msr::IA32_PERFEVTSEL0, 0);
+            apic::local().write(Register::LvtPerfCounter, LVT_MASKED);
+        }
+    }
+}
+
+/// Tells the watchdog that the executing CPU is making progress.
+pub fn touch() {
+    STATE.get().touched.store(true, Ordering::Relaxed);
+}
+
+/// Called for every NMI, returns true if the NMI was raised by the watchdog counter.
+pub fn handle_nmi(context: &mut InterruptContext) -> bool {
+    let config = match CONFIG.r#try() {
+        Some(config) => config,
+        None => return false,
+    };
+
+    let (version, width) = match config.source {
+        Source::PerfCounter { version, width } => (version, width),
+        Source::TimerTick => return false,
+    };
+
+    // the counter is preloaded with a negative value, it only has the sign bit
+    // cleared after it overflowed
+    if rdmsr(msr::IA32_PMC0) & (1 << (width - 1)) != 0 {
+        return false;
+    }
+
+    unsafe {
+        reload_counter(config);
+        if version >= 2 {
+            wrmsr(msr::IA32_PERF_GLOBAL_OVF_CTRL, 1);
+        }
+        // the LVT entry masks itself when the PMI is delivered
+        apic::local().write(Register::LvtPerfCounter, APIC_LVT_DELIVER_NMI);
+    }
+
+    check(context);
+    true
+}
+
+/// Called from the kernel tick, runs the check once per second when there is no PMU.
+pub fn timer_tick(context: &InterruptContext) {
+    let config = match CONFIG.r#try() {
+        Some(config) => config,
+        None => return,
+    };
+    if config.source != Source::TimerTick {
+        return;
+    }
+    let state = STATE.get();
+    if !state.active.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if (state.ticks.fetch_add(1, Ordering::Relaxed) + 1) % config.period == 0 {
+        check(context);
+    }
+}
+
+unsafe fn reload_counter(config: &Config) {
+    wrmsr(msr::IA32_PMC0, (config.period as i64).wrapping_neg() as u64);
+}
+
+/// Counts a check without progress and reports the CPU once it exceeded the timeout.
+fn check(context: &InterruptContext) {
+    let config = match CONFIG.r#try() {
+        Some(config) => config,
+        None => return,
+    };
+    let state = STATE.get();
+
+    if state.touched.swap(false, Ordering::Relaxed) {
+        state.missed.store(0, Ordering::Relaxed);
+        state.reported.store(false, Ordering::Relaxed);
+        return;
+    }
+
+    let missed = state.missed.fetch_add(1, Ordering::Relaxed) + 1;
+    if missed >= config.checks_until_timeout && !state.reported.swap(true, Ordering::Relaxed) {
+        emergency_println!(
+            "!!! [WATCHDOG] !!! cpu {} made no progress for {} seconds\n{:#?}",
+            percpu::cpu_index(),
+            config.timeout_secs,
+            context
+        );
+        backtrace::print(
+            context.frame.instruction_pointer.0 as u64,
+            context.registers.rbp,
+        );
+    }
+}
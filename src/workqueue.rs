@@ -0,0 +1,183 @@
+//! Workqueues, deferred work that runs in kernel threads and may sleep.
+//!
+//! A `WorkQueue` owns worker threads that take queued closures in FIFO order and
+//! run them. Queueing never blocks and works from anywhere, interrupt handlers and
+//! bottom halves included; the work itself runs in thread context and may block,
+//! sleep and allocate. With more than one worker, work items run concurrently and
+//! may complete out of order.
+//!
+//! `init` starts the system workqueue with one worker per CPU, for work that does not
+//! need a queue of its own. `queue_work` adds to it.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::smp;
+use crate::sync::{IrqSafeMutex, Mutex, WaitQueue};
+use crate::thread::{self, JoinHandle};
+use spin::Once;
+
+type Work = Box<dyn FnOnce() + Send>;
+
+/// A queue of closures run by its own worker threads.
+pub struct WorkQueue {
+    name: String,
+    work: IrqSafeMutex<VecDeque<Work>>,
+    /// Work items queued or running.
+    outstanding: AtomicUsize,
+    /// Set by `destroy`, no more work is accepted.
+    stopping: AtomicBool,
+    /// Idle workers wait here for work.
+    available: WaitQueue,
+    /// `flush` waits here for `outstanding` to drop to zero.
+    idle: WaitQueue,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+static SYSTEM: Once<Arc<WorkQueue>> = Once::new();
+
+impl WorkQueue {
+    /// Creates a queue served by `workers` threads named `<name>/<n>`.
+    ///
+    /// Requires the memory controller to be installed. Must not be called in interrupt
+    /// context.
+    pub fn new(name: &str, workers: usize) -> Arc<WorkQueue> {
+        assert!(workers > 0, "a workqueue needs a worker");
+        let queue = Arc::new(WorkQueue {
+            name: name.to_string(),
+            work: IrqSafeMutex::new(VecDeque::new()),
+            outstanding: AtomicUsize::new(0),
+            stopping: AtomicBool::new(false),
+            available: WaitQueue::new(),
+            idle: WaitQueue::new(),
+            workers: Mutex::new(Vec::new()),
+        });
+
+        let handles = (0..workers)
+            .map(|n| {
+                let queue = queue.clone();
+                thread::spawn(&format!("{}/{}", name, n), move || queue.worker())
+            })
+            .collect();
+        *queue.workers.lock() = handles;
+        queue
+    }
+
+    /// Adds `work` to the queue, a worker runs it later.
+    ///
+    /// Returns false and drops `work` if the queue is being destroyed. Can be called
+    /// in interrupt context.
+    pub fn queue<F>(&self, work: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let work: Work = Box::new(work);
+        {
+            let mut queue = self.work.lock();
+            if self.stopping.load(Ordering::Acquire) {
+                return false;
+            }
+            self.outstanding.fetch_add(1, Ordering::Relaxed);
+            queue.push_back(work);
+        }
+        self.available.notify_one();
+        true
+    }
+
+    /// Blocks until no work is queued or running.
+    ///
+    /// Work queued meanwhile is waited for as well. Must not be called by a worker of
+    /// the same queue, it would wait for itself.
+    pub fn flush(&self) {
+        self.idle.wait_until(|| {
+            if self.outstanding.load(Ordering::Acquire) == 0 {
+                Some(())
+            } else {
+                None
+            }
+        });
+    }
+
+    /// Stops accepting work, lets the workers finish what is queued and waits for
+    /// them to exit.
+    pub fn destroy(&self) {
+        {
+            let _queue = self.work.lock();
+            self.stopping.store(true, Ordering::Release);
+        }
+        self.available.notify_all();
+
+        let workers = mem::replace(&mut *self.workers.lock(), Vec::new());
+        for worker in workers {
+            worker.join();
+        }
+    }
+
+    /// The name given to `new`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of work items queued or running.
+    pub fn pending(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    fn worker(&self) {
+        loop {
+            let work = match self.available.wait_until(|| self.next()) {
+                Some(work) => work,
+                None => return,
+            };
+            work();
+            if self.outstanding.fetch_sub(1, Ordering::AcqRel) == 1 {
+                self.idle.notify_all();
+            }
+        }
+    }
+
+    /// The next work item, `Some(None)` once the queue is stopping and drained.
+    fn next(&self) -> Option<Option<Work>> {
+        let mut queue = self.work.lock();
+        match queue.pop_front() {
+            Some(work) => Some(Some(work)),
+            None if self.stopping.load(Ordering::Acquire) => Some(None),
+            None => None,
+        }
+    }
+}
+
+impl fmt::Debug for WorkQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WorkQueue")
+            .field("name", &self.name)
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+/// Starts the system workqueue with one worker per online CPU.
+///
+/// Requires the memory controller to be installed and the application processors
+/// to be running.
+pub fn init() {
+    SYSTEM.call_once(|| WorkQueue::new("events", smp::online_count().max(1)));
+}
+
+/// Returns the system workqueue.
+pub fn system() -> &'static Arc<WorkQueue> {
+    SYSTEM.r#try().expect("workqueue not initialized")
+}
+
+/// Adds `work` to the system workqueue. Can be called in interrupt context.
+pub fn queue_work<F>(work: F) -> bool
+where
+    F: FnOnce() + Send + 'static,
+{
+    system().queue(work)
+}
@@ -10,6 +10,41 @@ pub unsafe fn disable() {
     llvm_asm!("cli");
 }
 
+/// Enables hardware interrupts and halts until the next one arrives.
+///
+/// `sti` delays interrupt delivery by one instruction, so an interrupt that is
+/// already pending wakes the following `hlt` instead of being handled before it.
+pub unsafe fn enable_and_halt() {
+    llvm_asm!("sti; hlt" :::: "volatile");
+}
+
+/// Returns whether hardware interrupts are enabled, i.e. the `IF` flag is set.
+pub fn are_enabled() -> bool {
+    use crate::x86_64::registers::flags::{flags, Flags};
+
+    flags().contains(Flags::IF)
+}
+
+/// Runs the closure with hardware interrupts disabled.
+///
+/// The previous interrupt state is restored afterwards, so calls can be nested.
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let were_enabled = are_enabled();
+    if were_enabled {
+        unsafe { disable() };
+    }
+
+    let ret = f();
+
+    if were_enabled {
+        unsafe { enable() };
+    }
+    ret
+}
+
 /// Generate a software interrupt.
 /// This is a macro because the argument needs to be an immediate.
 #[macro_export]
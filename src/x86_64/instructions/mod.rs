@@ -14,6 +14,14 @@ pub unsafe fn halt() {
     llvm_asm!("hlt" :::: "volatile");
 }
 
+/// Serializes all loads and stores issued before it using the `MFENCE` instruction.
+#[inline(always)]
+pub fn mfence() {
+    unsafe {
+        llvm_asm!("mfence" ::: "memory" : "volatile");
+    }
+}
+
 /// Read time stamp counters
 
 /// Read the time stamp counter using the `RDTSC` instruction.
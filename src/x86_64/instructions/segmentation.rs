@@ -56,6 +56,11 @@ pub unsafe fn load_gs(sel: SegmentSelector) {
     llvm_asm!("movw $0, %gs " :: "r" (sel.0) : "memory");
 }
 
+/// Exchanges `IA32_GS_BASE` and `IA32_KERNEL_GS_BASE` using the `swapgs` instruction.
+pub unsafe fn swapgs() {
+    llvm_asm!("swapgs" ::: "memory" : "volatile");
+}
+
 /// Returns the current value of the code segment register.
 pub fn cs() -> SegmentSelector {
     let segment: u16;
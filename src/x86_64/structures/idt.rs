@@ -420,6 +420,30 @@ impl Idt {
         self.interrupts = [IdtEntry::missing(); 256 - 32];
     }
 
+    /// Sets the raw handler address of any entry, including the exceptions that push an
+    /// error code and the reserved vectors, which can not be reached through `Index`.
+    ///
+    /// Panics if index is outside the IDT (i.e. greater than 255).
+    ///
+    /// ## Safety
+    /// See `IdtEntry::set_handler_addr`.
+    pub unsafe fn set_handler_addr(&mut self, index: usize, addr: u64) -> &mut EntryOptions {
+        match index {
+            8 => self.double_fault.set_handler_addr(addr),
+            10 => self.invalid_tss.set_handler_addr(addr),
+            11 => self.segment_not_present.set_handler_addr(addr),
+            12 => self.stack_segment_fault.set_handler_addr(addr),
+            13 => self.general_protection_fault.set_handler_addr(addr),
+            14 => self.page_fault.set_handler_addr(addr),
+            15 => self.reserved_1.set_handler_addr(addr),
+            17 => self.alignment_check.set_handler_addr(addr),
+            i @ 21..=29 => self.reserved_2[i - 21].set_handler_addr(addr),
+            30 => self.security_exception.set_handler_addr(addr),
+            31 => self.reserved_3.set_handler_addr(addr),
+            i => self[i].set_handler_addr(addr),
+        }
+    }
+
     /// Loads the IDT in the CPU using the `lidt` command.unresolved import `x86_64`
 
     pub fn load(&'static self) {
@@ -544,7 +568,12 @@ impl<F> IdtEntry<F> {
     ///
     /// The function returns a mutable reference to the entry's options that allows
     /// further customization.
-    fn set_handler_addr(&mut self, addr: u64) -> &mut EntryOptions {
+    ///
+    /// ## Safety
+    /// This function is unsafe because `addr` must point to a valid interrupt entry point
+    /// that matches the calling convention of the vector (error code or not) and returns
+    /// with `iretq`.
+    pub unsafe fn set_handler_addr(&mut self, addr: u64) -> &mut EntryOptions {
         use crate::x86_64::instructions::segmentation;
 
         self.pointer_low = addr as u16;
@@ -569,7 +598,7 @@ macro_rules! impl_set_handler_fn {
             /// The function returns a mutable reference to the entry's options that allows
             /// further customization.
             pub fn set_handler_fn(&mut self, handler: $h) -> &mut EntryOptions {
-                self.set_handler_addr(handler as u64)
+                unsafe { self.set_handler_addr(handler as u64) }
             }
         }
     }